@@ -1,5 +1,39 @@
 //! Capability-based authorization.
+//!
+//! # Matchers
+//!
+//! A granted param may be a matcher instead of a plain value. Matchers are
+//! JSON objects whose keys are all operators starting with `$`; the
+//! requested value must satisfy every operator:
+//!
+//! | Operator | Matches |
+//! |----------|---------|
+//! | `{"$glob": "/srv/*.md"}` | strings matching the pattern; `*` is any run of characters, `?` any one character |
+//! | `{"$prefix": "log."}` | strings starting with the prefix |
+//! | `{"$path": "/home/user"}` | `/home/user` itself and anything beneath it (`/home/user/notes.md`, but not `/home/username`); repeated slashes count as one, and paths with `.` or `..` segments never match |
+//! | `{"$in": [1, 2, 3]}` | any of the listed values |
+//! | `{"$min": 0, "$max": 10}` | numbers within the inclusive bounds; either bound may be omitted |
+//!
+//! Params of exactly `{"*": true}` are a blanket grant, permitting any
+//! params of the type; the [registry](CapabilityRegistry) decides which
+//! types may be granted that way.
+//!
+//! Unknown operators never match. When the requested value is itself a
+//! matcher, as when checking that a delegation only narrows its parent, it
+//! matches only if everything it could accept is accepted by the granted
+//! matcher. Where that can't be decided cheaply (two different globs, say),
+//! the answer is no.
 
+mod matcher;
+mod registry;
+#[cfg(feature = "tokens")]
+mod token;
+
+pub use registry::{CapabilityRegistry, CapabilityType, ParamKind, ParamMatching, ParamSpec};
+#[cfg(feature = "tokens")]
+pub use token::{CapabilityToken, Caveat, TokenError, TokenKey};
+
+use crate::clock::{Clock, SystemClock};
 use crate::entity::EntityId;
 use serde::{Deserialize, Serialize};
 
@@ -13,22 +47,67 @@ pub struct Capability {
     /// Type of capability (e.g., "entity.control", "fs.read").
     pub cap_type: String,
     /// Parameters for the capability (e.g., {"target_id": 42}).
+    ///
+    /// Values are compared by equality unless they are matchers such as
+    /// `{"$path": "/home/user"}`; see the [module docs](crate::capability#matchers).
     pub params: serde_json::Value,
+    /// Time (ms since epoch) before which the capability grants nothing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<i64>,
+    /// Time (ms since epoch) from which the capability grants nothing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    /// Capability this one was delegated from, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
 }
 
 impl Capability {
-    /// Check if this capability grants access for a given type and params.
+    /// Check whether the capability is within its validity window at `now`.
+    pub fn is_valid_at(&self, now: i64) -> bool {
+        self.not_before.is_none_or(|not_before| now >= not_before)
+            && self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+
+    /// Check whether the capability has expired at `now`.
+    pub fn is_expired_at(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
+    /// Check whether this is a blanket `{"*": true}` grant.
+    pub fn is_blanket(&self) -> bool {
+        matcher::is_blanket(&self.params)
+    }
+
+    /// Check if this capability grants access for a given type and params,
+    /// using the system clock for the validity window.
     pub fn permits(&self, cap_type: &str, required_params: &serde_json::Value) -> bool {
-        if self.cap_type != cap_type {
+        self.permits_with_clock(&SystemClock, cap_type, required_params)
+    }
+
+    /// Check if this capability grants access for a given type and params,
+    /// taking the current time from `clock`.
+    pub fn permits_with_clock(
+        &self,
+        clock: &dyn Clock,
+        cap_type: &str,
+        required_params: &serde_json::Value,
+    ) -> bool {
+        if self.cap_type != cap_type || !self.is_valid_at(clock.now_ms()) {
             return false;
         }
+        if self.is_blanket() {
+            return true;
+        }
 
         // Check that all required params are present and match
         match (required_params, &self.params) {
             (serde_json::Value::Object(required), serde_json::Value::Object(granted)) => {
                 for (key, required_value) in required {
                     match granted.get(key) {
-                        Some(granted_value) if granted_value == required_value => continue,
+                        Some(granted_value) if matcher::matches(granted_value, required_value) => {
+                            continue;
+                        }
                         _ => return false,
                     }
                 }
@@ -65,6 +144,9 @@ mod tests {
             owner_id: 1,
             cap_type: "entity.control".to_string(),
             params: json!({"target_id": 42}),
+            not_before: None,
+            expires_at: None,
+            parent_id: None,
         };
 
         assert!(cap.permits("entity.control", &json!({"target_id": 42})));
@@ -79,6 +161,9 @@ mod tests {
             owner_id: 1,
             cap_type: "fs.read".to_string(),
             params: json!({"path": "/home/user", "recursive": true}),
+            not_before: None,
+            expires_at: None,
+            parent_id: None,
         };
 
         // Subset of params should match
@@ -86,4 +171,363 @@ mod tests {
         // But extra required params should fail
         assert!(!cap.permits("fs.read", &json!({"path": "/home/user", "execute": true})));
     }
+
+    /// A capability of type "test" with the given params.
+    fn cap_with(params: serde_json::Value) -> Capability {
+        Capability {
+            id: "test-cap".to_string(),
+            owner_id: 1,
+            cap_type: "test".to_string(),
+            params,
+            not_before: None,
+            expires_at: None,
+            parent_id: None,
+        }
+    }
+
+    #[test]
+    fn test_capability_permits_blanket() {
+        let cap = cap_with(json!({"*": true}));
+        assert!(cap.is_blanket());
+        assert!(cap.permits("test", &json!({"target_id": 3})));
+        assert!(cap.permits("test", &json!({"path": {"$path": "/tmp"}})));
+        assert!(cap.permits("test", &json!({"*": true})));
+        assert!(!cap.permits("other", &json!({})));
+
+        // Only exactly `{"*": true}` is blanket
+        let cap = cap_with(json!({"*": true, "target_id": 3}));
+        assert!(!cap.is_blanket());
+        assert!(!cap.permits("test", &json!({"target_id": 4})));
+        // and a specific grant can't be widened to one
+        let cap = cap_with(json!({"target_id": 3}));
+        assert!(!cap.permits("test", &json!({"*": true})));
+    }
+
+    #[test]
+    fn test_capability_permits_glob() {
+        let cap = cap_with(json!({"path": {"$glob": "/srv/*/notes-??.md"}}));
+
+        assert!(cap.permits("test", &json!({"path": "/srv/alice/notes-01.md"})));
+        assert!(cap.permits("test", &json!({"path": "/srv/a/b/notes-99.md"})));
+        assert!(!cap.permits("test", &json!({"path": "/srv/alice/notes-1.md"})));
+        assert!(!cap.permits("test", &json!({"path": "/srv/alice/notes-01.txt"})));
+        assert!(!cap.permits("test", &json!({"path": "/home/alice/notes-01.md"})));
+        assert!(!cap.permits("test", &json!({"path": 42})));
+
+        let any = cap_with(json!({"name": {"$glob": "*"}}));
+        assert!(any.permits("test", &json!({"name": ""})));
+        assert!(any.permits("test", &json!({"name": "anything"})));
+
+        let literal = cap_with(json!({"name": {"$glob": "exact"}}));
+        assert!(literal.permits("test", &json!({"name": "exact"})));
+        assert!(!literal.permits("test", &json!({"name": "exactly"})));
+
+        let stars = cap_with(json!({"name": {"$glob": "a*b*c"}}));
+        assert!(stars.permits("test", &json!({"name": "abc"})));
+        assert!(stars.permits("test", &json!({"name": "aXXbYYbZZc"})));
+        assert!(!stars.permits("test", &json!({"name": "aXXcYYb"})));
+    }
+
+    #[test]
+    fn test_capability_permits_prefix() {
+        let cap = cap_with(json!({"channel": {"$prefix": "log."}}));
+
+        assert!(cap.permits("test", &json!({"channel": "log."})));
+        assert!(cap.permits("test", &json!({"channel": "log.errors"})));
+        assert!(!cap.permits("test", &json!({"channel": "logs"})));
+        assert!(!cap.permits("test", &json!({"channel": ["log.errors"]})));
+    }
+
+    #[test]
+    fn test_capability_permits_path() {
+        let cap = cap_with(json!({"path": {"$path": "/home/user"}}));
+
+        assert!(cap.permits("test", &json!({"path": "/home/user"})));
+        assert!(cap.permits("test", &json!({"path": "/home/user/notes.md"})));
+        assert!(cap.permits("test", &json!({"path": "/home/user/a/b/c"})));
+        assert!(!cap.permits("test", &json!({"path": "/home/username"})));
+        assert!(!cap.permits("test", &json!({"path": "/home"})));
+        assert!(!cap.permits("test", &json!({"path": "/etc/passwd"})));
+
+        // A trailing slash on the grant makes no difference
+        let slash = cap_with(json!({"path": {"$path": "/home/user/"}}));
+        assert!(slash.permits("test", &json!({"path": "/home/user"})));
+        assert!(slash.permits("test", &json!({"path": "/home/user/notes.md"})));
+        assert!(!slash.permits("test", &json!({"path": "/home/username"})));
+
+        // Repeated slashes are one separator
+        assert!(cap.permits("test", &json!({"path": "/home//user/notes.md"})));
+        assert!(!cap.permits("test", &json!({"path": "//home/username"})));
+        assert!(!cap.permits("test", &json!({"path": "home/user"})));
+
+        // `.` and `..` segments never match, so can't climb out of the base
+        assert!(!cap.permits("test", &json!({"path": "/home/user/../../etc/passwd"})));
+        assert!(!cap.permits("test", &json!({"path": "/home/user//../other"})));
+        assert!(!cap.permits("test", &json!({"path": "/home/user/./notes.md"})));
+        assert!(!cap.permits("test", &json!({"path": "/home/user/.."})));
+        let dotted = cap_with(json!({"path": {"$path": "/home/../etc"}}));
+        assert!(!dotted.permits("test", &json!({"path": "/home/../etc/passwd"})));
+    }
+
+    #[test]
+    fn test_capability_permits_in() {
+        let cap = cap_with(json!({"target_id": {"$in": [1, 2, 3]}}));
+
+        assert!(cap.permits("test", &json!({"target_id": 1})));
+        assert!(cap.permits("test", &json!({"target_id": 3})));
+        assert!(!cap.permits("test", &json!({"target_id": 4})));
+        assert!(!cap.permits("test", &json!({"target_id": "1"})));
+
+        let empty = cap_with(json!({"target_id": {"$in": []}}));
+        assert!(!empty.permits("test", &json!({"target_id": 1})));
+    }
+
+    #[test]
+    fn test_capability_permits_range() {
+        let cap = cap_with(json!({"rate": {"$min": 1, "$max": 10}}));
+
+        assert!(cap.permits("test", &json!({"rate": 1})));
+        assert!(cap.permits("test", &json!({"rate": 5.5})));
+        assert!(cap.permits("test", &json!({"rate": 10})));
+        assert!(!cap.permits("test", &json!({"rate": 0})));
+        assert!(!cap.permits("test", &json!({"rate": 10.01})));
+        assert!(!cap.permits("test", &json!({"rate": "5"})));
+
+        let floor = cap_with(json!({"rate": {"$min": 0}}));
+        assert!(floor.permits("test", &json!({"rate": 1_000_000})));
+        assert!(!floor.permits("test", &json!({"rate": -1})));
+
+        let ceiling = cap_with(json!({"rate": {"$max": 0}}));
+        assert!(ceiling.permits("test", &json!({"rate": -5})));
+        assert!(!ceiling.permits("test", &json!({"rate": 1})));
+    }
+
+    #[test]
+    fn test_capability_permits_combined_operators() {
+        let cap = cap_with(json!({"path": {"$path": "/srv", "$glob": "*.md"}}));
+
+        assert!(cap.permits("test", &json!({"path": "/srv/notes.md"})));
+        assert!(!cap.permits("test", &json!({"path": "/srv/notes.txt"})));
+        assert!(!cap.permits("test", &json!({"path": "/etc/notes.md"})));
+    }
+
+    #[test]
+    fn test_capability_permits_invalid_matchers() {
+        // Unknown operators and mistyped arguments never match
+        let unknown = cap_with(json!({"path": {"$regex": ".*"}}));
+        assert!(!unknown.permits("test", &json!({"path": "/anything"})));
+        let mistyped = cap_with(json!({"path": {"$prefix": 5}}));
+        assert!(!mistyped.permits("test", &json!({"path": "5"})));
+        let mixed = cap_with(json!({"rate": {"$min": 1, "$max": "10"}}));
+        assert!(!mixed.permits("test", &json!({"rate": 5})));
+
+        // Objects without `$` keys are plain values, compared by equality
+        let plain = cap_with(json!({"opts": {"mode": "r"}}));
+        assert!(plain.permits("test", &json!({"opts": {"mode": "r"}})));
+        assert!(!plain.permits("test", &json!({"opts": {"mode": "w"}})));
+        let empty = cap_with(json!({"opts": {}}));
+        assert!(empty.permits("test", &json!({"opts": {}})));
+    }
+
+    #[test]
+    fn test_capability_permits_matcher_against_matcher() {
+        let home = cap_with(json!({
+            "path": {"$path": "/home/user"},
+            "target_id": {"$in": [1, 2, 3]},
+            "rate": {"$min": 0, "$max": 10},
+            "channel": {"$prefix": "log."},
+        }));
+
+        // Narrower matchers are covered
+        assert!(home.permits("test", &json!({"path": {"$path": "/home/user/docs"}})));
+        assert!(home.permits("test", &json!({"path": {"$path": "/home//user/docs/"}})));
+        assert!(home.permits("test", &json!({"path": {"$glob": "/home/user/notes.md"}})));
+        assert!(home.permits(
+            "test",
+            &json!({"path": {"$in": ["/home/user/a", "/home/user"]}})
+        ));
+        assert!(home.permits("test", &json!({"target_id": {"$in": [1, 3]}})));
+        assert!(home.permits("test", &json!({"rate": {"$min": 2, "$max": 8}})));
+        assert!(home.permits("test", &json!({"rate": {"$in": [0, 10]}})));
+        assert!(home.permits("test", &json!({"channel": {"$prefix": "log.err"}})));
+        assert!(home.permits("test", &json!({"channel": {"$glob": "log.*"}})));
+        // An extra operator only narrows further
+        assert!(home.permits(
+            "test",
+            &json!({"path": {"$path": "/home/user/docs", "$glob": "*.md"}})
+        ));
+
+        // Wider or incomparable matchers are not
+        assert!(!home.permits("test", &json!({"path": {"$path": "/home"}})));
+        assert!(!home.permits("test", &json!({"path": {"$prefix": "/home/user"}})));
+        assert!(!home.permits("test", &json!({"path": {"$glob": "/home/*"}})));
+        assert!(!home.permits("test", &json!({"path": {"$path": "/home/user/../.."}})));
+        assert!(!home.permits("test", &json!({"path": {"$glob": "/home/user/../x"}})));
+        // Prefixes and wildcards would also match `..` segments
+        assert!(!home.permits("test", &json!({"path": {"$prefix": "/home/user/"}})));
+        assert!(!home.permits("test", &json!({"path": {"$glob": "/home/user/*.md"}})));
+        assert!(!home.permits("test", &json!({"target_id": {"$in": [1, 4]}})));
+        assert!(!home.permits("test", &json!({"rate": {"$min": 2}})));
+        assert!(!home.permits("test", &json!({"rate": {"$min": -1, "$max": 5}})));
+        assert!(!home.permits("test", &json!({"channel": {"$prefix": "lo"}})));
+        assert!(!home.permits("test", &json!({"channel": {"$regex": "log.*"}})));
+
+        // Globs only cover identical globs
+        let glob = cap_with(json!({"name": {"$glob": "a*"}}));
+        assert!(glob.permits("test", &json!({"name": {"$glob": "a*"}})));
+        assert!(!glob.permits("test", &json!({"name": {"$glob": "ab*"}})));
+        assert!(glob.permits("test", &json!({"name": {"$in": ["ab", "a"]}})));
+    }
+
+    /// Clock fixed at a given time.
+    struct FixedClock(i64);
+
+    impl Clock for FixedClock {
+        fn now_ms(&self) -> i64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_capability_permits_validity_window() {
+        let cap = Capability {
+            id: "test-cap".to_string(),
+            owner_id: 1,
+            cap_type: "entity.control".to_string(),
+            params: json!({"target_id": 42}),
+            not_before: Some(1_000),
+            expires_at: Some(2_000),
+            parent_id: None,
+        };
+        let params = json!({"target_id": 42});
+
+        assert!(!cap.permits_with_clock(&FixedClock(999), "entity.control", &params));
+        assert!(cap.permits_with_clock(&FixedClock(1_000), "entity.control", &params));
+        assert!(cap.permits_with_clock(&FixedClock(1_999), "entity.control", &params));
+        assert!(!cap.permits_with_clock(&FixedClock(2_000), "entity.control", &params));
+        // Validity doesn't widen params
+        assert!(!cap.permits_with_clock(
+            &FixedClock(1_500),
+            "entity.control",
+            &json!({"target_id": 7})
+        ));
+
+        assert!(!cap.is_expired_at(1_999));
+        assert!(cap.is_expired_at(2_000));
+        // The wall clock is long past 2_000
+        assert!(!cap.permits("entity.control", &params));
+    }
+
+    #[cfg(feature = "tokens")]
+    #[test]
+    fn test_capability_token_round_trip() {
+        let key = TokenKey::generate().unwrap();
+        let mut cap = cap_with(json!({"path": {"$path": "/notes"}}));
+        cap.expires_at = Some(5_000);
+        let token = CapabilityToken::mint(&cap, &key).encode();
+
+        let verified = CapabilityToken::decode(&token)
+            .unwrap()
+            .verify(&key)
+            .unwrap();
+        assert_eq!(verified.id, cap.id);
+        assert_eq!(verified.params, cap.params);
+        assert_eq!(verified.expires_at, Some(5_000));
+        assert!(verified.permits_with_clock(&FixedClock(0), "test", &json!({"path": "/notes/a"})));
+
+        // Another server's key, or a tampered token, doesn't verify
+        let other = TokenKey::generate().unwrap();
+        assert!(matches!(
+            CapabilityToken::decode(&token).unwrap().verify(&other),
+            Err(TokenError::BadSignature)
+        ));
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let forged = base64::Engine::encode(
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+            br#"{"id":"x","owner_id":1,"cap_type":"test","params":{"path":"/"}}"#,
+        );
+        parts[1] = &forged;
+        assert!(matches!(
+            CapabilityToken::decode(&parts.join("."))
+                .unwrap()
+                .verify(&key),
+            Err(TokenError::BadSignature)
+        ));
+        assert!(matches!(
+            CapabilityToken::decode("lotus1.!!"),
+            Err(TokenError::Malformed(_))
+        ));
+        assert!(matches!(
+            CapabilityToken::decode("macaroon.abc.def"),
+            Err(TokenError::Malformed(_))
+        ));
+    }
+
+    #[cfg(feature = "tokens")]
+    #[test]
+    fn test_capability_token_caveats() {
+        let key = TokenKey::from_bytes([7; 32]);
+        let cap = cap_with(json!({"path": {"$path": "/notes"}, "recursive": true}));
+        let token = CapabilityToken::mint(&cap, &key)
+            .attenuate(&Caveat::Params(json!({"path": {"$path": "/notes/public"}})))
+            .attenuate(&Caveat::ExpiresAt(2_000))
+            .attenuate(&Caveat::NotBefore(1_000));
+
+        let verified = CapabilityToken::decode(&token.encode())
+            .unwrap()
+            .verify(&key)
+            .unwrap();
+        assert_eq!(verified.params["path"], json!({"$path": "/notes/public"}));
+        assert_eq!(verified.not_before, Some(1_000));
+        assert_eq!(verified.expires_at, Some(2_000));
+        let now = FixedClock(1_500);
+        assert!(verified.permits_with_clock(&now, "test", &json!({"path": "/notes/public/a"})));
+        assert!(!verified.permits_with_clock(&now, "test", &json!({"path": "/notes/private"})));
+        assert!(!verified.permits_with_clock(&FixedClock(2_000), "test", &json!({})));
+
+        // Caveats can't be dropped without breaking the signature
+        let encoded = token.encode();
+        let mut parts: Vec<&str> = encoded.split('.').collect();
+        parts.remove(2);
+        assert!(matches!(
+            CapabilityToken::decode(&parts.join("."))
+                .unwrap()
+                .verify(&key),
+            Err(TokenError::BadSignature)
+        ));
+
+        // A later expiry can't extend an earlier one, and params can't widen
+        let extended = token.clone().attenuate(&Caveat::ExpiresAt(9_000));
+        assert_eq!(extended.verify(&key).unwrap().expires_at, Some(2_000));
+        let escaped = token.clone().attenuate(&Caveat::Params(
+            json!({"path": {"$path": "/notes/public/../../etc"}}),
+        ));
+        assert!(matches!(
+            escaped.verify(&key),
+            Err(TokenError::WideningCaveat(_))
+        ));
+        let widened = token.attenuate(&Caveat::Params(json!({"path": {"$path": "/"}})));
+        assert!(matches!(
+            widened.verify(&key),
+            Err(TokenError::WideningCaveat(_))
+        ));
+        let added =
+            CapabilityToken::mint(&cap, &key).attenuate(&Caveat::Params(json!({"owner": "me"})));
+        assert!(matches!(
+            added.verify(&key),
+            Err(TokenError::WideningCaveat(_))
+        ));
+
+        // Blanket grants can be narrowed to anything
+        let blanket = cap_with(json!({"*": true}));
+        let narrowed = CapabilityToken::mint(&blanket, &key)
+            .attenuate(&Caveat::Params(json!({"target_id": 3})))
+            .verify(&key)
+            .unwrap();
+        assert_eq!(narrowed.params, json!({"target_id": 3}));
+        assert!(narrowed.permits("test", &json!({"target_id": 3})));
+        assert!(!narrowed.permits("test", &json!({"target_id": 4})));
+        let verified = CapabilityToken::mint(&blanket, &key).verify(&key).unwrap();
+        assert!(verified.permits("test", &json!({"target_id": 4})));
+    }
 }
@@ -0,0 +1,232 @@
+//! Evaluation of matchers in capability params.
+//!
+//! The operators are documented on the [`capability`](super) module.
+
+use serde_json::Value;
+
+/// One operator of a matcher. `$min`/`$max` combine into a single range.
+#[derive(Debug)]
+enum Constraint<'a> {
+    Glob(&'a str),
+    Prefix(&'a str),
+    Path(&'a str),
+    In(&'a [Value]),
+    Range {
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    /// An operator that is unknown or has an argument of the wrong type.
+    Invalid,
+}
+
+/// Parse `value` as a matcher, or `None` if it is a plain value.
+fn parse(value: &Value) -> Option<Vec<Constraint<'_>>> {
+    let Value::Object(ops) = value else {
+        return None;
+    };
+    if ops.is_empty() || !ops.keys().all(|op| op.starts_with('$')) {
+        return None;
+    }
+
+    let mut constraints = Vec::new();
+    let mut min = None;
+    let mut max = None;
+    for (op, arg) in ops {
+        let constraint = match (op.as_str(), arg) {
+            ("$glob", Value::String(pattern)) => Constraint::Glob(pattern),
+            ("$prefix", Value::String(prefix)) => Constraint::Prefix(prefix),
+            ("$path", Value::String(base)) => Constraint::Path(base),
+            ("$in", Value::Array(values)) => Constraint::In(values),
+            ("$min", Value::Number(bound)) => {
+                min = bound.as_f64();
+                continue;
+            }
+            ("$max", Value::Number(bound)) => {
+                max = bound.as_f64();
+                continue;
+            }
+            _ => Constraint::Invalid,
+        };
+        constraints.push(constraint);
+    }
+    if min.is_some() || max.is_some() {
+        constraints.push(Constraint::Range { min, max });
+    }
+    Some(constraints)
+}
+
+/// Check whether `params` are a blanket `{"*": true}` grant.
+pub(crate) fn is_blanket(params: &Value) -> bool {
+    params
+        .as_object()
+        .is_some_and(|params| params.len() == 1 && params.get("*") == Some(&Value::Bool(true)))
+}
+
+/// Check whether `value` is a matcher rather than a plain value.
+pub(crate) fn is_matcher(value: &Value) -> bool {
+    parse(value).is_some()
+}
+
+/// Check that every operator in a matcher is known and has an argument of
+/// the right type.
+pub(crate) fn validate(value: &Value) -> Result<(), String> {
+    let Value::Object(ops) = value else {
+        return Ok(());
+    };
+    for (op, arg) in ops {
+        let valid = match op.as_str() {
+            "$glob" | "$prefix" | "$path" => arg.is_string(),
+            "$in" => arg.is_array(),
+            "$min" | "$max" => arg.is_number(),
+            _ => return Err(format!("unknown matcher operator '{}'", op)),
+        };
+        if !valid {
+            return Err(format!("invalid argument for '{}'", op));
+        }
+    }
+    Ok(())
+}
+
+/// Check whether a granted param value permits a requested one.
+pub(crate) fn matches(granted: &Value, requested: &Value) -> bool {
+    let Some(granted) = parse(granted) else {
+        return granted == requested;
+    };
+    match parse(requested) {
+        None => granted
+            .iter()
+            .all(|constraint| satisfies(constraint, requested)),
+        Some(requested) => granted
+            .iter()
+            .all(|wider| requested.iter().any(|narrower| subsumes(wider, narrower))),
+    }
+}
+
+/// Check a concrete value against one constraint.
+fn satisfies(constraint: &Constraint<'_>, value: &Value) -> bool {
+    match (constraint, value) {
+        (Constraint::Glob(pattern), Value::String(text)) => glob_match(pattern, text),
+        (Constraint::Prefix(prefix), Value::String(text)) => text.starts_with(prefix),
+        (Constraint::Path(base), Value::String(path)) => path_within(base, path),
+        (Constraint::In(values), value) => values.contains(value),
+        (Constraint::Range { min, max }, Value::Number(number)) => {
+            let Some(number) = number.as_f64() else {
+                return false;
+            };
+            min.is_none_or(|min| number >= min) && max.is_none_or(|max| number <= max)
+        }
+        _ => false,
+    }
+}
+
+/// Check that everything `narrower` accepts is accepted by `wider`.
+fn subsumes(wider: &Constraint<'_>, narrower: &Constraint<'_>) -> bool {
+    use Constraint::*;
+    match (wider, narrower) {
+        (Invalid, _) | (_, Invalid) => false,
+        (_, In(values)) => values.iter().all(|value| satisfies(wider, value)),
+        (
+            Range { min, max },
+            Range {
+                min: inner_min,
+                max: inner_max,
+            },
+        ) => {
+            let min_ok = match (min, inner_min) {
+                (None, _) => true,
+                (Some(min), Some(inner)) => inner >= min,
+                (Some(_), None) => false,
+            };
+            let max_ok = match (max, inner_max) {
+                (None, _) => true,
+                (Some(max), Some(inner)) => inner <= max,
+                (Some(_), None) => false,
+            };
+            min_ok && max_ok
+        }
+        // String constraints: `narrower` is covered if every string it
+        // accepts starts with something `wider` accepts wholesale. `$path`
+        // accepts its base spelled with extra slashes, so no prefix covers it.
+        (Prefix(prefix), Prefix(inner)) => inner.starts_with(prefix),
+        (Prefix(prefix), Glob(pattern)) => literal_prefix(pattern).starts_with(prefix),
+        (Path(base), Path(inner)) => path_within(base, inner),
+        // Prefixes and wildcards can go on to match `..`, which `$path`
+        // never does, so only a glob without wildcards is narrow enough
+        (Path(base), Glob(pattern)) => {
+            literal_prefix(pattern) == *pattern && path_within(base, pattern)
+        }
+        (Glob(pattern), Glob(inner)) => pattern == inner,
+        _ => false,
+    }
+}
+
+/// The part of a glob pattern before its first wildcard.
+fn literal_prefix(pattern: &str) -> &str {
+    match pattern.find(['*', '?']) {
+        Some(idx) => &pattern[..idx],
+        None => pattern,
+    }
+}
+
+/// Check whether `path` is `base` or lies beneath it, comparing whole
+/// segments. Paths with `.` or `..` segments are never within anything.
+fn path_within(base: &str, path: &str) -> bool {
+    match (path_segments(base), path_segments(path)) {
+        (Some((base_absolute, base)), Some((absolute, path))) => {
+            base_absolute == absolute && path.starts_with(&base)
+        }
+        _ => false,
+    }
+}
+
+/// Whether `path` is absolute, and its segments with empty ones (from `//`
+/// or a trailing `/`) dropped. `None` if any segment is `.` or `..`.
+fn path_segments(path: &str) -> Option<(bool, Vec<&str>)> {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" => {}
+            "." | ".." => return None,
+            _ => segments.push(segment),
+        }
+    }
+    Some((path.starts_with('/'), segments))
+}
+
+/// Match `text` against a glob where `*` is any run of characters and `?`
+/// is any single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut pattern_idx, mut text_idx) = (0, 0);
+    // Position of the last `*` and the text position it was tried at.
+    let mut backtrack = None;
+
+    while text_idx < text.len() {
+        match pattern.get(pattern_idx) {
+            Some('*') => {
+                backtrack = Some((pattern_idx, text_idx));
+                pattern_idx += 1;
+            }
+            Some('?') => {
+                pattern_idx += 1;
+                text_idx += 1;
+            }
+            Some(&literal) if literal == text[text_idx] => {
+                pattern_idx += 1;
+                text_idx += 1;
+            }
+            _ => match backtrack {
+                Some((star_idx, star_text_idx)) => {
+                    pattern_idx = star_idx + 1;
+                    text_idx = star_text_idx + 1;
+                    backtrack = Some((star_idx, star_text_idx + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[pattern_idx..]
+        .iter()
+        .all(|&remaining| remaining == '*')
+}
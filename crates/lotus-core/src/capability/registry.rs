@@ -0,0 +1,236 @@
+//! Registry of known capability types.
+//!
+//! Each [`CapabilityType`] declares the params a capability of that type
+//! may carry, whether holders may delegate it, and whether its params may
+//! use matchers. Storage backends validate new capabilities against their
+//! registry, so typos like `"fs.raed"` or an `entity.control` without a
+//! `target_id` are rejected at mint time instead of silently granting
+//! nothing.
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use super::{cap_types, matcher};
+use crate::storage::StorageError;
+
+/// Expected shape of a param value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamKind {
+    Any,
+    String,
+    Integer,
+    Number,
+    Bool,
+    EntityId,
+    /// An absolute, `/`-separated path.
+    Path,
+}
+
+impl ParamKind {
+    fn accepts(self, value: &Value) -> bool {
+        match self {
+            ParamKind::Any => true,
+            ParamKind::String => value.is_string(),
+            ParamKind::Integer | ParamKind::EntityId => value.is_i64(),
+            ParamKind::Number => value.is_number(),
+            ParamKind::Bool => value.is_boolean(),
+            ParamKind::Path => value.as_str().is_some_and(|path| path.starts_with('/')),
+        }
+    }
+}
+
+/// How granted params are compared against requested ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamMatching {
+    /// Plain equality only; matcher objects are rejected at creation.
+    Exact,
+    /// Params may be matchers (see the [`capability`](super) module).
+    Matchers,
+}
+
+/// A declared param.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParamSpec {
+    pub name: String,
+    pub kind: ParamKind,
+    pub required: bool,
+}
+
+/// Declaration of a capability type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapabilityType {
+    pub name: String,
+    pub params: Vec<ParamSpec>,
+    /// Whether capabilities of this type may be delegated.
+    pub delegable: bool,
+    pub matching: ParamMatching,
+    /// Whether `{"*": true}` is accepted in place of the declared params,
+    /// as used by the seeds for blanket grants.
+    pub wildcard: bool,
+}
+
+impl CapabilityType {
+    /// A delegable type with no params that compares params exactly.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            params: Vec::new(),
+            delegable: true,
+            matching: ParamMatching::Exact,
+            wildcard: false,
+        }
+    }
+
+    /// Declare a required param.
+    pub fn param(mut self, name: &str, kind: ParamKind) -> Self {
+        self.params.push(ParamSpec {
+            name: name.to_string(),
+            kind,
+            required: true,
+        });
+        self
+    }
+
+    /// Declare an optional param.
+    pub fn optional_param(mut self, name: &str, kind: ParamKind) -> Self {
+        self.params.push(ParamSpec {
+            name: name.to_string(),
+            kind,
+            required: false,
+        });
+        self
+    }
+
+    pub fn delegable(mut self, delegable: bool) -> Self {
+        self.delegable = delegable;
+        self
+    }
+
+    pub fn matching(mut self, matching: ParamMatching) -> Self {
+        self.matching = matching;
+        self
+    }
+
+    pub fn wildcard(mut self, wildcard: bool) -> Self {
+        self.wildcard = wildcard;
+        self
+    }
+
+    /// Check that `params` fit this type's declaration.
+    pub fn validate(&self, params: &Value) -> Result<(), StorageError> {
+        let invalid = |message: String| {
+            Err(StorageError::InvalidCapability(format!(
+                "{}: {}",
+                self.name, message
+            )))
+        };
+
+        if self.wildcard && matcher::is_blanket(params) {
+            return Ok(());
+        }
+        let Value::Object(params) = params else {
+            return invalid("params must be an object".to_string());
+        };
+
+        for (key, value) in params {
+            let Some(spec) = self.params.iter().find(|spec| spec.name == *key) else {
+                return invalid(format!("unknown param '{}'", key));
+            };
+            if matcher::is_matcher(value) {
+                if self.matching == ParamMatching::Exact {
+                    return invalid(format!("param '{}' does not accept matchers", key));
+                }
+                if let Err(message) = matcher::validate(value) {
+                    return invalid(format!("param '{}': {}", key, message));
+                }
+            } else if !spec.kind.accepts(value) {
+                return invalid(format!("param '{}' must be {:?}", key, spec.kind));
+            }
+        }
+        for spec in self.params.iter().filter(|spec| spec.required) {
+            if !params.contains_key(&spec.name) {
+                return invalid(format!("missing param '{}'", spec.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Capability types known to a storage backend.
+#[derive(Debug, Clone)]
+pub struct CapabilityRegistry {
+    types: HashMap<String, CapabilityType>,
+}
+
+impl CapabilityRegistry {
+    /// A registry with no types.
+    pub fn empty() -> Self {
+        Self {
+            types: HashMap::new(),
+        }
+    }
+
+    /// Register a type. Fails if one with the same name already exists.
+    pub fn register(&mut self, capability_type: CapabilityType) -> Result<(), StorageError> {
+        if self.types.contains_key(&capability_type.name) {
+            return Err(StorageError::InvalidCapability(format!(
+                "type '{}' is already registered",
+                capability_type.name
+            )));
+        }
+        self.types
+            .insert(capability_type.name.clone(), capability_type);
+        Ok(())
+    }
+
+    /// Look up a type by name.
+    pub fn get(&self, name: &str) -> Option<&CapabilityType> {
+        self.types.get(name)
+    }
+
+    /// Check that `cap_type` is registered and `params` fit it.
+    pub fn validate(&self, cap_type: &str, params: &Value) -> Result<(), StorageError> {
+        match self.get(cap_type) {
+            Some(capability_type) => capability_type.validate(params),
+            None => Err(StorageError::InvalidCapability(format!(
+                "unknown capability type '{}'",
+                cap_type
+            ))),
+        }
+    }
+}
+
+impl Default for CapabilityRegistry {
+    /// A registry with the built-in types from [`cap_types`].
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        let builtins = [
+            CapabilityType::new(cap_types::ENTITY_CONTROL)
+                .param("target_id", ParamKind::EntityId)
+                .matching(ParamMatching::Matchers)
+                .wildcard(true),
+            CapabilityType::new(cap_types::FS_READ)
+                .param("path", ParamKind::Path)
+                .optional_param("recursive", ParamKind::Bool)
+                .matching(ParamMatching::Matchers),
+            CapabilityType::new(cap_types::FS_WRITE)
+                .param("path", ParamKind::Path)
+                .optional_param("recursive", ParamKind::Bool)
+                .matching(ParamMatching::Matchers),
+            CapabilityType::new(cap_types::NET_REQUEST)
+                .optional_param("host", ParamKind::String)
+                .optional_param("method", ParamKind::String)
+                .matching(ParamMatching::Matchers),
+            CapabilityType::new(cap_types::SYSTEM_EXEC)
+                .optional_param("command", ParamKind::String)
+                .delegable(false),
+        ];
+        for capability_type in builtins {
+            registry
+                .register(capability_type)
+                .expect("built-in capability types are distinct");
+        }
+        registry
+    }
+}
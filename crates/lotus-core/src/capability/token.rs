@@ -0,0 +1,283 @@
+//! Signed, portable capability tokens.
+//!
+//! A capability row only means something inside the database that holds
+//! it. A [`CapabilityToken`] carries the capability itself, signed with the
+//! issuing server's [`TokenKey`], so any service holding the same key can
+//! check it without a database lookup.
+//!
+//! Tokens are minted through [`WorldStorage::mint_token`], which only
+//! exports delegable capabilities and always sets an expiry. Other servers
+//! check them with [`CapabilityToken::verify`] and may keep them with
+//! [`WorldStorage::import_token`]; the issuing server can use
+//! [`WorldStorage::verify_issued_token`] instead, so capabilities it has
+//! revoked stop working before their tokens expire.
+//!
+//! Tokens follow the macaroon construction: the signature over the
+//! capability is used as the key for signing the first [`Caveat`], that
+//! signature as the key for the next, and so on. Anyone holding a token can
+//! therefore attenuate it by appending caveats, but nobody without the
+//! server key can remove one or forge a signature for a shorter chain.
+//!
+//! The wire format is `lotus1.<capability>.<caveat>....<signature>`, each
+//! part base64url-encoded without padding. Signatures cover the encoded
+//! JSON bytes exactly as transmitted.
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use thiserror::Error;
+
+use super::{Capability, matcher};
+#[cfg(doc)]
+use crate::WorldStorage;
+use crate::entity::EntityId;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const PREFIX: &str = "lotus1";
+
+#[derive(Debug, Error)]
+pub enum TokenError {
+    #[error("malformed token: {0}")]
+    Malformed(String),
+
+    #[error("token signature does not match")]
+    BadSignature,
+
+    #[error("caveat widens the capability: {0}")]
+    WideningCaveat(String),
+
+    #[error("could not generate key: {0}")]
+    KeyGeneration(String),
+}
+
+/// Secret key a server signs its tokens with.
+#[derive(Clone, PartialEq, Eq)]
+pub struct TokenKey([u8; 32]);
+
+impl TokenKey {
+    /// Generate a random key.
+    pub fn generate() -> Result<Self, TokenError> {
+        let mut bytes = [0u8; 32];
+        getrandom::getrandom(&mut bytes)
+            .map_err(|error| TokenError::KeyGeneration(error.to_string()))?;
+        Ok(Self(bytes))
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for TokenKey {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter.write_str("TokenKey(..)")
+    }
+}
+
+/// A restriction appended to a token.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Caveat {
+    /// The token grants nothing before this time (ms since epoch).
+    NotBefore(i64),
+    /// The token grants nothing from this time (ms since epoch).
+    ExpiresAt(i64),
+    /// Replace the given params with narrower values, as a delegation
+    /// would. Every key must already be granted.
+    Params(Value),
+}
+
+/// The signed part of a token.
+#[derive(Serialize, Deserialize)]
+struct TokenBody {
+    id: String,
+    owner_id: EntityId,
+    cap_type: String,
+    params: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    not_before: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<i64>,
+}
+
+/// A capability exported as a self-contained signed token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapabilityToken {
+    body: Vec<u8>,
+    caveats: Vec<Vec<u8>>,
+    signature: [u8; 32],
+}
+
+impl CapabilityToken {
+    /// Sign `capability` with `key`, as is.
+    pub(crate) fn mint(capability: &Capability, key: &TokenKey) -> Self {
+        let body = TokenBody {
+            id: capability.id.clone(),
+            owner_id: capability.owner_id,
+            cap_type: capability.cap_type.clone(),
+            params: capability.params.clone(),
+            not_before: capability.not_before,
+            expires_at: capability.expires_at,
+        };
+        let body = serde_json::to_vec(&body).expect("token body serializes");
+        let signature = sign(&key.0, &body);
+        Self {
+            body,
+            caveats: Vec::new(),
+            signature,
+        }
+    }
+
+    /// Append a caveat. Needs no key.
+    pub fn attenuate(mut self, caveat: &Caveat) -> Self {
+        let caveat = serde_json::to_vec(caveat).expect("caveat serializes");
+        self.signature = sign(&self.signature, &caveat);
+        self.caveats.push(caveat);
+        self
+    }
+
+    /// Encode to the wire format.
+    pub fn encode(&self) -> String {
+        let mut parts = vec![PREFIX.to_string(), URL_SAFE_NO_PAD.encode(&self.body)];
+        parts.extend(
+            self.caveats
+                .iter()
+                .map(|caveat| URL_SAFE_NO_PAD.encode(caveat)),
+        );
+        parts.push(URL_SAFE_NO_PAD.encode(self.signature));
+        parts.join(".")
+    }
+
+    /// Parse the wire format. Does not check the signature.
+    pub fn decode(token: &str) -> Result<Self, TokenError> {
+        let mut parts = token.split('.');
+        if parts.next() != Some(PREFIX) {
+            return Err(TokenError::Malformed(format!(
+                "expected '{}' prefix",
+                PREFIX
+            )));
+        }
+        let mut parts = parts
+            .map(|part| {
+                URL_SAFE_NO_PAD
+                    .decode(part)
+                    .map_err(|error| TokenError::Malformed(error.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if parts.len() < 2 {
+            return Err(TokenError::Malformed("missing parts".to_string()));
+        }
+        let signature = parts
+            .pop()
+            .and_then(|signature| <[u8; 32]>::try_from(signature).ok())
+            .ok_or_else(|| TokenError::Malformed("bad signature length".to_string()))?;
+        let body = parts.remove(0);
+        Ok(Self {
+            body,
+            caveats: parts,
+            signature,
+        })
+    }
+
+    /// Check the signature chain against `key` and return the capability
+    /// the token grants, with every caveat applied.
+    ///
+    /// Needs no database, so this is how servers other than the issuer
+    /// check tokens. It can't tell whether the capability has since been
+    /// revoked; see [`WorldStorage::verify_issued_token`]. The returned
+    /// capability's validity window is enforced by
+    /// [`Capability::permits_with_clock`] as for any other capability. Its
+    /// `parent_id` is always `None`.
+    pub fn verify(&self, key: &TokenKey) -> Result<Capability, TokenError> {
+        let mut mac = HmacSha256::new_from_slice(&key.0).expect("HMAC accepts any key length");
+        mac.update(&self.body);
+        for caveat in &self.caveats {
+            let signature: [u8; 32] = mac.finalize().into_bytes().into();
+            mac = HmacSha256::new_from_slice(&signature).expect("HMAC accepts any key length");
+            mac.update(caveat);
+        }
+        mac.verify_slice(&self.signature)
+            .map_err(|_| TokenError::BadSignature)?;
+
+        let body: TokenBody = parse(&self.body)?;
+        let mut capability = Capability {
+            id: body.id,
+            owner_id: body.owner_id,
+            cap_type: body.cap_type,
+            params: body.params,
+            not_before: body.not_before,
+            expires_at: body.expires_at,
+            parent_id: None,
+        };
+        for caveat in &self.caveats {
+            apply(&mut capability, parse(caveat)?)?;
+        }
+        Ok(capability)
+    }
+}
+
+fn sign(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+fn parse<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T, TokenError> {
+    serde_json::from_slice(bytes).map_err(|error| TokenError::Malformed(error.to_string()))
+}
+
+/// Narrow `capability` by one caveat.
+fn apply(capability: &mut Capability, caveat: Caveat) -> Result<(), TokenError> {
+    match caveat {
+        Caveat::NotBefore(not_before) => {
+            capability.not_before = Some(
+                capability
+                    .not_before
+                    .map_or(not_before, |own| own.max(not_before)),
+            );
+        }
+        Caveat::ExpiresAt(expires_at) => {
+            capability.expires_at = Some(
+                capability
+                    .expires_at
+                    .map_or(expires_at, |own| own.min(expires_at)),
+            );
+        }
+        Caveat::Params(Value::Object(restricted)) => {
+            if capability.is_blanket() {
+                capability.params = Value::Object(restricted);
+                return Ok(());
+            }
+            let granted = match &mut capability.params {
+                Value::Object(granted) => granted,
+                _ => {
+                    return Err(TokenError::WideningCaveat(
+                        "capability params are not an object".to_string(),
+                    ));
+                }
+            };
+            for (key, value) in restricted {
+                match granted.get(&key) {
+                    Some(current) if matcher::matches(current, &value) => {
+                        granted.insert(key, value);
+                    }
+                    _ => return Err(TokenError::WideningCaveat(format!("param '{}'", key))),
+                }
+            }
+        }
+        Caveat::Params(_) => {
+            return Err(TokenError::Malformed(
+                "params caveat must be an object".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,68 @@
+//! Time sources.
+//!
+//! Code that compares against "now" takes its time from a [`Clock`] so
+//! tests and simulations can control it. [`WorldStorage`] uses one for
+//! capability expiry and the timestamps it records, and the [`Scheduler`]
+//! for deciding which tasks are due; sharing one [`ManualClock`] between
+//! them runs the world on simulated time.
+//!
+//! [`WorldStorage`]: crate::WorldStorage
+//! [`Scheduler`]: crate::Scheduler
+
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// A source of the current time, in milliseconds since the Unix epoch.
+pub trait Clock: Send + Sync {
+    fn now_ms(&self) -> i64;
+
+    /// Move the clock forward to `time` if it is behind.
+    ///
+    /// [`Scheduler::run_until`](crate::Scheduler::run_until) calls this to
+    /// step simulated time from one task to the next. Clocks that can't be
+    /// moved, like [`SystemClock`], ignore it.
+    fn advance_to(&self, _time: i64) {}
+}
+
+/// The system wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> i64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("System time before Unix epoch")
+            .as_millis() as i64
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug, Default)]
+pub struct ManualClock(AtomicI64);
+
+impl ManualClock {
+    /// A clock reading `now`.
+    pub fn new(now: i64) -> Self {
+        Self(AtomicI64::new(now))
+    }
+
+    /// Set the time, backwards or forwards.
+    pub fn set(&self, now: i64) {
+        self.0.store(now, Ordering::SeqCst);
+    }
+
+    /// Move the time forward by `ms`.
+    pub fn advance(&self, ms: i64) {
+        self.0.fetch_add(ms, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> i64 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn advance_to(&self, time: i64) {
+        self.0.fetch_max(time, Ordering::SeqCst);
+    }
+}
@@ -47,4 +47,8 @@ pub struct Verb {
     /// Optional capability type required to call this verb.
     /// If set, caller must hold a capability of this type to execute the verb.
     pub required_capability: Option<String>,
+    /// Params the required capability must permit, templated from the call.
+    /// See [`WorldStorage::authorize_verb_call`](crate::WorldStorage::authorize_verb_call).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub required_params: Option<serde_json::Value>,
 }
@@ -1,11 +1,26 @@
 //! Entity system, capabilities, and storage for Lotus.
 
 pub mod capability;
+pub mod clock;
 pub mod entity;
 pub mod scheduler;
 pub mod storage;
+pub mod store;
 
-pub use capability::{Capability, cap_types};
+pub use capability::{
+    Capability, CapabilityRegistry, CapabilityType, ParamKind, ParamMatching, ParamSpec, cap_types,
+};
+#[cfg(feature = "tokens")]
+pub use capability::{CapabilityToken, Caveat, TokenError, TokenKey};
+pub use clock::{Clock, ManualClock, SystemClock};
 pub use entity::{Entity, EntityId, Verb};
-pub use scheduler::{ScheduledTask, Scheduler, SchedulerError};
-pub use storage::{StorageError, WorldStorage};
+pub use scheduler::{
+    CancellationToken, CronSchedule, DeadLetterTask, LatencyHistogram, Recurrence, RetryPolicy,
+    ScheduledTask, Scheduler, SchedulerError, SchedulerStats, TaskFilter, TaskOptions, VerbStats,
+};
+pub use storage::{
+    AuditAction, AuditEntry, AuditQuery, Authorized, ChangeEvent, Denied, EntityQuery,
+    EntityRevision, PatchOperation, PropChange, PropIndex, PropsPatch, Relation, RevisionKind,
+    RevisionPoint, StorageError, Transaction, WorldIssue, WorldStorage,
+};
+pub use store::{MemoryStore, WorldStore};
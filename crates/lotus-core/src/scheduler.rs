@@ -2,13 +2,28 @@
 //!
 //! The scheduler manages tasks stored in the database and executes them
 //! when their scheduled time arrives. Tasks are persisted to survive restarts.
+//!
+//! Delivery is at-least-once: a due task is leased while its verb runs and
+//! only removed (or re-armed, if recurring) after the verb succeeds. A
+//! failed one-shot task is retried with exponential backoff per the
+//! scheduler's [`RetryPolicy`] and dead-lettered once out of attempts. A
+//! failed recurring task simply waits for its next run.
+
+mod cron;
+mod stats;
 
-use crate::{StorageError, WorldStorage};
+pub use cron::CronSchedule;
+pub use stats::{LATENCY_BUCKETS_MS, LatencyHistogram, SchedulerStats, VerbStats};
+
+use crate::{Clock, StorageError, SystemClock, WorldStorage, WorldStore};
+use std::collections::BTreeMap;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 use thiserror::Error;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, mpsc};
 use tokio::time;
+pub use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
 #[derive(Debug, Error)]
 pub enum SchedulerError {
@@ -17,30 +32,172 @@ pub enum SchedulerError {
 
     #[error("Task execution error: {0}")]
     Execution(String),
+
+    #[error("Invalid schedule: {0}")]
+    InvalidSchedule(String),
+}
+
+/// How a recurring task is re-armed each time it fires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recurrence {
+    /// Every `interval_ms` milliseconds.
+    Every { interval_ms: u64 },
+    /// At each time matching a cron expression.
+    Cron(CronSchedule),
+}
+
+impl Recurrence {
+    /// Next run of a task that was due at `due`, when it fires at `now`.
+    ///
+    /// Runs missed while the scheduler was behind are skipped rather than
+    /// fired in a burst. `None` if the schedule never matches again, or the
+    /// next run would be past the end of time.
+    pub fn next_run(&self, due: i64, now: i64) -> Result<Option<i64>, SchedulerError> {
+        match self {
+            Recurrence::Every { interval_ms } => {
+                let interval =
+                    checked_interval(*interval_ms).map_err(SchedulerError::InvalidSchedule)?;
+                let missed = now.saturating_sub(due).max(0) / interval;
+                Ok((missed + 1)
+                    .checked_mul(interval)
+                    .and_then(|step| due.checked_add(step)))
+            }
+            Recurrence::Cron(schedule) => Ok(schedule.next_after(now.max(due))),
+        }
+    }
+
+    /// Check that the recurrence can be stored and run.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        match self {
+            Recurrence::Every { interval_ms } => checked_interval(*interval_ms).map(|_| ()),
+            Recurrence::Cron(_) => Ok(()),
+        }
+    }
+}
+
+/// `interval_ms` as a signed interval, if it is between 1 ms and
+/// `i64::MAX` ms.
+pub(crate) fn checked_interval(interval_ms: u64) -> Result<i64, String> {
+    match i64::try_from(interval_ms) {
+        Ok(interval) if interval > 0 => Ok(interval),
+        _ => Err(format!(
+            "interval must be between 1 and {} ms, not {}",
+            i64::MAX,
+            interval_ms
+        )),
+    }
 }
 
 // Re-export ScheduledTask from storage for convenience
-pub use crate::storage::ScheduledTask;
+pub use crate::storage::{DeadLetterTask, ScheduledTask, TaskFilter, TaskOptions};
+
+/// How failed one-shot tasks are retried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts, including the first, before a task is dead-lettered.
+    pub max_attempts: u32,
+    /// Delay before the first retry; each further retry doubles it.
+    pub base_delay_ms: u64,
+    /// Upper bound on the delay between retries.
+    pub max_delay_ms: u64,
+}
+
+impl RetryPolicy {
+    /// Delay before retrying after failed attempt number `attempt`
+    /// (starting at 1).
+    pub fn backoff_ms(&self, attempt: u32) -> u64 {
+        let doublings = attempt.saturating_sub(1).min(63);
+        self.base_delay_ms
+            .saturating_mul(1 << doublings)
+            .min(self.max_delay_ms)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay_ms: 1_000,
+            max_delay_ms: 300_000,
+        }
+    }
+}
+
+/// How long a claimed task stays leased by default.
+pub const DEFAULT_LEASE_MS: u64 = 60_000;
+
+/// How many claimed tasks each worker queues by default.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 64;
 
 /// Task scheduler that executes verbs after a delay.
-pub struct Scheduler {
-    storage: Arc<Mutex<WorldStorage>>,
+///
+/// Generic over the storage backend; defaults to the libSQL-backed
+/// [`WorldStorage`].
+pub struct Scheduler<S: WorldStore = WorldStorage> {
+    storage: Arc<Mutex<S>>,
     interval_ms: u64,
+    retry: RetryPolicy,
+    lease_ms: u64,
+    clock: Arc<dyn Clock>,
+    workers: usize,
+    queue_capacity: usize,
+    verb_stats: std::sync::Mutex<BTreeMap<String, VerbStats>>,
 }
 
-impl Scheduler {
+impl<S: WorldStore> Scheduler<S> {
     /// Create a new scheduler.
     ///
     /// # Arguments
     /// * `storage` - Shared world storage
     /// * `interval_ms` - How often to check for due tasks (in milliseconds)
-    pub fn new(storage: Arc<Mutex<WorldStorage>>, interval_ms: u64) -> Self {
+    pub fn new(storage: Arc<Mutex<S>>, interval_ms: u64) -> Self {
         Self {
             storage,
             interval_ms,
+            retry: RetryPolicy::default(),
+            lease_ms: DEFAULT_LEASE_MS,
+            clock: Arc::new(SystemClock),
+            workers: 1,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            verb_stats: std::sync::Mutex::default(),
         }
     }
 
+    /// Set how failed tasks are retried.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Set how long a task stays leased while its verb runs. A task still
+    /// running when its lease ends may be delivered again.
+    pub fn with_lease_ms(mut self, lease_ms: u64) -> Self {
+        self.lease_ms = lease_ms;
+        self
+    }
+
+    /// Set the clock used to decide when tasks are due. Give the storage
+    /// the same clock (see [`WorldStorage::set_clock`]) so capability
+    /// expiry follows it too.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Set how many tasks [`run`](Self::run) executes at once (at least
+    /// one, the default).
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Set how many claimed tasks each worker of [`run`](Self::run) may
+    /// have waiting (at least one).
+    pub fn with_queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity.max(1);
+        self
+    }
+
     /// Schedule a task to execute after a delay.
     ///
     /// # Arguments
@@ -55,98 +212,470 @@ impl Scheduler {
         args: serde_json::Value,
         delay_ms: u64,
     ) -> Result<i64, SchedulerError> {
-        let execute_at = (current_time_ms() + delay_ms) as i64;
+        self.schedule_with(entity_id, verb, args, delay_ms, TaskOptions::default())
+            .await
+    }
+
+    /// Schedule a task to execute after a delay, with extra options such
+    /// as a recurrence, a dedupe key, or the caller and capability it runs
+    /// under.
+    ///
+    /// With a dedupe key, an existing task on the entity with the same key
+    /// is replaced and its id returned, so scheduling `tick` on every load
+    /// doesn't pile up ticks.
+    pub async fn schedule_with(
+        &self,
+        entity_id: i64,
+        verb: &str,
+        args: serde_json::Value,
+        delay_ms: u64,
+        options: TaskOptions,
+    ) -> Result<i64, SchedulerError> {
+        let delay_ms = i64::try_from(delay_ms).unwrap_or(i64::MAX);
+        let execute_at = self.clock.now_ms().saturating_add(delay_ms);
+        self.schedule_at(entity_id, verb, args, execute_at, options)
+            .await
+    }
+
+    /// Schedule a task for the absolute time `execute_at` (ms since epoch).
+    async fn schedule_at(
+        &self,
+        entity_id: i64,
+        verb: &str,
+        args: serde_json::Value,
+        execute_at: i64,
+        options: TaskOptions,
+    ) -> Result<i64, SchedulerError> {
+        if let Some(recurrence) = &options.recurrence {
+            recurrence
+                .validate()
+                .map_err(SchedulerError::InvalidSchedule)?;
+        }
         let storage = self.storage.lock().await;
         let task_id = storage
-            .schedule_task(entity_id, verb, args, execute_at)
+            .schedule_task_with(entity_id, verb, args, execute_at, options)
             .await?;
         Ok(task_id)
     }
 
+    /// Schedule a task to run every `interval_ms` milliseconds, starting
+    /// one interval from now.
+    ///
+    /// The scheduler re-arms the task itself before each run, so it keeps
+    /// firing whether or not the verb succeeds, until it is stopped with
+    /// [`stop_recurring`](Self::stop_recurring).
+    pub async fn schedule_recurring(
+        &self,
+        entity_id: i64,
+        verb: &str,
+        args: serde_json::Value,
+        interval_ms: u64,
+    ) -> Result<i64, SchedulerError> {
+        let options = TaskOptions::new().recurring(Recurrence::Every { interval_ms });
+        self.schedule_with(entity_id, verb, args, interval_ms, options)
+            .await
+    }
+
+    /// Schedule a task to run at every time matching the cron expression
+    /// `expr` (see [`CronSchedule`]), re-armed like
+    /// [`schedule_recurring`](Self::schedule_recurring).
+    pub async fn schedule_cron(
+        &self,
+        entity_id: i64,
+        verb: &str,
+        args: serde_json::Value,
+        expr: &str,
+    ) -> Result<i64, SchedulerError> {
+        let schedule = CronSchedule::parse(expr)?;
+        let now = self.clock.now_ms();
+        let execute_at = schedule
+            .next_after(now)
+            .ok_or_else(|| SchedulerError::InvalidSchedule(format!("'{}' never matches", expr)))?;
+        let options = TaskOptions::new().recurring(Recurrence::Cron(schedule));
+        self.schedule_at(entity_id, verb, args, execute_at, options)
+            .await
+    }
+
+    /// List recurring tasks, soonest first.
+    pub async fn recurring_tasks(&self) -> Result<Vec<ScheduledTask>, SchedulerError> {
+        self.list_tasks(&TaskFilter::new().recurring(true)).await
+    }
+
+    /// Stop a recurring task so it no longer fires.
+    pub async fn stop_recurring(&self, task_id: i64) -> Result<(), SchedulerError> {
+        self.cancel(task_id).await?;
+        Ok(())
+    }
+
+    /// List tasks matching `filter`, soonest first.
+    pub async fn list_tasks(
+        &self,
+        filter: &TaskFilter,
+    ) -> Result<Vec<ScheduledTask>, SchedulerError> {
+        let storage = self.storage.lock().await;
+        Ok(storage.list_tasks(filter).await?)
+    }
+
+    /// Cancel a task. Returns whether it existed.
+    pub async fn cancel(&self, task_id: i64) -> Result<bool, SchedulerError> {
+        let storage = self.storage.lock().await;
+        Ok(storage.delete_tasks(&TaskFilter::new().id(task_id)).await? > 0)
+    }
+
+    /// Cancel every task that would run `verb` on `entity_id`, returning
+    /// how many there were.
+    pub async fn cancel_for(&self, entity_id: i64, verb: &str) -> Result<u64, SchedulerError> {
+        let storage = self.storage.lock().await;
+        Ok(storage
+            .delete_tasks(&TaskFilter::new().entity(entity_id).verb(verb))
+            .await?)
+    }
+
+    /// Move a task to run at `execute_at` (ms since epoch) instead. Returns
+    /// whether it existed.
+    pub async fn reschedule(&self, task_id: i64, execute_at: i64) -> Result<bool, SchedulerError> {
+        let storage = self.storage.lock().await;
+        Ok(storage.reschedule_task(task_id, execute_at).await?)
+    }
+
     /// Get all tasks that are due for execution.
+    #[cfg(test)]
     async fn get_due_tasks(&self) -> Result<Vec<ScheduledTask>, SchedulerError> {
-        let now = current_time_ms() as i64;
+        let now = self.clock.now_ms();
         let storage = self.storage.lock().await;
         let tasks = storage.get_due_tasks(now).await?;
         Ok(tasks)
     }
 
-    /// Delete a task from the database.
-    async fn delete_task(&self, task_id: i64) -> Result<(), SchedulerError> {
+    /// Release leases left behind by a scheduler that stopped mid-task,
+    /// returning how many. [`run`](Self::run) does this on startup.
+    pub async fn reclaim_expired_leases(&self) -> Result<u64, SchedulerError> {
         let storage = self.storage.lock().await;
-        storage.delete_task(task_id).await?;
-        Ok(())
+        Ok(storage.reclaim_expired_leases(self.clock.now_ms()).await?)
+    }
+
+    /// Tasks that ran out of attempts, oldest failure first.
+    pub async fn dead_letters(&self) -> Result<Vec<DeadLetterTask>, SchedulerError> {
+        let storage = self.storage.lock().await;
+        Ok(storage.get_dead_letter_tasks().await?)
     }
 
     /// Process all due tasks.
     ///
-    /// This should be called periodically by the server. Tasks are executed
-    /// by calling the provided execution callback.
+    /// Tasks are executed one at a time, in the order they fell due, by
+    /// calling the provided execution callback. [`run`](Self::run) uses a
+    /// worker pool instead.
     pub async fn process<F, Fut>(&self, mut execute: F) -> Result<(), SchedulerError>
     where
         F: FnMut(ScheduledTask) -> Fut,
         Fut: std::future::Future<Output = Result<(), String>>,
     {
-        let tasks = self.get_due_tasks().await?;
-        if tasks.is_empty() {
-            return Ok(());
+        self.process_due(&mut execute).await?;
+        Ok(())
+    }
+
+    /// Run every task due up to `until` (ms since epoch) in the order they
+    /// fall due, returning how many ran.
+    ///
+    /// The clock is advanced to each task's due time before it runs, so
+    /// recurring tasks and retries that come due before `until` run too,
+    /// and it is left at `until`. Meant for tests and offline simulations
+    /// on a [`ManualClock`](crate::ManualClock); a clock that can't be
+    /// advanced only runs what is already due.
+    pub async fn run_until<F, Fut>(
+        &self,
+        until: i64,
+        mut execute: F,
+    ) -> Result<usize, SchedulerError>
+    where
+        F: FnMut(ScheduledTask) -> Fut,
+        Fut: std::future::Future<Output = Result<(), String>>,
+    {
+        let mut executed = 0;
+        loop {
+            let next = {
+                let storage = self.storage.lock().await;
+                storage
+                    .list_tasks(&TaskFilter::new().due_by(until))
+                    .await?
+                    .iter()
+                    .map(|task| task.execute_at.max(task.leased_until.unwrap_or(i64::MIN)))
+                    .filter(|&ready| ready <= until)
+                    .min()
+            };
+            let Some(next) = next else { break };
+            self.clock.advance_to(next);
+            if self.clock.now_ms() < next {
+                break;
+            }
+            let ran = self.process_due(&mut execute).await?;
+            if ran == 0 {
+                break;
+            }
+            executed += ran;
         }
+        self.clock.advance_to(until);
+        Ok(executed)
+    }
+
+    /// Claim and run the tasks due now, returning how many ran.
+    async fn process_due<F, Fut>(&self, execute: &mut F) -> Result<usize, SchedulerError>
+    where
+        F: FnMut(ScheduledTask) -> Fut,
+        Fut: std::future::Future<Output = Result<(), String>>,
+    {
+        let tasks = self.claim(usize::MAX).await?;
+        let count = tasks.len();
 
-        // Execute and delete tasks one by one
+        // Execute tasks one by one, settling each before the next
         for task in tasks {
-            // Delete task before executing to avoid re-execution on failure
-            self.delete_task(task.id).await?;
+            let run = execute(task.clone());
+            self.run_task(task, run).await?;
+        }
 
-            if let Err(e) = execute(task.clone()).await {
-                eprintln!(
-                    "[Scheduler] Error executing task {} (entity {}, verb {}): {}",
-                    task.id, task.entity_id, task.verb, e
-                );
+        Ok(count)
+    }
+
+    /// Lease up to `limit` due tasks, oldest first.
+    async fn claim(&self, limit: usize) -> Result<Vec<ScheduledTask>, SchedulerError> {
+        let now = self.clock.now_ms();
+        let storage = self.storage.lock().await;
+        Ok(storage
+            .claim_due_tasks(now, now + self.lease_ms as i64, limit)
+            .await?)
+    }
+
+    /// Hand a claimed task back without running it.
+    async fn release(&self, task: &ScheduledTask) -> Result<(), SchedulerError> {
+        let Some(leased_until) = task.leased_until else {
+            return Ok(());
+        };
+        let storage = self.storage.lock().await;
+        storage.release_task(task.id, leased_until).await?;
+        Ok(())
+    }
+
+    /// Wait for a claimed task's verb to run, then settle it.
+    async fn run_task<Fut>(&self, task: ScheduledTask, run: Fut) -> Result<(), SchedulerError>
+    where
+        Fut: std::future::Future<Output = Result<(), String>>,
+    {
+        let span = tracing::info_span!(
+            "scheduled_task",
+            task_id = task.id,
+            entity_id = task.entity_id,
+            verb = %task.verb,
+            attempt = task.attempts,
+        );
+        async move {
+            let started = Instant::now();
+            let result = run.await;
+            let elapsed = started.elapsed();
+            match &result {
+                Ok(()) => tracing::debug!(elapsed_ms = elapsed.as_millis() as u64, "task ran"),
+                Err(error) => tracing::warn!(
+                    elapsed_ms = elapsed.as_millis() as u64,
+                    %error,
+                    "task failed"
+                ),
             }
+            self.verb_stats
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .entry(task.verb.clone())
+                .or_default()
+                .record(elapsed, result.is_err());
+            self.settle(&task, result).await
         }
+        .instrument(span)
+        .await
+    }
 
+    /// Record the outcome of running a claimed task: re-arm or remove it
+    /// on success, retry or dead-letter it on failure.
+    async fn settle(
+        &self,
+        task: &ScheduledTask,
+        result: Result<(), String>,
+    ) -> Result<(), SchedulerError> {
+        let now = self.clock.now_ms();
+        let next_run = match &task.recurrence {
+            Some(recurrence) => recurrence.next_run(task.execute_at, now)?,
+            None => None,
+        };
+        let Some(leased_until) = task.leased_until else {
+            return Ok(());
+        };
+        let storage = self.storage.lock().await;
+        let settled = match result {
+            Ok(()) => {
+                storage
+                    .complete_task(task.id, leased_until, next_run)
+                    .await?
+            }
+            Err(error) => {
+                let retry_at = match task.recurrence {
+                    Some(_) => next_run,
+                    None if task.attempts < self.retry.max_attempts => {
+                        Some(now + self.retry.backoff_ms(task.attempts) as i64)
+                    }
+                    None => None,
+                };
+                let settled = storage
+                    .fail_task(task.id, leased_until, &error, retry_at, now)
+                    .await?;
+                if settled && retry_at.is_none() && task.recurrence.is_none() {
+                    tracing::error!(attempts = task.attempts, "task dead-lettered");
+                }
+                settled
+            }
+        };
+        if !settled {
+            tracing::warn!("task was replaced or its lease ran out while running; result dropped");
+        }
         Ok(())
     }
 
-    /// Run the scheduler loop.
+    /// Snapshot the queue and the per-verb counters.
+    ///
+    /// The queue figures come from storage, so they cover tasks scheduled
+    /// by every scheduler sharing it; the counters only cover tasks this
+    /// scheduler ran.
+    pub async fn stats(&self) -> Result<SchedulerStats, SchedulerError> {
+        let now = self.clock.now_ms();
+        let tasks = self.list_tasks(&TaskFilter::new()).await?;
+        let mut stats = SchedulerStats {
+            queue_depth: tasks.len(),
+            ..SchedulerStats::default()
+        };
+        for task in &tasks {
+            if task.leased_until.is_some_and(|until| until > now) {
+                stats.leased += 1;
+            } else if task.execute_at <= now {
+                stats.due += 1;
+                let lag = (now - task.execute_at) as u64;
+                stats.oldest_overdue_ms = Some(
+                    stats
+                        .oldest_overdue_ms
+                        .map_or(lag, |oldest| oldest.max(lag)),
+                );
+            }
+        }
+        stats.verbs = self
+            .verb_stats
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+        Ok(stats)
+    }
+}
+
+impl<S: WorldStore + 'static> Scheduler<S> {
+    /// Run the scheduler loop until `shutdown` is cancelled.
     ///
-    /// This continuously checks for due tasks at the configured interval
-    /// and executes them using the provided callback.
-    pub async fn run<F, Fut>(self: Arc<Self>, execute: F)
+    /// Every tick, due tasks are claimed and handed to a pool of
+    /// [`with_workers`](Self::with_workers) workers, so a slow verb only
+    /// holds up its own worker. All tasks for an entity go to the same
+    /// worker and run in the order they fell due. Each worker queues at
+    /// most [`with_queue_capacity`](Self::with_queue_capacity) tasks; no
+    /// more are claimed than the queues have room for in total, and tasks
+    /// claimed for a worker whose queue is full are released again rather
+    /// than waited on, so a busy worker never holds up the loop.
+    ///
+    /// On shutdown no new tasks are claimed; the workers finish the tasks
+    /// already handed to them and this returns once they have.
+    pub async fn run<F, Fut>(self: Arc<Self>, execute: F, shutdown: CancellationToken)
     where
         F: Fn(ScheduledTask) -> Fut + Send + Sync + 'static,
-        Fut: std::future::Future<Output = Result<(), String>> + Send,
+        Fut: std::future::Future<Output = Result<(), String>> + Send + 'static,
     {
-        let mut interval = time::interval(Duration::from_millis(self.interval_ms));
+        if let Err(error) = self.reclaim_expired_leases().await {
+            tracing::error!(%error, "failed to reclaim task leases");
+        }
         let execute = Arc::new(execute);
+        let mut queues = Vec::with_capacity(self.workers);
+        let mut workers = Vec::with_capacity(self.workers);
+        for _ in 0..self.workers {
+            let (queue, mut tasks) = mpsc::channel::<ScheduledTask>(self.queue_capacity);
+            let scheduler = Arc::clone(&self);
+            let execute = Arc::clone(&execute);
+            workers.push(tokio::spawn(async move {
+                while let Some(task) = tasks.recv().await {
+                    let task_id = task.id;
+                    let run = execute(task.clone());
+                    if let Err(error) = scheduler.run_task(task, run).await {
+                        tracing::error!(task_id, %error, "failed to settle task");
+                    }
+                }
+            }));
+            queues.push(queue);
+        }
 
+        let mut interval = time::interval(Duration::from_millis(self.interval_ms));
         loop {
-            interval.tick().await;
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = interval.tick() => {}
+            }
 
-            let exec_clone = Arc::clone(&execute);
-            if let Err(e) = self.process(|task| exec_clone(task)).await {
-                eprintln!("[Scheduler] Error processing tasks: {}", e);
+            let room: usize = queues.iter().map(|queue| queue.capacity()).sum();
+            if room == 0 {
+                continue;
+            }
+            let tasks = match self.claim(room).await {
+                Ok(tasks) => tasks,
+                Err(error) => {
+                    tracing::error!(%error, "failed to claim due tasks");
+                    continue;
+                }
+            };
+            // Room is counted across every queue, so one can be handed more
+            // than it holds. Rather than wait on it, the overflow, and every
+            // later task for that queue so entities keep their order, is
+            // released to be claimed again on a later tick.
+            let mut full = vec![false; queues.len()];
+            for task in tasks {
+                // Same entity, same worker, so its tasks stay in order
+                let shard = task.entity_id.rem_euclid(queues.len() as i64) as usize;
+                let task = if full[shard] {
+                    task
+                } else {
+                    match queues[shard].try_send(task) {
+                        Ok(()) => continue,
+                        Err(mpsc::error::TrySendError::Full(task)) => task,
+                        Err(mpsc::error::TrySendError::Closed(task)) => {
+                            tracing::error!(worker = shard, "scheduler worker stopped");
+                            task
+                        }
+                    }
+                };
+                full[shard] = true;
+                if let Err(error) = self.release(&task).await {
+                    tracing::error!(task_id = task.id, %error, "failed to release task");
+                }
             }
         }
-    }
-}
 
-/// Get current time in milliseconds since Unix epoch.
-fn current_time_ms() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("System time before Unix epoch")
-        .as_millis() as u64
+        // Closing the queues lets each worker drain its own and exit
+        drop(queues);
+        for worker in workers {
+            if let Err(error) = worker.await {
+                tracing::error!(%error, "scheduler worker failed");
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ManualClock;
 
     #[tokio::test]
     async fn test_schedule_and_retrieve() {
         let storage = Arc::new(Mutex::new(WorldStorage::in_memory().await.unwrap()));
-        let scheduler = Scheduler::new(Arc::clone(&storage), 100);
+        let clock = Arc::new(ManualClock::new(1_000));
+        let scheduler = Scheduler::new(Arc::clone(&storage), 100).with_clock(clock.clone());
 
         // Create an entity
         let entity_id = {
@@ -163,8 +692,8 @@ mod tests {
             .await
             .unwrap();
 
-        // Wait a tiny bit to ensure time passes
-        tokio::time::sleep(Duration::from_millis(10)).await;
+        // Let a little time pass
+        clock.advance(10);
 
         // Get due tasks
         let tasks = scheduler.get_due_tasks().await.unwrap();
@@ -177,7 +706,8 @@ mod tests {
     #[tokio::test]
     async fn test_process_executes_and_deletes() {
         let storage = Arc::new(Mutex::new(WorldStorage::in_memory().await.unwrap()));
-        let scheduler = Scheduler::new(Arc::clone(&storage), 100);
+        let clock = Arc::new(ManualClock::new(1_000));
+        let scheduler = Scheduler::new(Arc::clone(&storage), 100).with_clock(clock.clone());
 
         let entity_id = {
             let storage = storage.lock().await;
@@ -193,7 +723,7 @@ mod tests {
             .await
             .unwrap();
 
-        tokio::time::sleep(Duration::from_millis(10)).await;
+        clock.advance(10);
 
         // Process tasks with a simple callback
         let mut executed = false;
@@ -248,4 +778,782 @@ mod tests {
         let tasks = scheduler.get_due_tasks().await.unwrap();
         assert_eq!(tasks.len(), 0, "Future task not yet due");
     }
+
+    #[tokio::test]
+    async fn test_process_with_memory_store() {
+        let storage = Arc::new(Mutex::new(crate::MemoryStore::new()));
+        let scheduler = Scheduler::new(Arc::clone(&storage), 100);
+
+        let entity_id = {
+            let storage = storage.lock().await;
+            storage
+                .create_entity(serde_json::json!({"name": "Test"}), None)
+                .await
+                .unwrap()
+        };
+
+        scheduler
+            .schedule(entity_id, "tick", serde_json::json!([]), 0)
+            .await
+            .unwrap();
+
+        let mut executed = Vec::new();
+        scheduler
+            .process(|task| {
+                executed.push(task.verb);
+                async { Ok(()) }
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(executed, vec!["tick".to_string()]);
+        assert!(scheduler.get_due_tasks().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_recurring_task_survives_failure() {
+        let storage = Arc::new(Mutex::new(WorldStorage::in_memory().await.unwrap()));
+        let clock = Arc::new(ManualClock::new(1_000));
+        let scheduler = Scheduler::new(Arc::clone(&storage), 100).with_clock(clock.clone());
+
+        let entity_id = {
+            let storage = storage.lock().await;
+            storage
+                .create_entity(serde_json::json!({"name": "Clock"}), None)
+                .await
+                .unwrap()
+        };
+
+        let task_id = scheduler
+            .schedule_recurring(entity_id, "tick", serde_json::json!([]), 5)
+            .await
+            .unwrap();
+        assert!(
+            scheduler
+                .schedule_recurring(entity_id, "tick", serde_json::json!([]), 0)
+                .await
+                .is_err()
+        );
+
+        for _ in 0..2 {
+            clock.advance(10);
+            let mut executed = 0;
+            scheduler
+                .process(|_task| {
+                    executed += 1;
+                    async { Err("tick failed".to_string()) }
+                })
+                .await
+                .unwrap();
+            assert_eq!(executed, 1);
+        }
+
+        // Still armed, in the future
+        let recurring = scheduler.recurring_tasks().await.unwrap();
+        assert_eq!(recurring.len(), 1);
+        assert_eq!(recurring[0].id, task_id);
+        assert_eq!(
+            recurring[0].recurrence,
+            Some(Recurrence::Every { interval_ms: 5 })
+        );
+        assert_eq!(recurring[0].execute_at, 1_025);
+
+        scheduler.stop_recurring(task_id).await.unwrap();
+        assert!(scheduler.recurring_tasks().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_schedule_cron() {
+        let storage = Arc::new(Mutex::new(crate::MemoryStore::new()));
+        // 2024-03-15 10:07:30 UTC
+        let clock = Arc::new(ManualClock::new(1_710_497_250_000));
+        let scheduler = Scheduler::new(Arc::clone(&storage), 100).with_clock(clock.clone());
+
+        let entity_id = {
+            let storage = storage.lock().await;
+            storage
+                .create_entity(serde_json::json!({"name": "ClockTower"}), None)
+                .await
+                .unwrap()
+        };
+
+        let task_id = scheduler
+            .schedule_cron(entity_id, "chime", serde_json::json!([]), "0 * * * *")
+            .await
+            .unwrap();
+        let recurring = scheduler.recurring_tasks().await.unwrap();
+        assert_eq!(recurring[0].id, task_id);
+        assert_eq!(recurring[0].execute_at, 1_710_500_400_000);
+
+        assert!(matches!(
+            scheduler
+                .schedule_cron(entity_id, "chime", serde_json::json!([]), "0 0 30 2 *")
+                .await,
+            Err(SchedulerError::InvalidSchedule(_))
+        ));
+    }
+
+    /// A clock that moves on a second every time it is read.
+    struct TickingClock(std::sync::atomic::AtomicI64);
+
+    impl Clock for TickingClock {
+        fn now_ms(&self) -> i64 {
+            self.0.fetch_add(1_000, std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_schedule_cron_keeps_matching_time() {
+        let storage = Arc::new(Mutex::new(crate::MemoryStore::new()));
+        // 2024-03-15 10:59:59.500 UTC, just before the hour
+        let clock = Arc::new(TickingClock(1_710_500_399_500.into()));
+        let scheduler = Scheduler::new(Arc::clone(&storage), 100).with_clock(clock);
+
+        let entity_id = storage
+            .lock()
+            .await
+            .create_entity(serde_json::json!({}), None)
+            .await
+            .unwrap();
+        scheduler
+            .schedule_cron(entity_id, "chime", serde_json::json!([]), "0 * * * *")
+            .await
+            .unwrap();
+        let recurring = scheduler.recurring_tasks().await.unwrap();
+        assert_eq!(recurring[0].execute_at, 1_710_500_400_000);
+    }
+
+    #[tokio::test]
+    async fn test_recurrence_rejects_bad_intervals() {
+        let every = |interval_ms| Recurrence::Every { interval_ms };
+        assert!(matches!(
+            every(0).next_run(1_000, 2_000),
+            Err(SchedulerError::InvalidSchedule(_))
+        ));
+        assert!(matches!(
+            every(u64::MAX).next_run(1_000, 2_000),
+            Err(SchedulerError::InvalidSchedule(_))
+        ));
+        assert_eq!(every(10).next_run(1_000, 1_025).unwrap(), Some(1_030));
+        // Past the end of time, it never runs again
+        assert_eq!(every(i64::MAX as u64).next_run(1_000, 1_000).unwrap(), None);
+
+        let storage = Arc::new(Mutex::new(crate::MemoryStore::new()));
+        let scheduler = Scheduler::new(Arc::clone(&storage), 100);
+        for interval_ms in [0, i64::MAX as u64 + 1, u64::MAX] {
+            assert!(matches!(
+                scheduler
+                    .schedule_recurring(1, "tick", serde_json::json!([]), interval_ms)
+                    .await,
+                Err(SchedulerError::InvalidSchedule(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_cron_next_after() {
+        // 2024-03-15 10:07:30 UTC, a Friday
+        let now = 1_710_497_250_000;
+        let minute = 60_000;
+        let hour = 60 * minute;
+        let day = 24 * hour;
+        let next = |expr: &str| CronSchedule::parse(expr).unwrap().next_after(now);
+
+        assert_eq!(next("* * * * *"), Some(now - 30_000 + minute));
+        assert_eq!(
+            next("*/15 * * * *"),
+            Some(now - 7 * minute - 30_000 + 15 * minute)
+        );
+        assert_eq!(next("0 * * * *"), Some(now - 7 * minute - 30_000 + hour));
+        // Midnight on Monday the 18th
+        let midnight = now - 10 * hour - 7 * minute - 30_000;
+        assert_eq!(next("0 0 * * 1"), Some(midnight + 3 * day));
+        // First of April, and Sundays count as 0 or 7
+        assert_eq!(next("@monthly"), Some(midnight + 17 * day));
+        assert_eq!(next("0 0 * * 7"), next("0 0 * * 0"));
+        // Either day field matches when both are restricted
+        assert_eq!(next("0 0 1 * 1"), Some(midnight + 3 * day));
+        // Leap day
+        let leap = CronSchedule::parse("0 12 29 2 *").unwrap();
+        assert_eq!(leap.next_after(now), Some(1_835_438_400_000));
+
+        assert_eq!(next("0 0 30 2 *"), None);
+        assert_eq!(
+            CronSchedule::parse("5 4 * * *").unwrap().as_str(),
+            "5 4 * * *"
+        );
+        for bad in [
+            "",
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "0 0 * * MON",
+        ] {
+            assert!(
+                CronSchedule::parse(bad).is_err(),
+                "{:?} should not parse",
+                bad
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_list_and_reschedule() {
+        let storage = Arc::new(Mutex::new(crate::MemoryStore::new()));
+        let scheduler = Scheduler::new(Arc::clone(&storage), 100);
+
+        let (clock, player) = {
+            let storage = storage.lock().await;
+            (
+                storage
+                    .create_entity(serde_json::json!({}), None)
+                    .await
+                    .unwrap(),
+                storage
+                    .create_entity(serde_json::json!({}), None)
+                    .await
+                    .unwrap(),
+            )
+        };
+
+        // Reloading a seed re-schedules its tick under the same key
+        let tick = TaskOptions::new().dedupe_key("tick");
+        let first = scheduler
+            .schedule_with(clock, "tick", serde_json::json!([]), 10_000, tick.clone())
+            .await
+            .unwrap();
+        let second = scheduler
+            .schedule_with(clock, "tick", serde_json::json!([]), 20_000, tick)
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+        let chime = scheduler
+            .schedule(clock, "chime", serde_json::json!([]), 30_000)
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            scheduler
+                .schedule(player, "tick", serde_json::json!([]), 40_000)
+                .await
+                .unwrap();
+        }
+
+        let clock_tasks = scheduler
+            .list_tasks(&TaskFilter::new().entity(clock))
+            .await
+            .unwrap();
+        let ids: Vec<_> = clock_tasks.iter().map(|now| now.id).collect();
+        assert_eq!(ids, vec![first, chime]);
+
+        // Bring the chime forward so it is due
+        assert!(scheduler.reschedule(chime, 0).await.unwrap());
+        assert!(!scheduler.reschedule(999, 0).await.unwrap());
+        let due = scheduler.get_due_tasks().await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, chime);
+
+        assert!(scheduler.cancel(chime).await.unwrap());
+        assert!(!scheduler.cancel(chime).await.unwrap());
+        assert_eq!(scheduler.cancel_for(player, "tick").await.unwrap(), 2);
+        let remaining = scheduler.list_tasks(&TaskFilter::new()).await.unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, first);
+    }
+
+    #[tokio::test]
+    async fn test_failed_tasks_retry_then_dead_letter() {
+        let storage = Arc::new(Mutex::new(WorldStorage::in_memory().await.unwrap()));
+        let scheduler = Scheduler::new(Arc::clone(&storage), 100).with_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            base_delay_ms: 0,
+            max_delay_ms: 0,
+        });
+
+        let entity_id = {
+            let storage = storage.lock().await;
+            storage
+                .create_entity(serde_json::json!({"name": "Flaky"}), None)
+                .await
+                .unwrap()
+        };
+        let task_id = scheduler
+            .schedule(entity_id, "flaky", serde_json::json!([]), 0)
+            .await
+            .unwrap();
+
+        let fail = |task: ScheduledTask| async move { Err(format!("attempt {}", task.attempts)) };
+        scheduler.process(fail).await.unwrap();
+        let tasks = scheduler.list_tasks(&TaskFilter::new()).await.unwrap();
+        assert_eq!(tasks[0].id, task_id);
+        assert_eq!(tasks[0].last_error.as_deref(), Some("attempt 1"));
+
+        scheduler.process(fail).await.unwrap();
+        scheduler.process(fail).await.unwrap();
+        assert!(
+            scheduler
+                .list_tasks(&TaskFilter::new())
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        let dead = scheduler.dead_letters().await.unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].task_id, task_id);
+        assert_eq!(dead[0].attempts, 3);
+        assert_eq!(dead[0].last_error.as_deref(), Some("attempt 3"));
+
+        // A retry that succeeds completes the task
+        scheduler
+            .schedule(entity_id, "flaky", serde_json::json!([]), 0)
+            .await
+            .unwrap();
+        scheduler.process(fail).await.unwrap();
+        scheduler.process(|_task| async { Ok(()) }).await.unwrap();
+        assert!(
+            scheduler
+                .list_tasks(&TaskFilter::new())
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(scheduler.dead_letters().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_leased_tasks_are_not_redelivered_until_reclaimed() {
+        let storage = Arc::new(Mutex::new(crate::MemoryStore::new()));
+        let clock = Arc::new(ManualClock::new(1_000));
+        let scheduler = Scheduler::new(Arc::clone(&storage), 100).with_clock(clock.clone());
+
+        let entity_id = {
+            let storage = storage.lock().await;
+            storage
+                .create_entity(serde_json::json!({}), None)
+                .await
+                .unwrap()
+        };
+        scheduler
+            .schedule(entity_id, "tick", serde_json::json!([]), 0)
+            .await
+            .unwrap();
+
+        // Another scheduler claimed the task and died before finishing it
+        let now = clock.now_ms();
+        {
+            let storage = storage.lock().await;
+            storage
+                .claim_due_tasks(now, now + 60_000, usize::MAX)
+                .await
+                .unwrap();
+        }
+        let mut executed = 0;
+        scheduler
+            .process(|_task| {
+                executed += 1;
+                async { Ok(()) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(executed, 0);
+        assert_eq!(scheduler.reclaim_expired_leases().await.unwrap(), 0);
+
+        // One whose lease has run out is delivered again
+        let stalled = scheduler
+            .schedule(entity_id, "tock", serde_json::json!([]), 0)
+            .await
+            .unwrap();
+        let now = clock.now_ms();
+        {
+            let storage = storage.lock().await;
+            let claimed = storage
+                .claim_due_tasks(now, now - 1, usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(claimed.len(), 1);
+        }
+        assert_eq!(scheduler.reclaim_expired_leases().await.unwrap(), 1);
+        scheduler
+            .process(|task| {
+                executed += 1;
+                assert_eq!(task.id, stalled);
+                assert_eq!(task.attempts, 2);
+                async { Ok(()) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(executed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_until_drains_in_order() {
+        let hour = 3_600_000;
+        let clock = Arc::new(ManualClock::new(0));
+        let mut world = WorldStorage::in_memory().await.unwrap();
+        world.set_clock(clock.clone());
+        let storage = Arc::new(Mutex::new(world));
+        let scheduler = Scheduler::new(Arc::clone(&storage), 100)
+            .with_clock(clock.clone())
+            .with_retry_policy(RetryPolicy {
+                max_attempts: 3,
+                base_delay_ms: 1_000,
+                max_delay_ms: 1_000,
+            });
+
+        let (entity_id, cap_id) = {
+            let storage = storage.lock().await;
+            let entity_id = storage
+                .create_entity(serde_json::json!({"name": "Sundial"}), None)
+                .await
+                .unwrap();
+            let cap_id = storage
+                .create_capability_with_validity(
+                    entity_id,
+                    "entity.control",
+                    serde_json::json!({"target_id": entity_id}),
+                    None,
+                    Some(12 * hour),
+                )
+                .await
+                .unwrap();
+            (entity_id, cap_id)
+        };
+        scheduler
+            .schedule_recurring(entity_id, "tick", serde_json::json!([]), 6 * hour as u64)
+            .await
+            .unwrap();
+        scheduler
+            .schedule(entity_id, "flaky", serde_json::json!([]), 5 * hour as u64)
+            .await
+            .unwrap();
+        scheduler
+            .schedule(
+                entity_id,
+                "late",
+                serde_json::json!([]),
+                2 * 24 * hour as u64,
+            )
+            .await
+            .unwrap();
+
+        let mut runs = Vec::new();
+        let ran = scheduler
+            .run_until(24 * hour, |task| {
+                runs.push((task.verb.clone(), clock.now_ms()));
+                let result = match task.verb.as_str() {
+                    "flaky" if task.attempts < 3 => Err("not yet".to_string()),
+                    _ => Ok(()),
+                };
+                async move { result }
+            })
+            .await
+            .unwrap();
+
+        let expected: Vec<(String, i64)> = vec![
+            ("flaky".into(), 5 * hour),
+            ("flaky".into(), 5 * hour + 1_000),
+            ("flaky".into(), 5 * hour + 2_000),
+            ("tick".into(), 6 * hour),
+            ("tick".into(), 12 * hour),
+            ("tick".into(), 18 * hour),
+            ("tick".into(), 24 * hour),
+        ];
+        assert_eq!(runs, expected);
+        assert_eq!(ran, expected.len());
+        assert_eq!(clock.now_ms(), 24 * hour);
+
+        // The storage followed the same clock, so the capability expired
+        let storage = storage.lock().await;
+        let caps = storage.get_capabilities(entity_id).await.unwrap();
+        assert!(caps.iter().all(|cap| cap.id != cap_id));
+        let pending = storage.list_tasks(&TaskFilter::new()).await.unwrap();
+        let verbs: Vec<_> = pending.iter().map(|now| now.verb.as_str()).collect();
+        assert_eq!(verbs, vec!["tick", "late"]);
+    }
+
+    #[tokio::test]
+    async fn test_run_workers_in_parallel_with_per_entity_order() {
+        let storage = Arc::new(Mutex::new(crate::MemoryStore::new()));
+        let scheduler = Arc::new(
+            Scheduler::new(Arc::clone(&storage), 5)
+                .with_workers(2)
+                .with_queue_capacity(2),
+        );
+
+        let (walker, sleeper) = {
+            let storage = storage.lock().await;
+            (
+                storage
+                    .create_entity(serde_json::json!({}), None)
+                    .await
+                    .unwrap(),
+                storage
+                    .create_entity(serde_json::json!({}), None)
+                    .await
+                    .unwrap(),
+            )
+        };
+        assert_ne!(walker % 2, sleeper % 2);
+        for step in 0..3 {
+            scheduler
+                .schedule(walker, "step", serde_json::json!([step]), 0)
+                .await
+                .unwrap();
+        }
+        scheduler
+            .schedule(sleeper, "nap", serde_json::json!([]), 0)
+            .await
+            .unwrap();
+
+        // The nap and the first step only get past the barrier if they run
+        // at the same time
+        let barrier = Arc::new(tokio::sync::Barrier::new(2));
+        let (done, mut finished) = mpsc::unbounded_channel();
+        let shutdown = CancellationToken::new();
+        let run = tokio::spawn(Arc::clone(&scheduler).run(
+            move |task| {
+                let barrier = Arc::clone(&barrier);
+                let done = done.clone();
+                async move {
+                    if task.verb == "nap" || task.args == serde_json::json!([0]) {
+                        barrier.wait().await;
+                    }
+                    time::sleep(Duration::from_millis(5)).await;
+                    done.send((task.entity_id, task.args)).unwrap();
+                    Ok(())
+                }
+            },
+            shutdown.clone(),
+        ));
+
+        let mut steps = Vec::new();
+        for _ in 0..4 {
+            let (entity_id, args) = time::timeout(Duration::from_secs(5), finished.recv())
+                .await
+                .expect("independent tasks should run concurrently")
+                .unwrap();
+            if entity_id == walker {
+                steps.push(args[0].as_i64().unwrap());
+            }
+        }
+        assert_eq!(steps, vec![0, 1, 2]);
+
+        shutdown.cancel();
+        run.await.unwrap();
+        assert!(
+            scheduler
+                .list_tasks(&TaskFilter::new())
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_releases_tasks_for_a_full_worker() {
+        let storage = Arc::new(Mutex::new(crate::MemoryStore::new()));
+        // Only the first tick falls within the test
+        let scheduler = Arc::new(
+            Scheduler::new(Arc::clone(&storage), 60_000)
+                .with_workers(2)
+                .with_queue_capacity(1),
+        );
+
+        let (first, second) = {
+            let storage = storage.lock().await;
+            let mut ids = Vec::new();
+            for _ in 0..3 {
+                ids.push(
+                    storage
+                        .create_entity(serde_json::json!({}), None)
+                        .await
+                        .unwrap(),
+                );
+            }
+            (ids[0], ids[2])
+        };
+        assert_eq!(first % 2, second % 2);
+        for entity_id in [first, second] {
+            scheduler
+                .schedule(entity_id, "hold", serde_json::json!([]), 0)
+                .await
+                .unwrap();
+        }
+
+        // Both tasks are claimed but go to the same worker, which only
+        // has room for one
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        let (started, mut running) = mpsc::unbounded_channel();
+        let shutdown = CancellationToken::new();
+        let run = tokio::spawn(Arc::clone(&scheduler).run(
+            {
+                let gate = Arc::clone(&gate);
+                move |task| {
+                    started.send(task.id).unwrap();
+                    let gate = Arc::clone(&gate);
+                    async move {
+                        let _permit = gate.acquire().await.unwrap();
+                        Ok(())
+                    }
+                }
+            },
+            shutdown.clone(),
+        ));
+
+        let running = time::timeout(Duration::from_secs(5), running.recv())
+            .await
+            .expect("a task should start")
+            .unwrap();
+        let pending = scheduler.list_tasks(&TaskFilter::new()).await.unwrap();
+        let released: Vec<_> = pending.iter().filter(|now| now.id != running).collect();
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].leased_until, None);
+        assert_eq!(released[0].attempts, 0);
+
+        gate.add_permits(1);
+        shutdown.cancel();
+        time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("run should stop once cancelled")
+            .unwrap();
+        let pending = scheduler.list_tasks(&TaskFilter::new()).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, released[0].id);
+    }
+
+    #[tokio::test]
+    async fn test_run_shutdown_finishes_in_flight_tasks() {
+        let storage = Arc::new(Mutex::new(WorldStorage::in_memory().await.unwrap()));
+        let scheduler = Arc::new(Scheduler::new(Arc::clone(&storage), 5));
+
+        let entity_id = {
+            let storage = storage.lock().await;
+            storage
+                .create_entity(serde_json::json!({}), None)
+                .await
+                .unwrap()
+        };
+        scheduler
+            .schedule(entity_id, "slow", serde_json::json!([]), 0)
+            .await
+            .unwrap();
+
+        let started = Arc::new(tokio::sync::Notify::new());
+        let shutdown = CancellationToken::new();
+        let run = tokio::spawn(Arc::clone(&scheduler).run(
+            {
+                let started = Arc::clone(&started);
+                move |_task| {
+                    started.notify_one();
+                    async {
+                        time::sleep(Duration::from_millis(50)).await;
+                        Ok(())
+                    }
+                }
+            },
+            shutdown.clone(),
+        ));
+
+        started.notified().await;
+        shutdown.cancel();
+        time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("run should stop once cancelled")
+            .unwrap();
+
+        // The task finished rather than being left leased
+        assert!(
+            scheduler
+                .list_tasks(&TaskFilter::new())
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let storage = Arc::new(Mutex::new(crate::MemoryStore::new()));
+        let clock = Arc::new(ManualClock::new(10_000));
+        let scheduler = Scheduler::new(Arc::clone(&storage), 100).with_clock(clock.clone());
+
+        let entity_id = {
+            let storage = storage.lock().await;
+            storage
+                .create_entity(serde_json::json!({}), None)
+                .await
+                .unwrap()
+        };
+        for verb in ["tick", "tick", "boom"] {
+            scheduler
+                .schedule(entity_id, verb, serde_json::json!([]), 0)
+                .await
+                .unwrap();
+        }
+        scheduler
+            .schedule(entity_id, "later", serde_json::json!([]), 60_000)
+            .await
+            .unwrap();
+
+        clock.advance(1_500);
+        let stats = scheduler.stats().await.unwrap();
+        assert_eq!(stats.queue_depth, 4);
+        assert_eq!(stats.due, 3);
+        assert_eq!(stats.leased, 0);
+        assert_eq!(stats.oldest_overdue_ms, Some(1_500));
+        assert!(stats.verbs.is_empty());
+
+        scheduler
+            .process(|task| async move {
+                match task.verb.as_str() {
+                    "boom" => Err("boom".to_string()),
+                    _ => Ok(()),
+                }
+            })
+            .await
+            .unwrap();
+
+        // The failed task waits for its retry, which isn'now due yet
+        let stats = scheduler.stats().await.unwrap();
+        assert_eq!(stats.queue_depth, 2);
+        assert_eq!(stats.due, 0);
+        assert_eq!(stats.oldest_overdue_ms, None);
+        let tick = &stats.verbs["tick"];
+        assert_eq!((tick.executed, tick.failed), (2, 0));
+        assert_eq!(tick.latency.count(), 2);
+        let boom = &stats.verbs["boom"];
+        assert_eq!((boom.executed, boom.failed), (1, 1));
+        assert!(!stats.verbs.contains_key("later"));
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.quantile_ms(0.5), None);
+        for ms in [0, 3, 3, 40, 20_000] {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.counts()[0], 1);
+        assert_eq!(histogram.counts()[1], 2);
+        assert_eq!(histogram.counts()[LATENCY_BUCKETS_MS.len()], 1);
+        assert_eq!(histogram.sum_ms(), 20_046);
+        assert_eq!(histogram.max_ms(), 20_000);
+        assert_eq!(histogram.quantile_ms(0.5), Some(5));
+        assert_eq!(histogram.quantile_ms(0.8), Some(50));
+        assert_eq!(histogram.quantile_ms(1.0), Some(20_000));
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay_ms: 100,
+            max_delay_ms: 1_000,
+        };
+        let delays: Vec<_> = (1..=6).map(|attempt| policy.backoff_ms(attempt)).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1_000, 1_000]);
+        assert_eq!(policy.backoff_ms(u32::MAX), 1_000);
+    }
 }
@@ -0,0 +1,197 @@
+//! Cron expressions for recurring tasks.
+//!
+//! Standard five-field expressions (`minute hour day-of-month month
+//! day-of-week`), evaluated in UTC. Each field accepts `*`, single values,
+//! ranges (`1-5`), steps (`*/15`, `0-30/10`) and comma-separated lists of
+//! those. Day-of-week runs 0-6 from Sunday; 7 is also Sunday. As in
+//! Vixie cron, when both day fields are restricted a day matching either
+//! one matches. The shorthands `@hourly`, `@daily`, `@weekly`, `@monthly`
+//! and `@yearly` are accepted too.
+
+use std::fmt;
+
+use super::SchedulerError;
+
+const MINUTE_MS: i64 = 60_000;
+const HOUR_MS: i64 = 60 * MINUTE_MS;
+const DAY_MS: i64 = 24 * HOUR_MS;
+
+/// How far ahead to look for a match before deciding there is none, as
+/// for `0 0 30 2 *`.
+const SEARCH_LIMIT_MS: i64 = 5 * 366 * DAY_MS;
+
+/// A parsed cron expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    /// Whether day-of-month and day-of-week were both restricted.
+    either_day: bool,
+}
+
+impl CronSchedule {
+    /// Parse a cron expression.
+    pub fn parse(expr: &str) -> Result<Self, SchedulerError> {
+        let expanded = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minute, hour, dom, month, dow] = fields[..] else {
+            return Err(invalid(expr, "expected 5 fields"));
+        };
+
+        let mut days_of_week = parse_field(expr, dow, 0, 7)?;
+        // 7 is Sunday as well
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week = (days_of_week | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            source: expr.trim().to_string(),
+            minutes: parse_field(expr, minute, 0, 59)?,
+            hours: parse_field(expr, hour, 0, 23)?,
+            days_of_month: parse_field(expr, dom, 1, 31)?,
+            months: parse_field(expr, month, 1, 12)?,
+            days_of_week,
+            either_day: !dom.starts_with('*') && !dow.starts_with('*'),
+        })
+    }
+
+    /// The expression as written.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// First matching minute strictly after `after` (ms since epoch), or
+    /// `None` if nothing matches within the next five years.
+    pub fn next_after(&self, after: i64) -> Option<i64> {
+        let mut candidate = after.div_euclid(MINUTE_MS) * MINUTE_MS + MINUTE_MS;
+        let limit = candidate + SEARCH_LIMIT_MS;
+        while candidate < limit {
+            let days = candidate.div_euclid(DAY_MS);
+            let (year, month, day) = civil_from_days(days);
+            if !has(self.months, month) {
+                candidate = if month == 12 {
+                    days_from_civil(year + 1, 1, 1)
+                } else {
+                    days_from_civil(year, month + 1, 1)
+                } * DAY_MS;
+                continue;
+            }
+            if !self.day_matches(day, (days + 4).rem_euclid(7) as u32) {
+                candidate = (days + 1) * DAY_MS;
+                continue;
+            }
+            let in_day = candidate - days * DAY_MS;
+            let hour = (in_day / HOUR_MS) as u32;
+            if !has(self.hours, hour) {
+                candidate = days * DAY_MS + (hour as i64 + 1) * HOUR_MS;
+                continue;
+            }
+            let minute = ((in_day % HOUR_MS) / MINUTE_MS) as u32;
+            if !has(self.minutes, minute) {
+                candidate += MINUTE_MS;
+                continue;
+            }
+            return Some(candidate);
+        }
+        None
+    }
+
+    fn day_matches(&self, day_of_month: u32, day_of_week: u32) -> bool {
+        let dom = has(self.days_of_month, day_of_month);
+        let dow = has(self.days_of_week, day_of_week);
+        if self.either_day {
+            dom || dow
+        } else {
+            dom && dow
+        }
+    }
+}
+
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str(&self.source)
+    }
+}
+
+fn invalid(expr: &str, message: &str) -> SchedulerError {
+    SchedulerError::InvalidSchedule(format!("'{}': {}", expr, message))
+}
+
+fn has(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+/// Parse one field into a bitmask of the values it allows.
+fn parse_field(expr: &str, field: &str, min: u32, max: u32) -> Result<u64, SchedulerError> {
+    let number = |text: &str| -> Result<u32, SchedulerError> {
+        match text.parse::<u32>() {
+            Ok(value) if (min..=max).contains(&value) => Ok(value),
+            _ => Err(invalid(
+                expr,
+                &format!("'{}' is not in {}-{}", text, min, max),
+            )),
+        }
+    };
+
+    let mut set = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(invalid(expr, &format!("bad step in '{}'", part))),
+            },
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (number(start)?, number(end)?)
+        } else {
+            let first = number(range)?;
+            // `5/15` means "from 5 every 15"
+            (first, if step > 1 { max } else { first })
+        };
+        if start > end {
+            return Err(invalid(expr, &format!("empty range '{}'", range)));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+/// Convert days since the Unix epoch to a (year, month, day) date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let shifted = days + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let doe = shifted.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Convert a (year, month, day) date to days since the Unix epoch.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
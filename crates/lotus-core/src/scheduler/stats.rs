@@ -0,0 +1,107 @@
+//! Scheduler metrics.
+//!
+//! [`Scheduler::stats`](super::Scheduler::stats) combines what is waiting
+//! in storage with counters the scheduler keeps in memory since it was
+//! created: runs, failures and how long each verb took.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Upper bounds, in milliseconds, of the [`LatencyHistogram`] buckets.
+/// Anything slower lands in a final overflow bucket.
+pub const LATENCY_BUCKETS_MS: [u64; 12] =
+    [1, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+/// A snapshot of the scheduler's state.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SchedulerStats {
+    /// Tasks scheduled, whether due or not.
+    pub queue_depth: usize,
+    /// Tasks due but not yet claimed.
+    pub due: usize,
+    /// Tasks claimed and still running.
+    pub leased: usize,
+    /// How far behind the longest-waiting due task is, if any is waiting.
+    pub oldest_overdue_ms: Option<u64>,
+    /// Counters per verb, for verbs that have run.
+    pub verbs: BTreeMap<String, VerbStats>,
+}
+
+/// Counters for one verb.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerbStats {
+    /// Times the verb ran.
+    pub executed: u64,
+    /// How many of those runs failed.
+    pub failed: u64,
+    /// How long the runs took.
+    pub latency: LatencyHistogram,
+}
+
+impl VerbStats {
+    pub(super) fn record(&mut self, elapsed: Duration, failed: bool) {
+        self.executed += 1;
+        if failed {
+            self.failed += 1;
+        }
+        self.latency.record(elapsed);
+    }
+}
+
+/// Execution times bucketed by [`LATENCY_BUCKETS_MS`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LatencyHistogram {
+    counts: [u64; LATENCY_BUCKETS_MS.len() + 1],
+    sum_ms: u64,
+    max_ms: u64,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let ms = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| ms <= bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.counts[bucket] += 1;
+        self.sum_ms = self.sum_ms.saturating_add(ms);
+        self.max_ms = self.max_ms.max(ms);
+    }
+
+    /// Runs per bucket; the last entry counts runs slower than every bound.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn sum_ms(&self) -> u64 {
+        self.sum_ms
+    }
+
+    pub fn max_ms(&self) -> u64 {
+        self.max_ms
+    }
+
+    /// Upper bound of the bucket holding `quantile` (0.0 to 1.0), or
+    /// the slowest run if that falls in the overflow bucket. `None` if
+    /// nothing has been recorded.
+    pub fn quantile_ms(&self, quantile: f64) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &bucket_count) in self.counts.iter().enumerate() {
+            seen += bucket_count;
+            if seen >= rank {
+                let bound = LATENCY_BUCKETS_MS.get(bucket).copied().unwrap_or(u64::MAX);
+                return Some(bound.min(self.max_ms));
+            }
+        }
+        Some(self.max_ms)
+    }
+}
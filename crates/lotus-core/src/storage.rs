@@ -1,9 +1,45 @@
 //! SQLite storage layer.
 
+use std::sync::Arc;
+
 use libsql::{Connection, Database, params};
 use thiserror::Error;
 
+use crate::capability::{CapabilityRegistry, CapabilityType};
+use crate::clock::{Clock, SystemClock};
 use crate::entity::{Entity, EntityId, Verb};
+use crate::store::WorldStore;
+
+mod audit;
+mod authorize;
+mod changes;
+mod containment;
+pub(crate) mod delegation;
+mod history;
+mod indexes;
+mod migrations;
+mod patch;
+pub(crate) mod prototype;
+mod query;
+mod relations;
+mod tasks;
+#[cfg(feature = "tokens")]
+mod tokens;
+mod transaction;
+
+use audit::NewAuditEntry;
+pub use audit::{AuditAction, AuditEntry, AuditQuery};
+pub use authorize::{Authorized, Denied};
+pub use changes::{CHANGE_FEED_CAPACITY, ChangeEvent};
+pub use history::{EntityRevision, PropChange, RevisionKind, RevisionPoint};
+pub use indexes::PropIndex;
+pub use migrations::SCHEMA_VERSION;
+pub use patch::{PatchOperation, PropsPatch, apply_json_patch, apply_merge_patch};
+pub use prototype::{DEFAULT_MAX_PROTOTYPE_DEPTH, WorldIssue};
+pub use query::{EntityQuery, Filter, Order};
+pub use relations::Relation;
+pub use tasks::{DeadLetterTask, ScheduledTask, TaskFilter, TaskOptions};
+pub use transaction::Transaction;
 
 #[derive(Debug, Error)]
 pub enum StorageError {
@@ -18,6 +54,55 @@ pub enum StorageError {
 
     #[error("transaction error: {0}")]
     Transaction(String),
+
+    #[error("constraint violation: {0}")]
+    Constraint(String),
+
+    #[error("database schema version {found} is newer than supported version {supported}")]
+    SchemaTooNew { found: u32, supported: u32 },
+
+    #[error("migration to schema version {version} failed: {message}")]
+    Migration { version: u32, message: String },
+
+    #[error("revision {revision} not found for entity {entity_id}")]
+    RevisionNotFound { entity_id: EntityId, revision: i64 },
+
+    #[error("invalid patch: {0}")]
+    InvalidPatch(String),
+
+    #[error("setting prototype of {id} to {prototype_id} would create a cycle")]
+    PrototypeCycle {
+        id: EntityId,
+        prototype_id: EntityId,
+    },
+
+    #[error("prototype chain exceeds maximum depth of {max_depth}")]
+    PrototypeChainTooDeep { max_depth: usize },
+
+    #[error("entity {0} is the prototype of other entities")]
+    PrototypeInUse(EntityId),
+
+    #[error("invalid query: {0}")]
+    InvalidQuery(String),
+
+    #[error("moving {id} into {location} would create a containment cycle")]
+    ContainmentCycle { id: EntityId, location: EntityId },
+
+    #[error("capability not found: {0}")]
+    CapabilityNotFound(String),
+
+    #[error("invalid delegation: {0}")]
+    InvalidDelegation(String),
+
+    #[error("invalid capability: {0}")]
+    InvalidCapability(String),
+
+    #[error("invalid schedule: {0}")]
+    InvalidSchedule(String),
+
+    #[cfg(feature = "tokens")]
+    #[error("invalid token: {0}")]
+    Token(#[from] crate::capability::TokenError),
 }
 
 /// World storage backed by libSQL.
@@ -27,170 +112,128 @@ pub struct WorldStorage {
     db: Database,
     /// Transaction depth for nested savepoints.
     transaction_depth: usize,
+    /// Names of the open savepoints, innermost last.
+    savepoints: Vec<String>,
+    /// Number used to name the next savepoint.
+    savepoint_seq: u64,
+    /// Identifier of the outermost open transaction, recorded in revisions.
+    transaction_id: Option<String>,
+    /// Entity on whose behalf writes are made, recorded in revisions.
+    actor: Option<EntityId>,
+    /// Subscribers to committed changes.
+    changes: changes::ChangeFeed,
+    /// Maximum number of prototype hops followed by lineage queries.
+    max_prototype_depth: usize,
+    /// Time source for capability validity checks and every timestamp
+    /// storage writes.
+    clock: Arc<dyn Clock>,
+    /// Capability types that may be minted.
+    capability_types: CapabilityRegistry,
 }
 
 impl WorldStorage {
     /// Open or create a world database.
     pub async fn open(path: &str) -> Result<Self, StorageError> {
         let db = libsql::Builder::new_local(path).build().await?;
-        let conn = db.connect()?;
-        let storage = Self {
-            conn,
-            db,
-            transaction_depth: 0,
-        };
-        storage.init_schema().await?;
-        Ok(storage)
+        Self::from_database(db).await
     }
 
     /// Open an in-memory database.
     pub async fn in_memory() -> Result<Self, StorageError> {
         let db = libsql::Builder::new_local(":memory:").build().await?;
+        Self::from_database(db).await
+    }
+
+    async fn from_database(db: Database) -> Result<Self, StorageError> {
         let conn = db.connect()?;
+        // Off by default in stock SQLite, and the schema relies on cascades
+        conn.execute("PRAGMA foreign_keys = ON", ()).await?;
         let storage = Self {
             conn,
             db,
             transaction_depth: 0,
+            savepoints: Vec::new(),
+            savepoint_seq: 0,
+            transaction_id: None,
+            actor: None,
+            changes: changes::ChangeFeed::new(),
+            max_prototype_depth: DEFAULT_MAX_PROTOTYPE_DEPTH,
+            clock: Arc::new(SystemClock),
+            capability_types: CapabilityRegistry::default(),
         };
         storage.init_schema().await?;
         Ok(storage)
     }
 
-    // =========================================================================
-    // Transaction Management
-    // =========================================================================
-
-    /// Begin a transaction. Uses SAVEPOINT for nested transactions.
+    /// Set the entity on whose behalf subsequent writes are made.
     ///
-    /// Returns the transaction depth (0 for outer transaction).
-    pub async fn begin_transaction(&mut self) -> Result<usize, StorageError> {
-        let depth = self.transaction_depth;
-        if depth == 0 {
-            self.conn.execute("BEGIN IMMEDIATE", ()).await?;
-        } else {
-            self.conn
-                .execute(&format!("SAVEPOINT sp_{}", depth), ())
-                .await?;
-        }
-        self.transaction_depth += 1;
-        Ok(depth)
+    /// Recorded as `actor_id` in entity revisions.
+    pub fn set_actor(&mut self, actor: Option<EntityId>) {
+        self.actor = actor;
     }
 
-    /// Commit the current transaction.
-    ///
-    /// For nested transactions, releases the savepoint.
-    pub async fn commit(&mut self) -> Result<(), StorageError> {
-        if self.transaction_depth == 0 {
-            return Err(StorageError::Transaction(
-                "no active transaction".to_string(),
-            ));
-        }
-        self.transaction_depth -= 1;
-        if self.transaction_depth == 0 {
-            self.conn.execute("COMMIT", ()).await?;
-        } else {
-            self.conn
-                .execute(
-                    &format!("RELEASE SAVEPOINT sp_{}", self.transaction_depth),
-                    (),
-                )
-                .await?;
-        }
-        Ok(())
+    /// Get the entity on whose behalf writes are currently made.
+    pub fn actor(&self) -> Option<EntityId> {
+        self.actor
     }
 
-    /// Rollback the current transaction.
-    ///
-    /// For nested transactions, rolls back to the savepoint.
-    pub async fn rollback(&mut self) -> Result<(), StorageError> {
-        if self.transaction_depth == 0 {
-            return Err(StorageError::Transaction(
-                "no active transaction".to_string(),
-            ));
-        }
-        self.transaction_depth -= 1;
-        if self.transaction_depth == 0 {
-            self.conn.execute("ROLLBACK", ()).await?;
-        } else {
-            self.conn
-                .execute(
-                    &format!("ROLLBACK TO SAVEPOINT sp_{}", self.transaction_depth),
-                    (),
-                )
-                .await?;
-        }
-        Ok(())
+    /// Replace the clock used to decide whether capabilities have expired
+    /// and to timestamp revisions, relations, prop indexes and audit
+    /// entries.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
     }
 
-    /// Check if currently in a transaction.
-    pub fn in_transaction(&self) -> bool {
-        self.transaction_depth > 0
+    /// The clock used to decide whether capabilities have expired.
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
     }
 
-    /// Initialize the database schema.
-    async fn init_schema(&self) -> Result<(), StorageError> {
-        self.conn
-            .execute(
-                "CREATE TABLE IF NOT EXISTS entities (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                prototype_id INTEGER,
-                props TEXT DEFAULT '{}',
-                FOREIGN KEY(prototype_id) REFERENCES entities(id)
-            )",
-                (),
-            )
-            .await?;
+    /// Register a capability type so capabilities of it can be created.
+    ///
+    /// Plugins call this at startup for the types they check.
+    pub fn register_capability_type(
+        &mut self,
+        capability_type: CapabilityType,
+    ) -> Result<(), StorageError> {
+        self.capability_types.register(capability_type)
+    }
 
-        self.conn
-            .execute(
-                "CREATE TABLE IF NOT EXISTS verbs (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                entity_id INTEGER NOT NULL,
-                name TEXT NOT NULL,
-                code TEXT NOT NULL,
-                required_capability TEXT,
-                FOREIGN KEY(entity_id) REFERENCES entities(id) ON DELETE CASCADE,
-                UNIQUE(entity_id, name)
-            )",
-                (),
-            )
-            .await?;
+    /// Capability types that may be minted.
+    pub fn capability_types(&self) -> &CapabilityRegistry {
+        &self.capability_types
+    }
 
-        self.conn
-            .execute(
-                "CREATE TABLE IF NOT EXISTS scheduled_tasks (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                entity_id INTEGER NOT NULL,
-                verb TEXT NOT NULL,
-                args TEXT DEFAULT '[]',
-                execute_at INTEGER NOT NULL,
-                FOREIGN KEY(entity_id) REFERENCES entities(id) ON DELETE CASCADE
-            )",
-                (),
-            )
-            .await?;
+    /// Bring the database schema up to [`SCHEMA_VERSION`].
+    async fn init_schema(&self) -> Result<(), StorageError> {
+        migrations::migrate(&self.conn).await?;
+        self.restore_prop_indexes().await?;
+        Ok(())
+    }
 
-        self.conn
-            .execute(
-                "CREATE TABLE IF NOT EXISTS capabilities (
-                id TEXT PRIMARY KEY,
-                owner_id INTEGER NOT NULL,
-                type TEXT NOT NULL,
-                params TEXT NOT NULL,
-                FOREIGN KEY(owner_id) REFERENCES entities(id) ON DELETE CASCADE
-            )",
-                (),
-            )
-            .await?;
+    /// Get the schema version recorded in the database.
+    pub async fn schema_version(&self) -> Result<u32, StorageError> {
+        migrations::current_version(&self.conn).await
+    }
 
+    /// Run `body` inside a savepoint, so its statements apply together or
+    /// not at all. A savepoint rather than a transaction, so this also
+    /// works inside one the caller has open.
+    async fn atomically<T>(
+        &self,
+        body: impl AsyncFnOnce() -> Result<T, StorageError>,
+    ) -> Result<T, StorageError> {
+        self.conn.execute("SAVEPOINT atomically", ()).await?;
+        let result = body().await;
+        if result.is_err() {
+            self.conn
+                .execute("ROLLBACK TO SAVEPOINT atomically", ())
+                .await?;
+        }
         self.conn
-            .execute(
-                "CREATE INDEX IF NOT EXISTS idx_capabilities_owner ON capabilities(owner_id)",
-                (),
-            )
+            .execute("RELEASE SAVEPOINT atomically", ())
             .await?;
-
-        Ok(())
+        result
     }
 
     /// Create a new entity.
@@ -199,6 +242,7 @@ impl WorldStorage {
         props: serde_json::Value,
         prototype_id: Option<EntityId>,
     ) -> Result<EntityId, StorageError> {
+        self.check_prototype(None, prototype_id).await?;
         let props_str = serde_json::to_string(&props)?;
         self.conn
             .execute(
@@ -206,7 +250,15 @@ impl WorldStorage {
                 params![prototype_id, props_str],
             )
             .await?;
-        Ok(self.conn.last_insert_rowid())
+        let id = self.conn.last_insert_rowid();
+
+        if let serde_json::Value::Object(map) = &props {
+            let changes = history::diff_props(&serde_json::Map::new(), map);
+            self.record_revision(id, RevisionKind::Create, &changes)
+                .await?;
+        }
+        self.emit(ChangeEvent::EntityCreated { id, prototype_id });
+        Ok(id)
     }
 
     /// Get an entity by ID (raw, without prototype resolution).
@@ -247,10 +299,11 @@ impl WorldStorage {
                 SELECT e.id, e.prototype_id, e.props, l.depth + 1
                 FROM entities e
                 JOIN lineage l ON e.id = l.prototype_id
+                WHERE l.depth < ?2
             )
             SELECT id, prototype_id, props FROM lineage ORDER BY depth DESC
             "#,
-                params![id],
+                params![id, self.max_prototype_depth as i64],
             )
             .await?;
 
@@ -288,57 +341,116 @@ impl WorldStorage {
         id: EntityId,
         props: serde_json::Value,
     ) -> Result<(), StorageError> {
-        // Get current props and merge
-        let current = self.get_entity_raw(id).await?;
-        let current = current.ok_or(StorageError::EntityNotFound(id))?;
-
-        let mut merged = match current.props {
-            serde_json::Value::Object(map) => map,
-            _ => serde_json::Map::new(),
-        };
-
-        if let serde_json::Value::Object(updates) = props {
-            for (key, value) in updates {
-                merged.insert(key, value);
+        self.modify_props(id, RevisionKind::Update, |mut merged| {
+            if let serde_json::Value::Object(updates) = props {
+                for (key, value) in updates {
+                    merged.insert(key, value);
+                }
             }
-        }
+            Ok(merged)
+        })
+        .await?;
+        Ok(())
+    }
 
-        let props_str = serde_json::to_string(&serde_json::Value::Object(merged))?;
-        self.conn
-            .execute(
-                "UPDATE entities SET props = ?1 WHERE id = ?2",
-                params![props_str, id],
-            )
+    /// Replace an entity's own props with what `modify` makes of them,
+    /// recording the revision and change event for whatever differs.
+    ///
+    /// The read, the write and the revision happen in one savepoint, so a
+    /// concurrent writer can't slip in between them and a failure leaves
+    /// the props untouched. Returns the new revision number, or `None` if
+    /// nothing changed.
+    pub(crate) async fn modify_props(
+        &self,
+        id: EntityId,
+        kind: RevisionKind,
+        modify: impl FnOnce(
+            serde_json::Map<String, serde_json::Value>,
+        ) -> Result<serde_json::Map<String, serde_json::Value>, StorageError>,
+    ) -> Result<Option<i64>, StorageError> {
+        let (revision, changes) = self
+            .atomically(async || {
+                let current = self
+                    .get_entity_raw(id)
+                    .await?
+                    .ok_or(StorageError::EntityNotFound(id))?;
+                let previous = match current.props {
+                    serde_json::Value::Object(map) => map,
+                    _ => serde_json::Map::new(),
+                };
+                let next = modify(previous.clone())?;
+                let changes = history::diff_props(&previous, &next);
+                let props_str = serde_json::to_string(&serde_json::Value::Object(next))?;
+                self.conn
+                    .execute(
+                        "UPDATE entities SET props = ?1 WHERE id = ?2",
+                        params![props_str, id],
+                    )
+                    .await?;
+                let revision = self.record_revision(id, kind, &changes).await?;
+                Ok((revision, changes))
+            })
             .await?;
-        Ok(())
+        if !changes.is_empty() {
+            self.emit(ChangeEvent::PropsChanged {
+                id,
+                keys: changes.into_iter().map(|change| change.key).collect(),
+            });
+        }
+        Ok(revision)
     }
 
     /// Set an entity's prototype.
+    ///
+    /// Fails with [`StorageError::PrototypeCycle`] if `id` would become its
+    /// own ancestor.
     pub async fn set_prototype(
         &self,
         id: EntityId,
         prototype_id: Option<EntityId>,
     ) -> Result<(), StorageError> {
+        self.check_prototype(Some(id), prototype_id).await?;
         self.conn
             .execute(
                 "UPDATE entities SET prototype_id = ?1 WHERE id = ?2",
                 params![prototype_id, id],
             )
             .await?;
+        self.emit(ChangeEvent::PrototypeChanged { id, prototype_id });
         Ok(())
     }
 
-    /// Delete an entity.
+    /// Delete an entity along with its verbs, capabilities (and anything
+    /// delegated from them), relations and tasks.
+    ///
+    /// Fails with [`StorageError::PrototypeInUse`], deleting nothing, if
+    /// other entities still have it as their prototype.
     pub async fn delete_entity(&self, id: EntityId) -> Result<(), StorageError> {
-        self.conn
-            .execute("DELETE FROM verbs WHERE entity_id = ?1", params![id])
-            .await?;
-        self.conn
-            .execute("DELETE FROM capabilities WHERE owner_id = ?1", params![id])
-            .await?;
-        self.conn
-            .execute("DELETE FROM entities WHERE id = ?1", params![id])
-            .await?;
+        self.atomically(async || {
+            let mut children = self
+                .conn
+                .query(
+                    "SELECT 1 FROM entities WHERE prototype_id = ?1 LIMIT 1",
+                    params![id],
+                )
+                .await?;
+            if children.next().await?.is_some() {
+                return Err(StorageError::PrototypeInUse(id));
+            }
+            self.conn
+                .execute("DELETE FROM verbs WHERE entity_id = ?1", params![id])
+                .await?;
+            self.revoke_capability_trees("owner_id = ?1", id.into())
+                .await?;
+            // Relations, tasks and dead letters go with it through their
+            // foreign keys
+            self.conn
+                .execute("DELETE FROM entities WHERE id = ?1", params![id])
+                .await?;
+            Ok(())
+        })
+        .await?;
+        self.emit(ChangeEvent::EntityDeleted { id });
         Ok(())
     }
 
@@ -359,13 +471,61 @@ impl WorldStorage {
         name: &str,
         code: &serde_json::Value,
         required_capability: Option<&str>,
+    ) -> Result<i64, StorageError> {
+        self.insert_verb(entity_id, name, code, required_capability, None)
+            .await
+    }
+
+    /// Add a verb that requires a capability of `required_capability`
+    /// permitting `required_params`.
+    ///
+    /// String values in `required_params` may be templates filled in from
+    /// the call; see [`authorize_verb_call`](Self::authorize_verb_call).
+    pub async fn add_verb_with_requirement(
+        &self,
+        entity_id: EntityId,
+        name: &str,
+        code: &serde_json::Value,
+        required_capability: &str,
+        required_params: serde_json::Value,
+    ) -> Result<i64, StorageError> {
+        if !required_params.is_object() {
+            return Err(StorageError::InvalidCapability(format!(
+                "required params for verb '{}' must be an object",
+                name
+            )));
+        }
+        self.insert_verb(
+            entity_id,
+            name,
+            code,
+            Some(required_capability),
+            Some(&required_params),
+        )
+        .await
+    }
+
+    async fn insert_verb(
+        &self,
+        entity_id: EntityId,
+        name: &str,
+        code: &serde_json::Value,
+        required_capability: Option<&str>,
+        required_params: Option<&serde_json::Value>,
     ) -> Result<i64, StorageError> {
         let code_str = serde_json::to_string(code)?;
+        let params_str = required_params.map(serde_json::to_string).transpose()?;
         self.conn.execute(
-            "INSERT INTO verbs (entity_id, name, code, required_capability) VALUES (?1, ?2, ?3, ?4)",
-            params![entity_id, name, code_str, required_capability],
+            "INSERT INTO verbs (entity_id, name, code, required_capability, required_params) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![entity_id, name, code_str, required_capability, params_str],
         ).await?;
-        Ok(self.conn.last_insert_rowid())
+        let id = self.conn.last_insert_rowid();
+        self.emit(ChangeEvent::VerbAdded {
+            id,
+            entity_id,
+            name: name.to_string(),
+        });
+        Ok(id)
     }
 
     /// Get a verb by entity and name (resolves through prototype chain).
@@ -384,34 +544,22 @@ impl WorldStorage {
                 SELECT e.id, e.prototype_id, l.depth + 1
                 FROM entities e
                 JOIN lineage l ON e.id = l.prototype_id
+                WHERE l.depth < ?3
             )
-            SELECT v.id, v.entity_id, v.name, v.code, v.required_capability, l.depth
+            SELECT v.id, v.entity_id, v.name, v.code, v.required_capability, v.required_params, l.depth
             FROM verbs v
             JOIN lineage l ON v.entity_id = l.id
             WHERE v.name = ?2
             ORDER BY l.depth ASC
             LIMIT 1
             "#,
-                params![entity_id, name],
+                params![entity_id, name, self.max_prototype_depth as i64],
             )
             .await?;
 
-        if let Some(row) = rows.next().await? {
-            let id: i64 = row.get(0)?;
-            let entity_id: EntityId = row.get(1)?;
-            let name: String = row.get(2)?;
-            let code_str: String = row.get(3)?;
-            let required_capability: Option<String> = row.get(4)?;
-            let code: serde_json::Value = serde_json::from_str(&code_str)?;
-            Ok(Some(Verb {
-                id,
-                entity_id,
-                name,
-                code,
-                required_capability,
-            }))
-        } else {
-            Ok(None)
+        match rows.next().await? {
+            Some(row) => Ok(Some(row_to_verb(&row)?)),
+            None => Ok(None),
         }
     }
 
@@ -427,46 +575,29 @@ impl WorldStorage {
                 SELECT e.id, e.prototype_id, l.depth + 1
                 FROM entities e
                 JOIN lineage l ON e.id = l.prototype_id
+                WHERE l.depth < ?2
             )
-            SELECT v.id, v.entity_id, v.name, v.code, v.required_capability, l.depth
+            SELECT v.id, v.entity_id, v.name, v.code, v.required_capability, v.required_params, l.depth
             FROM verbs v
             JOIN lineage l ON v.entity_id = l.id
             ORDER BY l.depth DESC
             "#,
-                params![entity_id],
+                params![entity_id, self.max_prototype_depth as i64],
             )
             .await?;
 
         // Use a map to ensure child verbs override parent verbs
         let mut verb_map = std::collections::HashMap::new();
         while let Some(row) = rows.next().await? {
-            let id: i64 = row.get(0)?;
-            let entity_id: EntityId = row.get(1)?;
-            let name: String = row.get(2)?;
-            let code_str: String = row.get(3)?;
-            let required_capability: Option<String> = row.get(4)?;
-            let code: serde_json::Value = serde_json::from_str(&code_str)?;
-            verb_map.insert(
-                name.clone(),
-                Verb {
-                    id,
-                    entity_id,
-                    name,
-                    code,
-                    required_capability,
-                },
-            );
+            let verb = row_to_verb(&row)?;
+            verb_map.insert(verb.name.clone(), verb);
         }
 
         Ok(verb_map.into_values().collect())
     }
 
     /// Update a verb's code.
-    pub async fn update_verb(
-        &self,
-        id: i64,
-        code: &serde_json::Value,
-    ) -> Result<(), StorageError> {
+    pub async fn update_verb(&self, id: i64, code: &serde_json::Value) -> Result<(), StorageError> {
         let code_str = serde_json::to_string(code)?;
         self.conn
             .execute(
@@ -474,6 +605,7 @@ impl WorldStorage {
                 params![code_str, id],
             )
             .await?;
+        self.emit(ChangeEvent::VerbUpdated { id });
         Ok(())
     }
 
@@ -482,6 +614,7 @@ impl WorldStorage {
         self.conn
             .execute("DELETE FROM verbs WHERE id = ?1", params![id])
             .await?;
+        self.emit(ChangeEvent::VerbDeleted { id });
         Ok(())
     }
 
@@ -490,24 +623,65 @@ impl WorldStorage {
     // =========================================================================
 
     /// Create a new capability.
+    ///
+    /// Fails with [`StorageError::InvalidCapability`] unless `cap_type` is
+    /// registered and `params` fit its declaration.
     pub async fn create_capability(
         &self,
         owner_id: EntityId,
         cap_type: &str,
         params: serde_json::Value,
     ) -> Result<String, StorageError> {
+        self.create_capability_with_validity(owner_id, cap_type, params, None, None)
+            .await
+    }
+
+    /// Create a new capability that is only valid from `not_before` until
+    /// `expires_at` (ms since epoch, either bound optional).
+    pub async fn create_capability_with_validity(
+        &self,
+        owner_id: EntityId,
+        cap_type: &str,
+        params: serde_json::Value,
+        not_before: Option<i64>,
+        expires_at: Option<i64>,
+    ) -> Result<String, StorageError> {
+        self.capability_types.validate(cap_type, &params)?;
         let id = uuid::Uuid::new_v4().to_string();
         let params_str = serde_json::to_string(&params)?;
         self.conn
             .execute(
-                "INSERT INTO capabilities (id, owner_id, type, params) VALUES (?1, ?2, ?3, ?4)",
-                libsql::params![id.clone(), owner_id, cap_type, params_str],
+                "INSERT INTO capabilities (id, owner_id, type, params, not_before, expires_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                libsql::params![
+                    id.clone(),
+                    owner_id,
+                    cap_type,
+                    params_str,
+                    not_before,
+                    expires_at
+                ],
             )
             .await?;
+        self.audit(NewAuditEntry::new(
+            AuditAction::Created,
+            &id,
+            cap_type,
+            owner_id,
+        ))
+        .await?;
+        self.emit(ChangeEvent::CapabilityGranted {
+            id: id.clone(),
+            owner_id,
+            cap_type: cap_type.to_string(),
+        });
         Ok(id)
     }
 
     /// Get a capability by ID.
+    ///
+    /// Expired capabilities are still returned (until purged); use
+    /// [`Capability::permits`](crate::Capability::permits) to check them.
     pub async fn get_capability(
         &self,
         id: &str,
@@ -515,29 +689,19 @@ impl WorldStorage {
         let mut rows = self
             .conn
             .query(
-                "SELECT id, owner_id, type, params FROM capabilities WHERE id = ?1",
+                "SELECT id, owner_id, type, params, not_before, expires_at, parent_id
+                 FROM capabilities WHERE id = ?1",
                 params![id],
             )
             .await?;
 
-        if let Some(row) = rows.next().await? {
-            let id: String = row.get(0)?;
-            let owner_id: EntityId = row.get(1)?;
-            let cap_type: String = row.get(2)?;
-            let params_str: String = row.get(3)?;
-            let params: serde_json::Value = serde_json::from_str(&params_str)?;
-            Ok(Some(crate::Capability {
-                id,
-                owner_id,
-                cap_type,
-                params,
-            }))
-        } else {
-            Ok(None)
+        match rows.next().await? {
+            Some(row) => Ok(Some(row_to_capability(&row)?)),
+            None => Ok(None),
         }
     }
 
-    /// Get all capabilities owned by an entity.
+    /// Get all capabilities owned by an entity, excluding expired ones.
     pub async fn get_capabilities(
         &self,
         owner_id: EntityId,
@@ -545,116 +709,317 @@ impl WorldStorage {
         let mut rows = self
             .conn
             .query(
-                "SELECT id, owner_id, type, params FROM capabilities WHERE owner_id = ?1",
-                params![owner_id],
+                "SELECT id, owner_id, type, params, not_before, expires_at, parent_id
+                 FROM capabilities
+                 WHERE owner_id = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+                params![owner_id, self.clock.now_ms()],
             )
             .await?;
 
         let mut caps = Vec::new();
         while let Some(row) = rows.next().await? {
-            let id: String = row.get(0)?;
-            let owner_id: EntityId = row.get(1)?;
-            let cap_type: String = row.get(2)?;
-            let params_str: String = row.get(3)?;
-            let params: serde_json::Value = serde_json::from_str(&params_str)?;
-            caps.push(crate::Capability {
-                id,
-                owner_id,
-                cap_type,
-                params,
-            });
+            caps.push(row_to_capability(&row)?);
         }
 
         Ok(caps)
     }
 
+    /// Delete every capability that has expired, returning how many were
+    /// removed.
+    pub async fn purge_expired_capabilities(&self) -> Result<usize, StorageError> {
+        let mut rows = self
+            .conn
+            .query(
+                "DELETE FROM capabilities WHERE expires_at <= ?1 RETURNING id, type, owner_id",
+                params![self.clock.now_ms()],
+            )
+            .await?;
+
+        let mut purged = Vec::new();
+        while let Some(row) = rows.next().await? {
+            purged.push((row.get::<String>(0)?, row.get::<String>(1)?, row.get(2)?));
+        }
+        for (id, cap_type, owner_id) in &purged {
+            self.audit(NewAuditEntry::new(
+                AuditAction::Expired,
+                id,
+                cap_type,
+                *owner_id,
+            ))
+            .await?;
+            self.emit(ChangeEvent::CapabilityRevoked { id: id.clone() });
+        }
+        Ok(purged.len())
+    }
+
     /// Update the owner of a capability.
+    ///
+    /// Fails with [`StorageError::CapabilityNotFound`] if there is no such
+    /// capability. Handing a capability to its current owner changes
+    /// nothing.
     pub async fn update_capability_owner(
         &self,
         id: &str,
         new_owner_id: EntityId,
     ) -> Result<(), StorageError> {
-        self.conn
-            .execute(
-                "UPDATE capabilities SET owner_id = ?1 WHERE id = ?2",
-                params![new_owner_id, id],
-            )
+        let transferred = self
+            .atomically(async || {
+                let previous = self
+                    .get_capability(id)
+                    .await?
+                    .ok_or_else(|| StorageError::CapabilityNotFound(id.to_string()))?;
+                if previous.owner_id == new_owner_id {
+                    return Ok(false);
+                }
+                self.conn
+                    .execute(
+                        "UPDATE capabilities SET owner_id = ?1 WHERE id = ?2",
+                        params![new_owner_id, id],
+                    )
+                    .await?;
+                let mut entry = NewAuditEntry::new(
+                    AuditAction::Transferred,
+                    id,
+                    &previous.cap_type,
+                    new_owner_id,
+                );
+                entry.previous_owner_id = Some(previous.owner_id);
+                self.audit(entry).await?;
+                Ok(true)
+            })
             .await?;
+        if transferred {
+            self.emit(ChangeEvent::CapabilityTransferred {
+                id: id.to_string(),
+                new_owner_id,
+            });
+        }
         Ok(())
     }
 
-    /// Delete a capability.
+    /// Delete a capability and every capability delegated from it.
     pub async fn delete_capability(&self, id: &str) -> Result<(), StorageError> {
-        self.conn
-            .execute("DELETE FROM capabilities WHERE id = ?1", params![id])
-            .await?;
-        Ok(())
+        self.revoke_capability_trees("id = ?1", id.into()).await
     }
+}
 
-    // =========================================================================
-    // Scheduled Tasks
-    // =========================================================================
+impl WorldStore for WorldStorage {
+    async fn create_entity(
+        &self,
+        props: serde_json::Value,
+        prototype_id: Option<EntityId>,
+    ) -> Result<EntityId, StorageError> {
+        WorldStorage::create_entity(self, props, prototype_id).await
+    }
+
+    async fn get_entity_raw(&self, id: EntityId) -> Result<Option<Entity>, StorageError> {
+        WorldStorage::get_entity_raw(self, id).await
+    }
+
+    async fn get_entity(&self, id: EntityId) -> Result<Option<Entity>, StorageError> {
+        WorldStorage::get_entity(self, id).await
+    }
+
+    async fn update_entity(
+        &self,
+        id: EntityId,
+        props: serde_json::Value,
+    ) -> Result<(), StorageError> {
+        WorldStorage::update_entity(self, id, props).await
+    }
+
+    async fn set_prototype(
+        &self,
+        id: EntityId,
+        prototype_id: Option<EntityId>,
+    ) -> Result<(), StorageError> {
+        WorldStorage::set_prototype(self, id, prototype_id).await
+    }
+
+    async fn delete_entity(&self, id: EntityId) -> Result<(), StorageError> {
+        WorldStorage::delete_entity(self, id).await
+    }
+
+    async fn add_verb_with_cap(
+        &self,
+        entity_id: EntityId,
+        name: &str,
+        code: &serde_json::Value,
+        required_capability: Option<&str>,
+    ) -> Result<i64, StorageError> {
+        WorldStorage::add_verb_with_cap(self, entity_id, name, code, required_capability).await
+    }
 
-    /// Schedule a task for future execution.
-    pub async fn schedule_task(
+    async fn get_verb(
+        &self,
+        entity_id: EntityId,
+        name: &str,
+    ) -> Result<Option<Verb>, StorageError> {
+        WorldStorage::get_verb(self, entity_id, name).await
+    }
+
+    async fn get_verbs(&self, entity_id: EntityId) -> Result<Vec<Verb>, StorageError> {
+        WorldStorage::get_verbs(self, entity_id).await
+    }
+
+    async fn update_verb(&self, id: i64, code: &serde_json::Value) -> Result<(), StorageError> {
+        WorldStorage::update_verb(self, id, code).await
+    }
+
+    async fn delete_verb(&self, id: i64) -> Result<(), StorageError> {
+        WorldStorage::delete_verb(self, id).await
+    }
+
+    async fn create_capability_with_validity(
+        &self,
+        owner_id: EntityId,
+        cap_type: &str,
+        params: serde_json::Value,
+        not_before: Option<i64>,
+        expires_at: Option<i64>,
+    ) -> Result<String, StorageError> {
+        WorldStorage::create_capability_with_validity(
+            self, owner_id, cap_type, params, not_before, expires_at,
+        )
+        .await
+    }
+
+    async fn delegate_capability(
+        &self,
+        parent_id: &str,
+        new_owner_id: EntityId,
+        restricted_params: serde_json::Value,
+    ) -> Result<String, StorageError> {
+        WorldStorage::delegate_capability(self, parent_id, new_owner_id, restricted_params).await
+    }
+
+    async fn get_capability(&self, id: &str) -> Result<Option<crate::Capability>, StorageError> {
+        WorldStorage::get_capability(self, id).await
+    }
+
+    async fn get_capabilities(
+        &self,
+        owner_id: EntityId,
+    ) -> Result<Vec<crate::Capability>, StorageError> {
+        WorldStorage::get_capabilities(self, owner_id).await
+    }
+
+    async fn update_capability_owner(
+        &self,
+        id: &str,
+        new_owner_id: EntityId,
+    ) -> Result<(), StorageError> {
+        WorldStorage::update_capability_owner(self, id, new_owner_id).await
+    }
+
+    async fn delete_capability(&self, id: &str) -> Result<(), StorageError> {
+        WorldStorage::delete_capability(self, id).await
+    }
+
+    async fn schedule_task_with(
         &self,
         entity_id: EntityId,
         verb: &str,
         args: serde_json::Value,
         execute_at: i64,
+        options: TaskOptions,
     ) -> Result<i64, StorageError> {
-        let args_str = serde_json::to_string(&args)?;
-        self.conn.execute(
-            "INSERT INTO scheduled_tasks (entity_id, verb, args, execute_at) VALUES (?1, ?2, ?3, ?4)",
-            params![entity_id, verb, args_str, execute_at],
-        ).await?;
-        Ok(self.conn.last_insert_rowid())
+        WorldStorage::schedule_task_with(self, entity_id, verb, args, execute_at, options).await
     }
 
-    /// Get all tasks that are due (execute_at <= now).
-    pub async fn get_due_tasks(&self, now: i64) -> Result<Vec<ScheduledTask>, StorageError> {
-        let mut rows = self.conn.query(
-            "SELECT id, entity_id, verb, args, execute_at FROM scheduled_tasks WHERE execute_at <= ?1 ORDER BY execute_at ASC",
-            params![now],
-        ).await?;
+    async fn get_due_tasks(&self, now: i64) -> Result<Vec<ScheduledTask>, StorageError> {
+        WorldStorage::get_due_tasks(self, now).await
+    }
 
-        let mut tasks = Vec::new();
-        while let Some(row) = rows.next().await? {
-            let id: i64 = row.get(0)?;
-            let entity_id: EntityId = row.get(1)?;
-            let verb: String = row.get(2)?;
-            let args_str: String = row.get(3)?;
-            let execute_at: i64 = row.get(4)?;
-            let args: serde_json::Value = serde_json::from_str(&args_str)?;
-            tasks.push(ScheduledTask {
-                id,
-                entity_id,
-                verb,
-                args,
-                execute_at,
-            });
-        }
+    async fn list_tasks(&self, filter: &TaskFilter) -> Result<Vec<ScheduledTask>, StorageError> {
+        WorldStorage::list_tasks(self, filter).await
+    }
 
-        Ok(tasks)
+    async fn reschedule_task(&self, id: i64, execute_at: i64) -> Result<bool, StorageError> {
+        WorldStorage::reschedule_task(self, id, execute_at).await
     }
 
-    /// Delete a scheduled task.
-    pub async fn delete_task(&self, id: i64) -> Result<(), StorageError> {
-        self.conn
-            .execute("DELETE FROM scheduled_tasks WHERE id = ?1", params![id])
-            .await?;
-        Ok(())
+    async fn delete_task(&self, id: i64) -> Result<(), StorageError> {
+        WorldStorage::delete_task(self, id).await
+    }
+
+    async fn delete_tasks(&self, filter: &TaskFilter) -> Result<u64, StorageError> {
+        WorldStorage::delete_tasks(self, filter).await
     }
+
+    async fn claim_due_tasks(
+        &self,
+        now: i64,
+        lease_until: i64,
+        limit: usize,
+    ) -> Result<Vec<ScheduledTask>, StorageError> {
+        WorldStorage::claim_due_tasks(self, now, lease_until, limit).await
+    }
+
+    async fn complete_task(
+        &self,
+        id: i64,
+        leased_until: i64,
+        next_run: Option<i64>,
+    ) -> Result<bool, StorageError> {
+        WorldStorage::complete_task(self, id, leased_until, next_run).await
+    }
+
+    async fn fail_task(
+        &self,
+        id: i64,
+        leased_until: i64,
+        error: &str,
+        retry_at: Option<i64>,
+        now: i64,
+    ) -> Result<bool, StorageError> {
+        WorldStorage::fail_task(self, id, leased_until, error, retry_at, now).await
+    }
+
+    async fn release_task(&self, id: i64, leased_until: i64) -> Result<bool, StorageError> {
+        WorldStorage::release_task(self, id, leased_until).await
+    }
+
+    async fn reclaim_expired_leases(&self, now: i64) -> Result<u64, StorageError> {
+        WorldStorage::reclaim_expired_leases(self, now).await
+    }
+
+    async fn get_dead_letter_tasks(&self) -> Result<Vec<DeadLetterTask>, StorageError> {
+        WorldStorage::get_dead_letter_tasks(self).await
+    }
+}
+
+/// Read a capability from a row of
+/// `id, owner_id, type, params, not_before, expires_at, parent_id`.
+fn row_to_capability(row: &libsql::Row) -> Result<crate::Capability, StorageError> {
+    let params_str: String = row.get(3)?;
+    Ok(crate::Capability {
+        id: row.get(0)?,
+        owner_id: row.get(1)?,
+        cap_type: row.get(2)?,
+        params: serde_json::from_str(&params_str)?,
+        not_before: row.get(4)?,
+        expires_at: row.get(5)?,
+        parent_id: row.get(6)?,
+    })
 }
 
-/// A scheduled task.
-#[derive(Debug, Clone)]
-pub struct ScheduledTask {
-    pub id: i64,
-    pub entity_id: EntityId,
-    pub verb: String,
-    pub args: serde_json::Value,
-    pub execute_at: i64,
+/// Read a verb from a row of
+/// `id, entity_id, name, code, required_capability, required_params`.
+fn row_to_verb(row: &libsql::Row) -> Result<Verb, StorageError> {
+    let code_str: String = row.get(3)?;
+    let params_str: Option<String> = row.get(5)?;
+    Ok(Verb {
+        id: row.get(0)?,
+        entity_id: row.get(1)?,
+        name: row.get(2)?,
+        code: serde_json::from_str(&code_str)?,
+        required_capability: row.get(4)?,
+        required_params: params_str
+            .as_deref()
+            .map(serde_json::from_str)
+            .transpose()?,
+    })
 }
 
 #[cfg(test)]
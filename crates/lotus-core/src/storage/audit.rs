@@ -0,0 +1,273 @@
+//! Capability audit log.
+//!
+//! Every capability lifecycle event, and every use reported through
+//! [`WorldStorage::record_capability_use`], appends a row to
+//! `capability_audit`. Rows are never updated or deleted (triggers reject
+//! both) and outlive the capabilities they describe, so the log can answer
+//! "who gave this player `system.exec`, and when" after the fact.
+
+use libsql::{Value as SqlValue, params};
+use serde::{Deserialize, Serialize};
+
+use super::{StorageError, WorldStorage};
+use crate::entity::EntityId;
+
+/// What happened to a capability.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Created,
+    Delegated,
+    Transferred,
+    Used,
+    Revoked,
+    Expired,
+}
+
+impl AuditAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuditAction::Created => "created",
+            AuditAction::Delegated => "delegated",
+            AuditAction::Transferred => "transferred",
+            AuditAction::Used => "used",
+            AuditAction::Revoked => "revoked",
+            AuditAction::Expired => "expired",
+        }
+    }
+
+    fn parse(stored: &str) -> Result<Self, StorageError> {
+        Ok(match stored {
+            "created" => AuditAction::Created,
+            "delegated" => AuditAction::Delegated,
+            "transferred" => AuditAction::Transferred,
+            "used" => AuditAction::Used,
+            "revoked" => AuditAction::Revoked,
+            "expired" => AuditAction::Expired,
+            other => {
+                return Err(StorageError::Constraint(format!(
+                    "unknown audit action '{}'",
+                    other
+                )));
+            }
+        })
+    }
+}
+
+/// One entry in the capability audit log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub capability_id: String,
+    pub action: AuditAction,
+    pub cap_type: String,
+    /// Owner of the capability after the event.
+    pub owner_id: EntityId,
+    /// Owner before a transfer.
+    pub previous_owner_id: Option<EntityId>,
+    /// Capability a delegated one was derived from.
+    pub parent_id: Option<String>,
+    /// Storage actor at the time of the event.
+    pub actor_id: Option<EntityId>,
+    /// Verb and entity a use was recorded for.
+    pub verb: Option<String>,
+    pub entity_id: Option<EntityId>,
+    pub created_at: i64,
+}
+
+/// Filter for [`WorldStorage::get_capability_audit`]. Every field set
+/// narrows the result.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuditQuery {
+    pub capability_id: Option<String>,
+    pub owner_id: Option<EntityId>,
+    pub cap_type: Option<String>,
+    pub action: Option<AuditAction>,
+    /// Inclusive lower bound on `created_at`.
+    pub since: Option<i64>,
+    /// Exclusive upper bound on `created_at`.
+    pub until: Option<i64>,
+    pub limit: Option<u64>,
+}
+
+impl AuditQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn capability(mut self, capability_id: &str) -> Self {
+        self.capability_id = Some(capability_id.to_string());
+        self
+    }
+
+    pub fn owner(mut self, owner_id: EntityId) -> Self {
+        self.owner_id = Some(owner_id);
+        self
+    }
+
+    pub fn cap_type(mut self, cap_type: &str) -> Self {
+        self.cap_type = Some(cap_type.to_string());
+        self
+    }
+
+    pub fn action(mut self, action: AuditAction) -> Self {
+        self.action = Some(action);
+        self
+    }
+
+    /// Only entries with `since <= created_at < until`.
+    pub fn between(mut self, since: i64, until: i64) -> Self {
+        self.since = Some(since);
+        self.until = Some(until);
+        self
+    }
+
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+/// An audit entry about to be written.
+pub(crate) struct NewAuditEntry<'a> {
+    pub action: AuditAction,
+    pub capability_id: &'a str,
+    pub cap_type: &'a str,
+    pub owner_id: EntityId,
+    pub previous_owner_id: Option<EntityId>,
+    pub parent_id: Option<&'a str>,
+    pub verb: Option<&'a str>,
+    pub entity_id: Option<EntityId>,
+}
+
+impl<'a> NewAuditEntry<'a> {
+    pub fn new(
+        action: AuditAction,
+        capability_id: &'a str,
+        cap_type: &'a str,
+        owner_id: EntityId,
+    ) -> Self {
+        Self {
+            action,
+            capability_id,
+            cap_type,
+            owner_id,
+            previous_owner_id: None,
+            parent_id: None,
+            verb: None,
+            entity_id: None,
+        }
+    }
+}
+
+impl WorldStorage {
+    /// Append an entry to the audit log.
+    pub(crate) async fn audit(&self, entry: NewAuditEntry<'_>) -> Result<(), StorageError> {
+        self.conn
+            .execute(
+                "INSERT INTO capability_audit
+                 (capability_id, action, cap_type, owner_id, previous_owner_id, parent_id,
+                  actor_id, verb, entity_id, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    entry.capability_id,
+                    entry.action.as_str(),
+                    entry.cap_type,
+                    entry.owner_id,
+                    entry.previous_owner_id,
+                    entry.parent_id,
+                    self.actor,
+                    entry.verb,
+                    entry.entity_id,
+                    self.clock.now_ms()
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Record that a capability check succeeded for `verb` on `entity_id`.
+    ///
+    /// [`authorize_verb_call`](Self::authorize_verb_call) calls this itself;
+    /// hosts that check capabilities some other way should call it after a
+    /// successful check.
+    pub async fn record_capability_use(
+        &self,
+        cap_id: &str,
+        verb: &str,
+        entity_id: EntityId,
+    ) -> Result<(), StorageError> {
+        let cap = self.get_capability(cap_id).await?.ok_or_else(|| {
+            StorageError::InvalidCapability(format!("capability {} not found", cap_id))
+        })?;
+        let mut entry = NewAuditEntry::new(AuditAction::Used, cap_id, &cap.cap_type, cap.owner_id);
+        entry.verb = Some(verb);
+        entry.entity_id = Some(entity_id);
+        self.audit(entry).await
+    }
+
+    /// Read audit entries matching `query`, oldest first.
+    pub async fn get_capability_audit(
+        &self,
+        query: &AuditQuery,
+    ) -> Result<Vec<AuditEntry>, StorageError> {
+        let mut conditions = Vec::new();
+        let mut params: Vec<SqlValue> = Vec::new();
+        let mut condition = |sql: &str, value: SqlValue| {
+            params.push(value);
+            conditions.push(format!("{} ?{}", sql, params.len()));
+        };
+        if let Some(id) = &query.capability_id {
+            condition("capability_id =", id.clone().into());
+        }
+        if let Some(owner_id) = query.owner_id {
+            condition("owner_id =", owner_id.into());
+        }
+        if let Some(cap_type) = &query.cap_type {
+            condition("cap_type =", cap_type.clone().into());
+        }
+        if let Some(action) = query.action {
+            condition("action =", action.as_str().into());
+        }
+        if let Some(since) = query.since {
+            condition("created_at >=", since.into());
+        }
+        if let Some(until) = query.until {
+            condition("created_at <", until.into());
+        }
+
+        let mut sql = String::from(
+            "SELECT id, capability_id, action, cap_type, owner_id, previous_owner_id, parent_id,
+                    actor_id, verb, entity_id, created_at
+             FROM capability_audit",
+        );
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(" ORDER BY id");
+        if let Some(limit) = query.limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+
+        let mut rows = self.conn.query(&sql, params).await?;
+        let mut entries = Vec::new();
+        while let Some(row) = rows.next().await? {
+            let action: String = row.get(2)?;
+            entries.push(AuditEntry {
+                id: row.get(0)?,
+                capability_id: row.get(1)?,
+                action: AuditAction::parse(&action)?,
+                cap_type: row.get(3)?,
+                owner_id: row.get(4)?,
+                previous_owner_id: row.get(5)?,
+                parent_id: row.get(6)?,
+                actor_id: row.get(7)?,
+                verb: row.get(8)?,
+                entity_id: row.get(9)?,
+                created_at: row.get(10)?,
+            });
+        }
+        Ok(entries)
+    }
+}
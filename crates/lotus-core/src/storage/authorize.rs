@@ -0,0 +1,150 @@
+//! Verb-level authorization.
+//!
+//! A verb with a `required_capability` may only be called by an entity
+//! holding a currently valid capability of that type that permits the
+//! verb's `required_params`. String values in `required_params` are
+//! templates filled in from the call before matching:
+//!
+//! | Template | Replaced with |
+//! |----------|---------------|
+//! | `"$this"` | the entity the verb is called on |
+//! | `"$caller"` | the calling entity |
+//!
+//! So a verb declared with `entity.control` and `{"target_id": "$this"}`
+//! is callable by whoever controls the particular entity it is called on,
+//! even when the verb itself is inherited from a prototype.
+
+use serde_json::Value;
+use thiserror::Error;
+
+use super::{StorageError, WorldStorage};
+use crate::capability::Capability;
+use crate::entity::{EntityId, Verb};
+
+/// A permitted verb call.
+#[derive(Debug, Clone)]
+pub struct Authorized {
+    /// The verb as resolved through the target's prototype chain.
+    pub verb: Verb,
+    /// Capability that permitted the call, or `None` if the verb requires
+    /// none.
+    pub capability: Option<Capability>,
+}
+
+/// Why a verb call was refused.
+#[derive(Debug, Error)]
+pub enum Denied {
+    #[error("verb '{verb}' not found on entity {target_id}")]
+    VerbNotFound { target_id: EntityId, verb: String },
+
+    #[error("caller {caller_id} holds no valid '{cap_type}' capability")]
+    MissingCapability {
+        caller_id: EntityId,
+        cap_type: String,
+    },
+
+    #[error("no '{cap_type}' capability held by caller {caller_id} permits {params}")]
+    ParamsNotPermitted {
+        caller_id: EntityId,
+        cap_type: String,
+        /// Required params after template substitution.
+        params: Value,
+    },
+
+    #[error("authorization check failed: {0}")]
+    Storage(#[from] StorageError),
+}
+
+impl WorldStorage {
+    /// Decide whether `caller_id` may call `verb_name` on `target_id`.
+    ///
+    /// Resolves the verb through the target's prototype chain, fills in the
+    /// verb's param templates, and looks for a capability among the
+    /// caller's unexpired ones that permits them, preferring a specific
+    /// capability over a blanket `{"*": true}` grant. The capability used
+    /// is recorded in the audit log.
+    pub async fn authorize_verb_call(
+        &self,
+        caller_id: EntityId,
+        target_id: EntityId,
+        verb_name: &str,
+    ) -> Result<Authorized, Denied> {
+        let verb =
+            self.get_verb(target_id, verb_name)
+                .await?
+                .ok_or_else(|| Denied::VerbNotFound {
+                    target_id,
+                    verb: verb_name.to_string(),
+                })?;
+        let Some(cap_type) = verb.required_capability.clone() else {
+            return Ok(Authorized {
+                verb,
+                capability: None,
+            });
+        };
+
+        let template = verb
+            .required_params
+            .clone()
+            .unwrap_or_else(|| Value::Object(Default::default()));
+        let params = fill_templates(template, caller_id, target_id);
+
+        let now = self.clock.now_ms();
+        let held: Vec<Capability> = self
+            .get_capabilities(caller_id)
+            .await?
+            .into_iter()
+            .filter(|cap| cap.cap_type == cap_type && cap.is_valid_at(now))
+            .collect();
+        if held.is_empty() {
+            return Err(Denied::MissingCapability {
+                caller_id,
+                cap_type,
+            });
+        }
+
+        // A specific capability is preferred over a blanket one
+        let capability = held
+            .iter()
+            .filter(|cap| cap.permits_with_clock(self.clock.as_ref(), &cap_type, &params))
+            .min_by_key(|cap| cap.is_blanket())
+            .cloned();
+        let Some(capability) = capability else {
+            return Err(Denied::ParamsNotPermitted {
+                caller_id,
+                cap_type,
+                params,
+            });
+        };
+
+        self.record_capability_use(&capability.id, verb_name, target_id)
+            .await?;
+        Ok(Authorized {
+            verb,
+            capability: Some(capability),
+        })
+    }
+}
+
+/// Replace template strings in `value` with values from the call.
+fn fill_templates(value: Value, caller_id: EntityId, target_id: EntityId) -> Value {
+    match value {
+        Value::String(text) => match text.as_str() {
+            "$this" => Value::from(target_id),
+            "$caller" => Value::from(caller_id),
+            _ => Value::String(text),
+        },
+        Value::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .map(|item| fill_templates(item, caller_id, target_id))
+                .collect(),
+        ),
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| (key, fill_templates(value, caller_id, target_id)))
+                .collect(),
+        ),
+        other => other,
+    }
+}
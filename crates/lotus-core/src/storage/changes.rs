@@ -0,0 +1,153 @@
+//! Change feed for storage mutations.
+//!
+//! Mutating [`WorldStorage`] methods emit a [`ChangeEvent`] describing what
+//! changed. Outside a transaction events are published immediately; inside
+//! one they are buffered and only published once the outermost transaction
+//! commits. Rolling back (or rolling back to a savepoint) discards the
+//! events recorded since.
+
+use std::sync::{Mutex, MutexGuard};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use super::WorldStorage;
+use crate::entity::EntityId;
+
+/// Number of events a slow subscriber may fall behind before it starts
+/// missing events (see [`broadcast::error::RecvError::Lagged`]).
+pub const CHANGE_FEED_CAPACITY: usize = 1024;
+
+/// A committed mutation of world state.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChangeEvent {
+    EntityCreated {
+        id: EntityId,
+        prototype_id: Option<EntityId>,
+    },
+    /// Top-level keys of an entity's own props were added, changed or removed.
+    PropsChanged {
+        id: EntityId,
+        keys: Vec<String>,
+    },
+    PrototypeChanged {
+        id: EntityId,
+        prototype_id: Option<EntityId>,
+    },
+    /// The entity was deleted along with its verbs, capabilities and
+    /// relations.
+    EntityDeleted {
+        id: EntityId,
+    },
+    VerbAdded {
+        id: i64,
+        entity_id: EntityId,
+        name: String,
+    },
+    VerbUpdated {
+        id: i64,
+    },
+    VerbDeleted {
+        id: i64,
+    },
+    CapabilityGranted {
+        id: String,
+        owner_id: EntityId,
+        cap_type: String,
+    },
+    CapabilityRevoked {
+        id: String,
+    },
+    CapabilityTransferred {
+        id: String,
+        new_owner_id: EntityId,
+    },
+    TaskScheduled {
+        id: i64,
+        entity_id: EntityId,
+        verb: String,
+        execute_at: i64,
+    },
+    /// A relation was created or its attributes replaced.
+    RelationLinked {
+        source_id: EntityId,
+        relation: String,
+        target_id: EntityId,
+    },
+    RelationUnlinked {
+        source_id: EntityId,
+        relation: String,
+        target_id: EntityId,
+    },
+}
+
+/// Publishes change events, holding them back while a transaction is open.
+pub(crate) struct ChangeFeed {
+    sender: broadcast::Sender<ChangeEvent>,
+    /// Events recorded inside the open transaction, oldest first.
+    pending: Mutex<Vec<ChangeEvent>>,
+    /// Length of `pending` when each savepoint was opened.
+    savepoints: Vec<usize>,
+}
+
+impl ChangeFeed {
+    pub(crate) fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANGE_FEED_CAPACITY);
+        Self {
+            sender,
+            pending: Mutex::new(Vec::new()),
+            savepoints: Vec::new(),
+        }
+    }
+
+    fn pending(&self) -> MutexGuard<'_, Vec<ChangeEvent>> {
+        self.pending
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Record the start of a (possibly nested) transaction.
+    pub(crate) fn begin(&mut self) {
+        let len = self.pending().len();
+        self.savepoints.push(len);
+    }
+
+    /// Close the innermost transaction, publishing everything once the
+    /// outermost one commits.
+    pub(crate) fn commit(&mut self) {
+        self.savepoints.pop();
+        if self.savepoints.is_empty() {
+            let events = std::mem::take(&mut *self.pending());
+            for event in events {
+                // No subscribers is not an error.
+                let _ = self.sender.send(event);
+            }
+        }
+    }
+
+    /// Discard events recorded since the innermost transaction began.
+    pub(crate) fn rollback(&mut self) {
+        if let Some(len) = self.savepoints.pop() {
+            self.pending().truncate(len);
+        }
+    }
+}
+
+impl WorldStorage {
+    /// Subscribe to committed changes.
+    ///
+    /// Only events published after subscribing are received.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.changes.sender.subscribe()
+    }
+
+    /// Publish an event, or buffer it until the open transaction commits.
+    pub(crate) fn emit(&self, event: ChangeEvent) {
+        if self.changes.savepoints.is_empty() {
+            let _ = self.changes.sender.send(event);
+        } else {
+            self.changes.pending().push(event);
+        }
+    }
+}
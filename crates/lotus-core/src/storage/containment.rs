@@ -0,0 +1,135 @@
+//! Containment: which entity is inside which.
+//!
+//! The `location` prop on an entity is the source of truth and is what
+//! [`Entity::location`](crate::Entity::location) reads. Containers also
+//! carry a `contents` array of entity IDs for scripts that read it directly;
+//! [`WorldStorage::move_entity`] keeps the two in step, and
+//! [`WorldStorage::contents`] derives contents from `location` alone.
+
+use std::collections::HashSet;
+
+use super::{EntityQuery, RevisionKind, StorageError, WorldStorage};
+use crate::entity::EntityId;
+
+impl WorldStorage {
+    /// Move an entity into `new_location`, or out of any container if `None`.
+    ///
+    /// Sets the mover's `location` prop, removes it from the old container's
+    /// `contents` and appends it to the new one's, all in one transaction.
+    /// Fails with [`StorageError::ContainmentCycle`] if the mover would end
+    /// up inside itself.
+    pub async fn move_entity(
+        &mut self,
+        id: EntityId,
+        new_location: Option<EntityId>,
+    ) -> Result<(), StorageError> {
+        let tx = self.transaction().await?;
+        tx.move_entity_inner(id, new_location).await?;
+        tx.commit().await
+    }
+
+    async fn move_entity_inner(
+        &self,
+        id: EntityId,
+        new_location: Option<EntityId>,
+    ) -> Result<(), StorageError> {
+        let mover = self
+            .get_entity_raw(id)
+            .await?
+            .ok_or(StorageError::EntityNotFound(id))?;
+        if let Some(location) = new_location {
+            self.check_containment(id, location).await?;
+        }
+
+        let old_location = mover.location();
+        self.modify_props(id, RevisionKind::Update, |mut props| {
+            match new_location {
+                Some(location) => props.insert("location".to_string(), location.into()),
+                None => props.remove("location"),
+            };
+            Ok(props)
+        })
+        .await?;
+
+        if let Some(old) = old_location
+            && old_location != new_location
+        {
+            self.update_contents(old, |contents| {
+                contents.retain(|entry| entry.as_i64() != Some(id));
+            })
+            .await?;
+        }
+        if let Some(new) = new_location {
+            self.update_contents(new, |contents| {
+                if !contents.iter().any(|entry| entry.as_i64() == Some(id)) {
+                    contents.push(id.into());
+                }
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Reject putting `id` into `location` if `location` is `id` or is
+    /// (transitively) inside it.
+    async fn check_containment(
+        &self,
+        id: EntityId,
+        location: EntityId,
+    ) -> Result<(), StorageError> {
+        let mut seen = HashSet::new();
+        let mut current = Some(location);
+        while let Some(container) = current {
+            if container == id {
+                return Err(StorageError::ContainmentCycle { id, location });
+            }
+            // An existing cycle that doesn't involve `id` is not ours to fix.
+            if !seen.insert(container) {
+                break;
+            }
+            current = match self.get_entity_raw(container).await? {
+                Some(entity) => entity.location(),
+                None if container == location => {
+                    return Err(StorageError::EntityNotFound(location));
+                }
+                None => None,
+            };
+        }
+        Ok(())
+    }
+
+    /// Apply `edit` to a container's `contents` array. Missing containers
+    /// are skipped.
+    async fn update_contents(
+        &self,
+        container: EntityId,
+        edit: impl FnOnce(&mut Vec<serde_json::Value>),
+    ) -> Result<(), StorageError> {
+        let updated = self
+            .modify_props(container, RevisionKind::Update, |mut props| {
+                let mut contents = match props.remove("contents") {
+                    Some(serde_json::Value::Array(items)) => items,
+                    _ => Vec::new(),
+                };
+                edit(&mut contents);
+                props.insert("contents".to_string(), contents.into());
+                Ok(props)
+            })
+            .await;
+        match updated {
+            Ok(_) | Err(StorageError::EntityNotFound(_)) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// IDs of the entities whose `location` is `id`, in ID order.
+    ///
+    /// Derived from `location` rather than the container's `contents` prop,
+    /// so it is correct even where `contents` was edited by hand.
+    pub async fn contents(&self, id: EntityId) -> Result<Vec<EntityId>, StorageError> {
+        let entities = self
+            .find_entities(&EntityQuery::new().eq("location", id))
+            .await?;
+        Ok(entities.into_iter().map(|entity| entity.id).collect())
+    }
+}
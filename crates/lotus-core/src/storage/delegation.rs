@@ -0,0 +1,186 @@
+//! Capability delegation.
+//!
+//! A delegated capability records the capability it was derived from in
+//! `parent_id`. Children can only narrow what their parent grants, and
+//! revoking a capability revokes everything delegated from it, however
+//! many hands it has passed through.
+
+use libsql::params;
+
+use super::audit::NewAuditEntry;
+use super::{AuditAction, ChangeEvent, StorageError, WorldStorage};
+use crate::capability::{Capability, CapabilityRegistry};
+use crate::clock::Clock;
+use crate::entity::EntityId;
+
+/// Check that `parent` (looked up as `parent_id`) may be delegated with
+/// `restricted_params`, as described on
+/// [`WorldStorage::delegate_capability`], and return it.
+pub(crate) fn check_delegation(
+    parent: Option<Capability>,
+    parent_id: &str,
+    restricted_params: &serde_json::Value,
+    capability_types: &CapabilityRegistry,
+    clock: &dyn Clock,
+) -> Result<Capability, StorageError> {
+    let parent = parent.ok_or_else(|| {
+        StorageError::InvalidDelegation(format!("capability {} not found", parent_id))
+    })?;
+    if !parent.is_valid_at(clock.now_ms()) {
+        return Err(StorageError::InvalidDelegation(format!(
+            "capability {} is not currently valid",
+            parent_id
+        )));
+    }
+    match capability_types.get(&parent.cap_type) {
+        Some(capability_type) if capability_type.delegable => {
+            capability_type.validate(restricted_params)?;
+        }
+        Some(_) => {
+            return Err(StorageError::InvalidDelegation(format!(
+                "capability type '{}' is not delegable",
+                parent.cap_type
+            )));
+        }
+        None => {
+            return Err(StorageError::InvalidCapability(format!(
+                "unknown capability type '{}'",
+                parent.cap_type
+            )));
+        }
+    }
+    if !parent.permits_with_clock(clock, &parent.cap_type, restricted_params) {
+        return Err(StorageError::InvalidDelegation(format!(
+            "params {} are not a restriction of capability {}",
+            restricted_params, parent_id
+        )));
+    }
+    Ok(parent)
+}
+
+impl WorldStorage {
+    /// Derive a capability from `parent_id` for `new_owner_id`, restricted to
+    /// `restricted_params`.
+    ///
+    /// Every request the child would permit must also be permitted by the
+    /// parent, which `Capability::permits` decides by treating the child's
+    /// params as a request against the parent. The child has the parent's
+    /// type and validity window. Fails with
+    /// [`StorageError::InvalidDelegation`] if the parent is missing, not
+    /// currently valid, of a non-delegable type, or the params would widen
+    /// it, and with [`StorageError::InvalidCapability`] if the params don't
+    /// fit the type.
+    pub async fn delegate_capability(
+        &self,
+        parent_id: &str,
+        new_owner_id: EntityId,
+        restricted_params: serde_json::Value,
+    ) -> Result<String, StorageError> {
+        let parent = self.get_capability(parent_id).await?;
+        let parent = check_delegation(
+            parent,
+            parent_id,
+            &restricted_params,
+            &self.capability_types,
+            self.clock.as_ref(),
+        )?;
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let params_str = serde_json::to_string(&restricted_params)?;
+        self.conn
+            .execute(
+                "INSERT INTO capabilities
+                 (id, owner_id, type, params, not_before, expires_at, parent_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    id.clone(),
+                    new_owner_id,
+                    parent.cap_type.clone(),
+                    params_str,
+                    parent.not_before,
+                    parent.expires_at,
+                    parent_id
+                ],
+            )
+            .await?;
+        let mut entry =
+            NewAuditEntry::new(AuditAction::Delegated, &id, &parent.cap_type, new_owner_id);
+        entry.parent_id = Some(parent_id);
+        self.audit(entry).await?;
+        self.emit(ChangeEvent::CapabilityGranted {
+            id: id.clone(),
+            owner_id: new_owner_id,
+            cap_type: parent.cap_type,
+        });
+        Ok(id)
+    }
+
+    /// IDs of every capability delegated from `id`, directly or through
+    /// other delegations, in breadth-first order.
+    pub async fn capability_descendants(&self, id: &str) -> Result<Vec<String>, StorageError> {
+        let mut rows = self
+            .conn
+            .query(
+                r#"
+            WITH RECURSIVE tree(id, depth) AS (
+                SELECT id, 0 FROM capabilities WHERE parent_id = ?1
+                UNION
+                SELECT c.id, t.depth + 1
+                FROM capabilities c
+                JOIN tree t ON c.parent_id = t.id
+            )
+            SELECT id FROM tree ORDER BY depth, id
+            "#,
+                params![id],
+            )
+            .await?;
+
+        let mut ids = Vec::new();
+        while let Some(row) = rows.next().await? {
+            ids.push(row.get(0)?);
+        }
+        Ok(ids)
+    }
+
+    /// Delete the capabilities matching `condition` (an SQL expression over
+    /// `capabilities` using `?1`) and everything delegated from them,
+    /// auditing and emitting [`ChangeEvent::CapabilityRevoked`] for each.
+    pub(crate) async fn revoke_capability_trees(
+        &self,
+        condition: &str,
+        param: libsql::Value,
+    ) -> Result<(), StorageError> {
+        let mut rows = self
+            .conn
+            .query(
+                &format!(
+                    "WITH RECURSIVE tree(id) AS (
+                        SELECT id FROM capabilities WHERE {}
+                        UNION
+                        SELECT c.id FROM capabilities c JOIN tree t ON c.parent_id = t.id
+                    )
+                    DELETE FROM capabilities WHERE id IN (SELECT id FROM tree)
+                    RETURNING id, type, owner_id",
+                    condition
+                ),
+                vec![param],
+            )
+            .await?;
+
+        let mut revoked = Vec::new();
+        while let Some(row) = rows.next().await? {
+            revoked.push((row.get::<String>(0)?, row.get::<String>(1)?, row.get(2)?));
+        }
+        for (id, cap_type, owner_id) in revoked {
+            self.audit(NewAuditEntry::new(
+                AuditAction::Revoked,
+                &id,
+                &cap_type,
+                owner_id,
+            ))
+            .await?;
+            self.emit(ChangeEvent::CapabilityRevoked { id });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+-- World database as created by lotus-core before schema versioning.
+-- `verbs` predates the `required_capability` column.
+
+CREATE TABLE entities (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    prototype_id INTEGER,
+    props TEXT DEFAULT '{}',
+    FOREIGN KEY(prototype_id) REFERENCES entities(id)
+);
+
+CREATE TABLE verbs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    entity_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    code TEXT NOT NULL,
+    FOREIGN KEY(entity_id) REFERENCES entities(id) ON DELETE CASCADE,
+    UNIQUE(entity_id, name)
+);
+
+CREATE TABLE scheduled_tasks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    entity_id INTEGER NOT NULL,
+    verb TEXT NOT NULL,
+    args TEXT DEFAULT '[]',
+    execute_at INTEGER NOT NULL,
+    FOREIGN KEY(entity_id) REFERENCES entities(id) ON DELETE CASCADE
+);
+
+CREATE TABLE capabilities (
+    id TEXT PRIMARY KEY,
+    owner_id INTEGER NOT NULL,
+    type TEXT NOT NULL,
+    params TEXT NOT NULL,
+    FOREIGN KEY(owner_id) REFERENCES entities(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_capabilities_owner ON capabilities(owner_id);
+
+INSERT INTO entities (id, prototype_id, props) VALUES
+    (1, NULL, '{"kind": "room"}'),
+    (2, 1, '{"name": "Lobby"}');
+
+INSERT INTO verbs (entity_id, name, code) VALUES
+    (1, 'look', '["std.return", "You see a room."]');
+
+INSERT INTO scheduled_tasks (entity_id, verb, args, execute_at) VALUES
+    (2, 'tick', '[]', 0);
+
+INSERT INTO capabilities (id, owner_id, type, params) VALUES
+    ('cap-1', 2, 'entity.control', '{"target_id": 2}');
@@ -0,0 +1,279 @@
+//! Entity property revision history.
+//!
+//! Every write to an entity's own props appends a row to `entity_revisions`
+//! recording which keys changed, their old and new values, the acting
+//! entity and the enclosing transaction. Revisions are numbered per entity
+//! starting at 1 and are never rewritten, so past states can be rebuilt by
+//! undoing newer revisions on top of the current props.
+
+use libsql::params;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use super::{StorageError, WorldStorage};
+use crate::entity::{Entity, EntityId};
+
+/// What kind of write produced a revision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RevisionKind {
+    /// The entity was created with these props.
+    Create,
+    /// Props were changed by an update.
+    Update,
+    /// Props were restored to an earlier revision.
+    Revert,
+}
+
+impl RevisionKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            RevisionKind::Create => "create",
+            RevisionKind::Update => "update",
+            RevisionKind::Revert => "revert",
+        }
+    }
+
+    fn parse(stored: &str) -> Result<Self, StorageError> {
+        match stored {
+            "create" => Ok(RevisionKind::Create),
+            "update" => Ok(RevisionKind::Update),
+            "revert" => Ok(RevisionKind::Revert),
+            other => Err(StorageError::Constraint(format!(
+                "unknown revision kind '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// A change to a single top-level prop.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PropChange {
+    pub key: String,
+    /// Value before the change, or `None` if the key was absent.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "present"
+    )]
+    pub old: Option<serde_json::Value>,
+    /// Value after the change, or `None` if the key was removed.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "present"
+    )]
+    pub new: Option<serde_json::Value>,
+}
+
+/// Distinguish a stored JSON `null` (`Some(Null)`) from an absent key (`None`).
+fn present<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<serde_json::Value>, D::Error> {
+    serde_json::Value::deserialize(deserializer).map(Some)
+}
+
+/// One entry in an entity's revision log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntityRevision {
+    pub entity_id: EntityId,
+    /// Per-entity revision number, starting at 1.
+    pub revision: i64,
+    pub kind: RevisionKind,
+    /// Entity acting when the change was made, if one was set.
+    pub actor_id: Option<EntityId>,
+    /// Identifier of the outermost transaction the change was made in.
+    pub transaction_id: Option<String>,
+    pub changes: Vec<PropChange>,
+    /// Milliseconds since the Unix epoch.
+    pub created_at: i64,
+}
+
+/// A point in an entity's history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RevisionPoint {
+    /// State immediately after the given revision.
+    Revision(i64),
+    /// State as of the given time (milliseconds since the Unix epoch).
+    Time(i64),
+}
+
+/// Compute the per-key changes that turn `old` into `new`.
+pub(crate) fn diff_props(
+    old: &serde_json::Map<String, serde_json::Value>,
+    new: &serde_json::Map<String, serde_json::Value>,
+) -> Vec<PropChange> {
+    let mut changes = Vec::new();
+    for (key, value) in new {
+        if old.get(key) != Some(value) {
+            changes.push(PropChange {
+                key: key.clone(),
+                old: old.get(key).cloned(),
+                new: Some(value.clone()),
+            });
+        }
+    }
+    for (key, value) in old {
+        if !new.contains_key(key) {
+            changes.push(PropChange {
+                key: key.clone(),
+                old: Some(value.clone()),
+                new: None,
+            });
+        }
+    }
+    changes
+}
+
+impl WorldStorage {
+    /// Append a revision for `entity_id`.
+    ///
+    /// Updates and reverts that change nothing are not recorded; creations
+    /// always are, so history knows when the entity came into existence.
+    pub(crate) async fn record_revision(
+        &self,
+        entity_id: EntityId,
+        kind: RevisionKind,
+        changes: &[PropChange],
+    ) -> Result<Option<i64>, StorageError> {
+        if changes.is_empty() && kind != RevisionKind::Create {
+            return Ok(None);
+        }
+        let changes_str = serde_json::to_string(changes)?;
+        let mut rows = self
+            .conn
+            .query(
+                "INSERT INTO entity_revisions
+                    (entity_id, revision, kind, actor_id, transaction_id, changes, created_at)
+                SELECT ?1, COALESCE(MAX(revision), 0) + 1, ?2, ?3, ?4, ?5, ?6
+                FROM entity_revisions WHERE entity_id = ?1
+                RETURNING revision",
+                params![
+                    entity_id,
+                    kind.as_str(),
+                    self.actor,
+                    self.transaction_id.clone(),
+                    changes_str,
+                    self.clock.now_ms()
+                ],
+            )
+            .await?;
+        let revision: i64 = match rows.next().await? {
+            Some(row) => row.get(0)?,
+            None => return Err(StorageError::Transaction("revision not recorded".into())),
+        };
+        Ok(Some(revision))
+    }
+
+    /// Get an entity's revision log, oldest first.
+    pub async fn get_entity_revisions(
+        &self,
+        id: EntityId,
+    ) -> Result<Vec<EntityRevision>, StorageError> {
+        let mut rows = self
+            .conn
+            .query(
+                "SELECT entity_id, revision, kind, actor_id, transaction_id, changes, created_at
+                FROM entity_revisions WHERE entity_id = ?1 ORDER BY revision ASC",
+                params![id],
+            )
+            .await?;
+
+        let mut revisions = Vec::new();
+        while let Some(row) = rows.next().await? {
+            let kind: String = row.get(2)?;
+            let changes_str: String = row.get(5)?;
+            revisions.push(EntityRevision {
+                entity_id: row.get(0)?,
+                revision: row.get(1)?,
+                kind: RevisionKind::parse(&kind)?,
+                actor_id: row.get(3)?,
+                transaction_id: row.get(4)?,
+                changes: serde_json::from_str(&changes_str)?,
+                created_at: row.get(6)?,
+            });
+        }
+        Ok(revisions)
+    }
+
+    /// Get an entity's own props (without prototype resolution) as they
+    /// were at a point in its history.
+    ///
+    /// Returns `None` if the entity does not currently exist or had not yet
+    /// been created at that point. Entities that predate revision tracking
+    /// resolve to their oldest known state for earlier points.
+    pub async fn get_entity_at(
+        &self,
+        id: EntityId,
+        at: RevisionPoint,
+    ) -> Result<Option<Entity>, StorageError> {
+        let Some(mut entity) = self.get_entity_raw(id).await? else {
+            return Ok(None);
+        };
+        let revisions = self.get_entity_revisions(id).await?;
+
+        let is_after = |rev: &EntityRevision| match at {
+            RevisionPoint::Revision(revision) => rev.revision > revision,
+            RevisionPoint::Time(time) => rev.created_at > time,
+        };
+
+        let mut props = match entity.props {
+            serde_json::Value::Object(map) => map,
+            _ => serde_json::Map::new(),
+        };
+        for rev in revisions.iter().rev().take_while(|rev| is_after(rev)) {
+            if rev.kind == RevisionKind::Create {
+                return Ok(None);
+            }
+            for change in &rev.changes {
+                match &change.old {
+                    Some(value) => props.insert(change.key.clone(), value.clone()),
+                    None => props.remove(&change.key),
+                };
+            }
+        }
+
+        entity.props = serde_json::Value::Object(props);
+        Ok(Some(entity))
+    }
+
+    /// Restore an entity's own props to their state after `revision`.
+    ///
+    /// The restore is itself recorded as a new revision, so it can be
+    /// undone in turn. Returns the new revision number, or `None` if the
+    /// props already matched.
+    pub async fn revert_entity(
+        &self,
+        id: EntityId,
+        revision: i64,
+    ) -> Result<Option<i64>, StorageError> {
+        self.atomically(async || {
+            if self.get_entity_raw(id).await?.is_none() {
+                return Err(StorageError::EntityNotFound(id));
+            }
+            let not_found = StorageError::RevisionNotFound {
+                entity_id: id,
+                revision,
+            };
+            let latest = self
+                .get_entity_revisions(id)
+                .await?
+                .last()
+                .map(|latest| latest.revision);
+            if latest.is_none_or(|latest| revision > latest) {
+                return Err(not_found);
+            }
+            let target = self
+                .get_entity_at(id, RevisionPoint::Revision(revision))
+                .await?
+                .ok_or(not_found)?;
+            let target = match target.props {
+                serde_json::Value::Object(map) => map,
+                _ => serde_json::Map::new(),
+            };
+            self.modify_props(id, RevisionKind::Revert, |_| Ok(target))
+                .await
+        })
+        .await
+    }
+}
@@ -0,0 +1,163 @@
+//! Secondary indexes on entity props.
+//!
+//! A prop index is a SQLite expression index on
+//! `json_extract(props, <path>)`. [`EntityQuery`](super::EntityQuery)
+//! compiles filters and ordering on the same expression, so the query
+//! planner picks the index up without any hints.
+//!
+//! Index definitions are recorded in the `prop_indexes` table and recreated
+//! on open if the underlying SQLite index has gone missing.
+
+use libsql::params;
+use serde::{Deserialize, Serialize};
+
+use super::query::{json_path, sql_literal};
+use super::{StorageError, WorldStorage};
+
+/// A declared prop index.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PropIndex {
+    /// Name given at creation.
+    pub name: String,
+    /// Dot-separated prop path, as used by `EntityQuery`.
+    pub path: String,
+}
+
+/// Name of the SQLite index backing a prop index. SQLite compares index
+/// names case-insensitively, so prop index names are unique ignoring case.
+fn sql_index_name(name: &str) -> String {
+    format!("prop_idx_{}", name)
+}
+
+fn create_index_sql(name: &str, path: &str) -> Result<String, StorageError> {
+    let path = json_path(path)?;
+    Ok(format!(
+        "CREATE INDEX IF NOT EXISTS {} ON entities(json_extract(props, {}))",
+        sql_index_name(name),
+        sql_literal(&path.full)
+    ))
+}
+
+fn validate_name(name: &str) -> Result<(), StorageError> {
+    if name.is_empty()
+        || !name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+    {
+        return Err(StorageError::InvalidQuery(format!(
+            "invalid index name '{}': use ASCII letters, digits and underscores",
+            name
+        )));
+    }
+    Ok(())
+}
+
+impl WorldStorage {
+    /// Create an index on the prop at `path` (e.g. `location` or
+    /// `stats.hp`).
+    ///
+    /// Queries filtering or ordering on the path can use the index; whether
+    /// they do is up to the SQLite planner. Queries over inherited props
+    /// (`EntityQuery::inherited`) cannot.
+    pub async fn create_prop_index(&self, name: &str, path: &str) -> Result<(), StorageError> {
+        validate_name(name)?;
+        let sql = create_index_sql(name, path)?;
+
+        // A savepoint rather than a transaction, so this also works inside
+        // one the caller has open
+        self.conn.execute("SAVEPOINT create_prop_index", ()).await?;
+        let result = self.insert_prop_index(name, path, &sql).await;
+        if result.is_err() {
+            self.conn
+                .execute("ROLLBACK TO SAVEPOINT create_prop_index", ())
+                .await?;
+        }
+        self.conn
+            .execute("RELEASE SAVEPOINT create_prop_index", ())
+            .await?;
+        result
+    }
+
+    async fn insert_prop_index(
+        &self,
+        name: &str,
+        path: &str,
+        sql: &str,
+    ) -> Result<(), StorageError> {
+        let existing: Option<String> = match self
+            .conn
+            .query(
+                "SELECT name FROM prop_indexes WHERE name = ?1 COLLATE NOCASE",
+                params![name],
+            )
+            .await?
+            .next()
+            .await?
+        {
+            Some(row) => Some(row.get(0)?),
+            None => None,
+        };
+        if let Some(existing) = existing {
+            return Err(StorageError::Constraint(format!(
+                "prop index '{}' already exists",
+                existing
+            )));
+        }
+
+        self.conn.execute(sql, ()).await?;
+        self.conn
+            .execute(
+                "INSERT INTO prop_indexes (name, path, created_at) VALUES (?1, ?2, ?3)",
+                params![name, path, self.clock.now_ms()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Drop a prop index. Dropping an index that does not exist is a no-op.
+    pub async fn drop_prop_index(&self, name: &str) -> Result<(), StorageError> {
+        validate_name(name)?;
+        // Only drop the SQLite index if `name` is exactly the declared name,
+        // not one that merely matches it ignoring case
+        let dropped = self
+            .conn
+            .execute("DELETE FROM prop_indexes WHERE name = ?1", params![name])
+            .await?;
+        if dropped > 0 {
+            self.conn
+                .execute(
+                    &format!("DROP INDEX IF EXISTS {}", sql_index_name(name)),
+                    (),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// List declared prop indexes, ordered by name.
+    pub async fn prop_indexes(&self) -> Result<Vec<PropIndex>, StorageError> {
+        let mut rows = self
+            .conn
+            .query("SELECT name, path FROM prop_indexes ORDER BY name", ())
+            .await?;
+
+        let mut indexes = Vec::new();
+        while let Some(row) = rows.next().await? {
+            indexes.push(PropIndex {
+                name: row.get(0)?,
+                path: row.get(1)?,
+            });
+        }
+        Ok(indexes)
+    }
+
+    /// Recreate any declared index whose SQLite index is missing.
+    pub(crate) async fn restore_prop_indexes(&self) -> Result<(), StorageError> {
+        for index in self.prop_indexes().await? {
+            self.conn
+                .execute(&create_index_sql(&index.name, &index.path)?, ())
+                .await?;
+        }
+        Ok(())
+    }
+}
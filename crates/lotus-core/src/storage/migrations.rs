@@ -0,0 +1,565 @@
+//! Versioned schema migrations.
+//!
+//! Every change to the world schema is appended to [`MIGRATIONS`] as a new
+//! forward-only step. Applied versions are recorded in the `schema_version`
+//! table, so long-lived databases pick up new columns and tables the next
+//! time they are opened instead of relying on `CREATE TABLE IF NOT EXISTS`.
+//!
+//! Databases created before versioning existed have no `schema_version`
+//! table and are treated as version 0. Migration 1 therefore only creates
+//! tables that are missing, and later steps must tolerate whatever shape
+//! those legacy databases were left in.
+
+use libsql::{Connection, params};
+
+use super::StorageError;
+
+/// A single step within a migration.
+pub(crate) enum Step {
+    /// Execute a SQL statement.
+    Sql(&'static str),
+    /// Add a column unless the table already has it.
+    ///
+    /// Pre-versioning databases may or may not contain columns that were
+    /// later added to `CREATE TABLE IF NOT EXISTS`, so these are applied
+    /// conditionally.
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        definition: &'static str,
+    },
+}
+
+/// A forward migration to a specific schema version.
+pub(crate) struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub steps: &'static [Step],
+}
+
+/// All migrations, in the order they are applied.
+///
+/// Versions must be contiguous and start at 1. Never edit a migration that
+/// has shipped; add a new one instead.
+pub(crate) const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial schema",
+        steps: &[
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS entities (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                prototype_id INTEGER,
+                props TEXT DEFAULT '{}',
+                FOREIGN KEY(prototype_id) REFERENCES entities(id)
+            )",
+            ),
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS verbs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                entity_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                code TEXT NOT NULL,
+                FOREIGN KEY(entity_id) REFERENCES entities(id) ON DELETE CASCADE,
+                UNIQUE(entity_id, name)
+            )",
+            ),
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS scheduled_tasks (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                entity_id INTEGER NOT NULL,
+                verb TEXT NOT NULL,
+                args TEXT DEFAULT '[]',
+                execute_at INTEGER NOT NULL,
+                FOREIGN KEY(entity_id) REFERENCES entities(id) ON DELETE CASCADE
+            )",
+            ),
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS capabilities (
+                id TEXT PRIMARY KEY,
+                owner_id INTEGER NOT NULL,
+                type TEXT NOT NULL,
+                params TEXT NOT NULL,
+                FOREIGN KEY(owner_id) REFERENCES entities(id) ON DELETE CASCADE
+            )",
+            ),
+            Step::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_capabilities_owner ON capabilities(owner_id)",
+            ),
+        ],
+    },
+    Migration {
+        version: 2,
+        name: "verb capability requirements",
+        steps: &[Step::AddColumn {
+            table: "verbs",
+            column: "required_capability",
+            definition: "TEXT",
+        }],
+    },
+    Migration {
+        version: 3,
+        name: "entity revisions",
+        steps: &[
+            Step::Sql(
+                "CREATE TABLE entity_revisions (
+                entity_id INTEGER NOT NULL,
+                revision INTEGER NOT NULL,
+                kind TEXT NOT NULL,
+                actor_id INTEGER,
+                transaction_id TEXT,
+                changes TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY(entity_id, revision)
+            )",
+            ),
+            Step::Sql(
+                "CREATE INDEX idx_entity_revisions_time ON entity_revisions(entity_id, created_at)",
+            ),
+        ],
+    },
+    Migration {
+        version: 4,
+        name: "prop indexes",
+        steps: &[Step::Sql(
+            "CREATE TABLE prop_indexes (
+                name TEXT PRIMARY KEY,
+                path TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+        )],
+    },
+    Migration {
+        version: 5,
+        name: "relations",
+        steps: &[
+            Step::Sql(
+                "CREATE TABLE relations (
+                source_id INTEGER NOT NULL,
+                relation TEXT NOT NULL,
+                target_id INTEGER NOT NULL,
+                attributes TEXT NOT NULL DEFAULT '{}',
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY(source_id, relation, target_id),
+                FOREIGN KEY(source_id) REFERENCES entities(id) ON DELETE CASCADE,
+                FOREIGN KEY(target_id) REFERENCES entities(id) ON DELETE CASCADE
+            )",
+            ),
+            Step::Sql("CREATE INDEX idx_relations_target ON relations(target_id, relation)"),
+        ],
+    },
+    Migration {
+        version: 6,
+        name: "capability validity window",
+        steps: &[
+            Step::AddColumn {
+                table: "capabilities",
+                column: "not_before",
+                definition: "INTEGER",
+            },
+            Step::AddColumn {
+                table: "capabilities",
+                column: "expires_at",
+                definition: "INTEGER",
+            },
+        ],
+    },
+    Migration {
+        version: 7,
+        name: "capability delegation",
+        steps: &[
+            Step::AddColumn {
+                table: "capabilities",
+                column: "parent_id",
+                definition: "TEXT",
+            },
+            Step::Sql("CREATE INDEX idx_capabilities_parent ON capabilities(parent_id)"),
+        ],
+    },
+    Migration {
+        version: 8,
+        name: "capability audit log",
+        steps: &[
+            Step::Sql(
+                "CREATE TABLE capability_audit (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                capability_id TEXT NOT NULL,
+                action TEXT NOT NULL,
+                cap_type TEXT NOT NULL,
+                owner_id INTEGER NOT NULL,
+                previous_owner_id INTEGER,
+                parent_id TEXT,
+                actor_id INTEGER,
+                verb TEXT,
+                entity_id INTEGER,
+                created_at INTEGER NOT NULL
+            )",
+            ),
+            Step::Sql(
+                "CREATE INDEX idx_capability_audit_capability ON capability_audit(capability_id)",
+            ),
+            Step::Sql(
+                "CREATE INDEX idx_capability_audit_owner ON capability_audit(owner_id, created_at)",
+            ),
+            Step::Sql(
+                "CREATE INDEX idx_capability_audit_type ON capability_audit(cap_type, created_at)",
+            ),
+            Step::Sql(
+                "CREATE TRIGGER capability_audit_no_update BEFORE UPDATE ON capability_audit
+                BEGIN SELECT RAISE(ABORT, 'capability_audit is append-only'); END",
+            ),
+            Step::Sql(
+                "CREATE TRIGGER capability_audit_no_delete BEFORE DELETE ON capability_audit
+                BEGIN SELECT RAISE(ABORT, 'capability_audit is append-only'); END",
+            ),
+        ],
+    },
+    Migration {
+        version: 9,
+        name: "verb capability param templates",
+        steps: &[Step::AddColumn {
+            table: "verbs",
+            column: "required_params",
+            definition: "TEXT",
+        }],
+    },
+    Migration {
+        version: 10,
+        name: "recurring tasks",
+        steps: &[
+            Step::AddColumn {
+                table: "scheduled_tasks",
+                column: "interval_ms",
+                definition: "INTEGER",
+            },
+            Step::AddColumn {
+                table: "scheduled_tasks",
+                column: "cron",
+                definition: "TEXT",
+            },
+        ],
+    },
+    Migration {
+        version: 11,
+        name: "task dedupe keys",
+        steps: &[
+            Step::AddColumn {
+                table: "scheduled_tasks",
+                column: "dedupe_key",
+                definition: "TEXT",
+            },
+            Step::Sql(
+                "CREATE UNIQUE INDEX idx_scheduled_tasks_dedupe ON scheduled_tasks(entity_id, dedupe_key)
+                WHERE dedupe_key IS NOT NULL",
+            ),
+        ],
+    },
+    Migration {
+        version: 12,
+        name: "task leases and dead letters",
+        steps: &[
+            Step::AddColumn {
+                table: "scheduled_tasks",
+                column: "attempts",
+                definition: "INTEGER NOT NULL DEFAULT 0",
+            },
+            Step::AddColumn {
+                table: "scheduled_tasks",
+                column: "leased_until",
+                definition: "INTEGER",
+            },
+            Step::AddColumn {
+                table: "scheduled_tasks",
+                column: "last_error",
+                definition: "TEXT",
+            },
+            Step::AddColumn {
+                table: "scheduled_tasks",
+                column: "replaced",
+                definition: "INTEGER NOT NULL DEFAULT 0",
+            },
+            Step::Sql(
+                "CREATE INDEX idx_scheduled_tasks_due ON scheduled_tasks(execute_at, leased_until)",
+            ),
+            Step::Sql(
+                "CREATE TABLE dead_letter_tasks (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                task_id INTEGER NOT NULL,
+                entity_id INTEGER NOT NULL,
+                verb TEXT NOT NULL,
+                args TEXT NOT NULL,
+                attempts INTEGER NOT NULL,
+                last_error TEXT,
+                failed_at INTEGER NOT NULL,
+                FOREIGN KEY(entity_id) REFERENCES entities(id) ON DELETE CASCADE
+            )",
+            ),
+        ],
+    },
+    Migration {
+        version: 13,
+        name: "task callers, priorities and metadata",
+        steps: &[
+            Step::AddColumn {
+                table: "scheduled_tasks",
+                column: "caller_id",
+                definition: "INTEGER",
+            },
+            Step::AddColumn {
+                table: "scheduled_tasks",
+                column: "capability_id",
+                definition: "TEXT",
+            },
+            Step::AddColumn {
+                table: "scheduled_tasks",
+                column: "priority",
+                definition: "INTEGER NOT NULL DEFAULT 0",
+            },
+            Step::AddColumn {
+                table: "scheduled_tasks",
+                column: "metadata",
+                definition: "TEXT",
+            },
+        ],
+    },
+];
+
+/// Schema version this build of lotus-core migrates databases to.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+/// Read the schema version recorded in the database (0 if unversioned).
+pub(crate) async fn current_version(conn: &Connection) -> Result<u32, StorageError> {
+    let mut rows = conn
+        .query(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+            (),
+        )
+        .await?;
+    if rows.next().await?.is_none() {
+        return Ok(0);
+    }
+
+    let mut rows = conn
+        .query("SELECT COALESCE(MAX(version), 0) FROM schema_version", ())
+        .await?;
+    let version: i64 = match rows.next().await? {
+        Some(row) => row.get(0)?,
+        None => 0,
+    };
+    Ok(version as u32)
+}
+
+/// Bring the database up to [`SCHEMA_VERSION`].
+///
+/// Each migration runs in its own transaction together with the
+/// `schema_version` row that records it, so a failure leaves the database at
+/// the last fully applied version. Databases newer than this build are
+/// rejected rather than opened.
+pub(crate) async fn migrate(conn: &Connection) -> Result<u32, StorageError> {
+    let found = current_version(conn).await?;
+    if found > SCHEMA_VERSION {
+        return Err(StorageError::SchemaTooNew {
+            found,
+            supported: SCHEMA_VERSION,
+        });
+    }
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        )",
+        (),
+    )
+    .await?;
+
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > found)
+    {
+        conn.execute("BEGIN IMMEDIATE", ()).await?;
+        match apply(conn, migration).await {
+            Ok(()) => {
+                conn.execute("COMMIT", ()).await?;
+            }
+            Err(error) => {
+                conn.execute("ROLLBACK", ()).await?;
+                return Err(StorageError::Migration {
+                    version: migration.version,
+                    message: error.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(SCHEMA_VERSION)
+}
+
+async fn apply(conn: &Connection, migration: &Migration) -> Result<(), StorageError> {
+    for step in migration.steps {
+        match step {
+            Step::Sql(sql) => {
+                conn.execute(sql, ()).await?;
+            }
+            Step::AddColumn {
+                table,
+                column,
+                definition,
+            } => {
+                if !has_column(conn, table, column).await? {
+                    conn.execute(
+                        &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+                        (),
+                    )
+                    .await?;
+                }
+            }
+        }
+    }
+    conn.execute(
+        "INSERT INTO schema_version (version, name) VALUES (?1, ?2)",
+        params![migration.version, migration.name],
+    )
+    .await?;
+    Ok(())
+}
+
+async fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, StorageError> {
+    let mut rows = conn
+        .query(&format!("PRAGMA table_info({})", table), ())
+        .await?;
+    while let Some(row) = rows.next().await? {
+        let name: String = row.get(1)?;
+        if name == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WorldStorage;
+    use serde_json::json;
+
+    /// A world database as written by lotus-core before `verbs` gained the
+    /// `required_capability` column and before `schema_version` existed.
+    const V0_FIXTURE: &str = include_str!("fixtures/world_v0.sql");
+
+    /// Temporary database file removed on drop.
+    struct TempDb(std::path::PathBuf);
+
+    impl TempDb {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("lotus-{}.db", uuid::Uuid::new_v4())))
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    async fn write_fixture(db: &TempDb, sql: &str) {
+        let database = libsql::Builder::new_local(db.path()).build().await.unwrap();
+        let conn = database.connect().unwrap();
+        conn.execute_batch(sql).await.unwrap();
+    }
+
+    #[test]
+    fn test_migrations_are_contiguous() {
+        for (idx, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, idx + 1, "{}", migration.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fresh_database_is_at_head() {
+        let storage = WorldStorage::in_memory().await.unwrap();
+        assert_eq!(storage.schema_version().await.unwrap(), SCHEMA_VERSION);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_v0_fixture_to_head() {
+        let db = TempDb::new();
+        write_fixture(&db, V0_FIXTURE).await;
+
+        let storage = WorldStorage::open(db.path()).await.unwrap();
+        assert_eq!(storage.schema_version().await.unwrap(), SCHEMA_VERSION);
+
+        // Existing data survives
+        let entity = storage.get_entity(2).await.unwrap().unwrap();
+        assert_eq!(entity.name(), Some("Lobby"));
+        assert_eq!(entity.get_prop("kind"), Some(&json!("room")));
+        let verb = storage.get_verb(2, "look").await.unwrap().unwrap();
+        assert_eq!(verb.entity_id, 1);
+        assert!(verb.required_capability.is_none());
+
+        // New columns are usable
+        storage
+            .add_verb_with_cap(2, "reset", &json!(1), Some("system.exec"))
+            .await
+            .unwrap();
+        let verb = storage.get_verb(2, "reset").await.unwrap().unwrap();
+        assert_eq!(verb.required_capability.as_deref(), Some("system.exec"));
+    }
+
+    #[tokio::test]
+    async fn test_migrate_is_idempotent() {
+        let db = TempDb::new();
+        write_fixture(&db, V0_FIXTURE).await;
+
+        drop(WorldStorage::open(db.path()).await.unwrap());
+        let storage = WorldStorage::open(db.path()).await.unwrap();
+        assert_eq!(storage.schema_version().await.unwrap(), SCHEMA_VERSION);
+    }
+
+    #[tokio::test]
+    async fn test_unversioned_database_with_current_columns() {
+        // Databases created by the old CREATE TABLE IF NOT EXISTS schema after
+        // required_capability was added already have the column.
+        let db = TempDb::new();
+        write_fixture(
+            &db,
+            "CREATE TABLE verbs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                entity_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                code TEXT NOT NULL,
+                required_capability TEXT,
+                UNIQUE(entity_id, name)
+            );",
+        )
+        .await;
+
+        let storage = WorldStorage::open(db.path()).await.unwrap();
+        assert_eq!(storage.schema_version().await.unwrap(), SCHEMA_VERSION);
+    }
+
+    #[tokio::test]
+    async fn test_refuses_newer_database() {
+        let db = TempDb::new();
+        drop(WorldStorage::open(db.path()).await.unwrap());
+        write_fixture(
+            &db,
+            "INSERT INTO schema_version (version, name) VALUES (9999, 'from the future');",
+        )
+        .await;
+
+        match WorldStorage::open(db.path()).await {
+            Err(StorageError::SchemaTooNew { found, supported }) => {
+                assert_eq!(found, 9999);
+                assert_eq!(supported, SCHEMA_VERSION);
+            }
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(_) => panic!("newer database should be rejected"),
+        }
+    }
+}
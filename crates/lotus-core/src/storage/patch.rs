@@ -0,0 +1,370 @@
+//! Structured prop updates: JSON Patch, merge patch and key removal.
+//!
+//! [`WorldStorage::update_entity`] only merges top-level keys. The methods
+//! here can remove keys and edit nested values in place. Each computes the
+//! new props up front and writes them with a single `UPDATE`, so a failed
+//! patch leaves the entity untouched and a successful one participates in
+//! whatever transaction is open.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{RevisionKind, StorageError, WorldStorage};
+use crate::entity::EntityId;
+
+/// A single RFC 6902 JSON Patch operation.
+///
+/// Paths are RFC 6901 JSON Pointers into the entity's own props, e.g.
+/// `/stats/hp` or `/inventory/-` to append to an array.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+/// A patch to an entity's props.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "patch", rename_all = "snake_case")]
+pub enum PropsPatch {
+    /// RFC 6902 JSON Patch, applied in order and all-or-nothing.
+    JsonPatch(Vec<PatchOperation>),
+    /// RFC 7396 merge patch: objects merge recursively and `null` removes.
+    MergePatch(Value),
+}
+
+/// Apply an RFC 6902 patch to `doc`.
+///
+/// On error `doc` may be partially modified; callers should patch a copy.
+pub fn apply_json_patch(doc: &mut Value, ops: &[PatchOperation]) -> Result<(), String> {
+    for (idx, op) in ops.iter().enumerate() {
+        apply_operation(doc, op).map_err(|error| format!("operation {}: {}", idx, error))?;
+    }
+    Ok(())
+}
+
+/// Apply an RFC 7396 merge patch to `target`.
+pub fn apply_merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(serde_json::Map::new());
+    }
+    let Value::Object(target) = target else {
+        unreachable!()
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            apply_merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+fn apply_operation(doc: &mut Value, op: &PatchOperation) -> Result<(), String> {
+    match op {
+        PatchOperation::Add { path, value } => add(doc, path, value.clone()),
+        PatchOperation::Remove { path } => remove(doc, path).map(|_| ()),
+        PatchOperation::Replace { path, value } => {
+            let target = doc
+                .pointer_mut(path)
+                .ok_or_else(|| format!("path '{}' does not exist", path))?;
+            *target = value.clone();
+            Ok(())
+        }
+        PatchOperation::Move { from, path } => {
+            if path != from && path.starts_with(&format!("{}/", from)) {
+                return Err(format!("cannot move '{}' into its own child", from));
+            }
+            let value = remove(doc, from)?;
+            add(doc, path, value)
+        }
+        PatchOperation::Copy { from, path } => {
+            let value = doc
+                .pointer(from)
+                .cloned()
+                .ok_or_else(|| format!("path '{}' does not exist", from))?;
+            add(doc, path, value)
+        }
+        PatchOperation::Test { path, value } => match doc.pointer(path) {
+            Some(actual) if actual == value => Ok(()),
+            Some(_) => Err(format!("test failed at '{}'", path)),
+            None => Err(format!("path '{}' does not exist", path)),
+        },
+    }
+}
+
+/// Split a pointer into its parent pointer and unescaped last token.
+fn split_pointer(path: &str) -> Result<(&str, String), String> {
+    if path.is_empty() {
+        return Err("operation cannot target the whole document".to_string());
+    }
+    if !path.starts_with('/') {
+        return Err(format!("invalid JSON pointer '{}'", path));
+    }
+    let idx = path.rfind('/').unwrap();
+    let token = path[idx + 1..].replace("~1", "/").replace("~0", "~");
+    Ok((&path[..idx], token))
+}
+
+fn array_index(token: &str, len: usize, allow_end: bool) -> Result<usize, String> {
+    if allow_end && token == "-" {
+        return Ok(len);
+    }
+    let invalid = || format!("invalid array index '{}'", token);
+    if token.is_empty() || (token.len() > 1 && token.starts_with('0')) {
+        return Err(invalid());
+    }
+    let index: usize = token.parse().map_err(|_| invalid())?;
+    let max = if allow_end {
+        len
+    } else {
+        len.saturating_sub(1)
+    };
+    if index > max || (!allow_end && len == 0) {
+        return Err(format!("array index {} out of bounds", index));
+    }
+    Ok(index)
+}
+
+fn add(doc: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    if path.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+    let (parent, token) = split_pointer(path)?;
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => {
+            map.insert(token, value);
+            Ok(())
+        }
+        Some(Value::Array(items)) => {
+            let index = array_index(&token, items.len(), true)?;
+            items.insert(index, value);
+            Ok(())
+        }
+        Some(_) => Err(format!("parent of '{}' is not a container", path)),
+        None => Err(format!("parent of '{}' does not exist", path)),
+    }
+}
+
+fn remove(doc: &mut Value, path: &str) -> Result<Value, String> {
+    let (parent, token) = split_pointer(path)?;
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => map
+            .remove(&token)
+            .ok_or_else(|| format!("path '{}' does not exist", path)),
+        Some(Value::Array(items)) => {
+            let index = array_index(&token, items.len(), false)?;
+            Ok(items.remove(index))
+        }
+        _ => Err(format!("path '{}' does not exist", path)),
+    }
+}
+
+impl WorldStorage {
+    /// Apply a JSON Patch or merge patch to an entity's own props.
+    ///
+    /// The patch must leave props as a JSON object. Returns the new revision
+    /// number, or `None` if the patch changed nothing.
+    pub async fn patch_entity(
+        &self,
+        id: EntityId,
+        patch: &PropsPatch,
+    ) -> Result<Option<i64>, StorageError> {
+        self.modify_props(id, RevisionKind::Update, |previous| {
+            let mut doc = Value::Object(previous);
+            match patch {
+                PropsPatch::JsonPatch(ops) => {
+                    apply_json_patch(&mut doc, ops).map_err(StorageError::InvalidPatch)?
+                }
+                PropsPatch::MergePatch(patch) => apply_merge_patch(&mut doc, patch),
+            }
+            match doc {
+                Value::Object(next) => Ok(next),
+                _ => Err(StorageError::InvalidPatch(
+                    "props must remain a JSON object".to_string(),
+                )),
+            }
+        })
+        .await
+    }
+
+    /// Remove top-level keys from an entity's own props.
+    ///
+    /// Keys that are not present are ignored. Inherited values from the
+    /// prototype chain become visible again through [`get_entity`].
+    ///
+    /// [`get_entity`]: WorldStorage::get_entity
+    pub async fn remove_props(
+        &self,
+        id: EntityId,
+        keys: &[&str],
+    ) -> Result<Option<i64>, StorageError> {
+        self.modify_props(id, RevisionKind::Update, |mut props| {
+            for key in keys {
+                props.remove(*key);
+            }
+            Ok(props)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patch(doc: Value, ops: Value) -> Result<Value, String> {
+        let ops: Vec<PatchOperation> = serde_json::from_value(ops).unwrap();
+        let mut doc = doc;
+        apply_json_patch(&mut doc, &ops)?;
+        Ok(doc)
+    }
+
+    #[test]
+    fn test_json_patch_add_and_replace() {
+        let doc = patch(
+            json!({"stats": {"hp": 10}, "tags": ["a"]}),
+            json!([
+                {"op": "add", "path": "/stats/mp", "value": 5},
+                {"op": "replace", "path": "/stats/hp", "value": 7},
+                {"op": "add", "path": "/tags/-", "value": "c"},
+                {"op": "add", "path": "/tags/1", "value": "b"}
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            doc,
+            json!({"stats": {"hp": 7, "mp": 5}, "tags": ["a", "b", "c"]})
+        );
+    }
+
+    #[test]
+    fn test_json_patch_remove_move_copy() {
+        let doc = patch(
+            json!({"a": {"b": 1}, "list": [1, 2, 3]}),
+            json!([
+                {"op": "copy", "from": "/a/b", "path": "/c"},
+                {"op": "move", "from": "/a", "path": "/moved"},
+                {"op": "remove", "path": "/list/0"}
+            ]),
+        )
+        .unwrap();
+        assert_eq!(doc, json!({"c": 1, "moved": {"b": 1}, "list": [2, 3]}));
+    }
+
+    #[test]
+    fn test_json_patch_escaped_pointer() {
+        let doc = patch(
+            json!({"a/b": 1, "c~d": 2}),
+            json!([
+                {"op": "remove", "path": "/a~1b"},
+                {"op": "replace", "path": "/c~0d", "value": 3}
+            ]),
+        )
+        .unwrap();
+        assert_eq!(doc, json!({"c~d": 3}));
+    }
+
+    #[test]
+    fn test_json_patch_errors() {
+        let doc = json!({"a": 1, "list": [1]});
+        assert!(patch(doc.clone(), json!([{"op": "remove", "path": "/missing"}])).is_err());
+        assert!(
+            patch(
+                doc.clone(),
+                json!([{"op": "replace", "path": "/missing", "value": 1}])
+            )
+            .is_err()
+        );
+        assert!(
+            patch(
+                doc.clone(),
+                json!([{"op": "add", "path": "/x/y", "value": 1}])
+            )
+            .is_err()
+        );
+        assert!(
+            patch(
+                doc.clone(),
+                json!([{"op": "add", "path": "/list/5", "value": 1}])
+            )
+            .is_err()
+        );
+        assert!(patch(doc.clone(), json!([{"op": "remove", "path": "/list/01"}])).is_err());
+        assert!(
+            patch(
+                doc.clone(),
+                json!([{"op": "test", "path": "/a", "value": 2}])
+            )
+            .is_err()
+        );
+        assert!(
+            patch(
+                doc.clone(),
+                json!([{"op": "move", "from": "/list", "path": "/list/0"}])
+            )
+            .is_err()
+        );
+        assert!(patch(doc, json!([{"op": "test", "path": "/a", "value": 1}])).is_ok());
+    }
+
+    #[test]
+    fn test_merge_patch_rfc7396_examples() {
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (
+                json!({"a": "b"}),
+                json!({"b": "c"}),
+                json!({"a": "b", "b": "c"}),
+            ),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (
+                json!({"a": "b", "b": "c"}),
+                json!({"a": null}),
+                json!({"b": "c"}),
+            ),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (
+                json!({"a": [{"b": "c"}]}),
+                json!({"a": [1]}),
+                json!({"a": [1]}),
+            ),
+            (
+                json!({"e": null}),
+                json!({"a": 1}),
+                json!({"e": null, "a": 1}),
+            ),
+            (
+                json!([1, 2]),
+                json!({"a": "b", "c": null}),
+                json!({"a": "b"}),
+            ),
+            (
+                json!({}),
+                json!({"a": {"bb": {"ccc": null}}}),
+                json!({"a": {"bb": {}}}),
+            ),
+        ];
+        for (target, patch, expected) in cases {
+            let mut doc = target.clone();
+            apply_merge_patch(&mut doc, &patch);
+            assert_eq!(doc, expected, "{} + {}", target, patch);
+        }
+    }
+}
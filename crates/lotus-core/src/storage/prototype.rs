@@ -0,0 +1,177 @@
+//! Prototype chain integrity.
+//!
+//! The lineage queries in `get_entity`, `get_verb` and `get_verbs` follow
+//! `prototype_id` with a recursive CTE. A cycle would make them recurse
+//! forever, so writes that could introduce one are checked here and every
+//! lineage query is capped at [`WorldStorage::max_prototype_depth`].
+
+use std::collections::{HashMap, HashSet};
+
+use libsql::params;
+use serde::{Deserialize, Serialize};
+
+use super::{StorageError, WorldStorage};
+use crate::entity::EntityId;
+
+/// Default limit on the number of prototype hops followed from an entity.
+pub const DEFAULT_MAX_PROTOTYPE_DEPTH: usize = 64;
+
+/// A structural problem found by [`WorldStorage::validate_world`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WorldIssue {
+    /// These entities form a prototype cycle, listed in chain order starting
+    /// from the lowest ID.
+    PrototypeCycle { entities: Vec<EntityId> },
+    /// An entity's prototype does not exist.
+    DanglingPrototype {
+        id: EntityId,
+        prototype_id: EntityId,
+    },
+}
+
+/// Check that giving `id` the prototype `prototype_id` keeps the chain
+/// acyclic and within `max_depth` hops, given a `parent` lookup for the
+/// existing chain.
+///
+/// `id` is `None` for an entity that does not exist yet.
+pub(crate) fn check_chain(
+    id: Option<EntityId>,
+    prototype_id: EntityId,
+    max_depth: usize,
+    mut parent: impl FnMut(EntityId) -> Option<EntityId>,
+) -> Result<(), StorageError> {
+    let mut seen = HashSet::new();
+    let mut current = Some(prototype_id);
+    let mut depth = 0;
+    while let Some(ancestor) = current {
+        depth += 1;
+        if Some(ancestor) == id || !seen.insert(ancestor) {
+            return Err(StorageError::PrototypeCycle {
+                id: id.unwrap_or(ancestor),
+                prototype_id,
+            });
+        }
+        if depth > max_depth {
+            return Err(StorageError::PrototypeChainTooDeep { max_depth });
+        }
+        current = parent(ancestor);
+    }
+    Ok(())
+}
+
+impl WorldStorage {
+    /// Maximum number of prototype hops followed when resolving props and
+    /// verbs, and allowed when setting a prototype.
+    pub fn max_prototype_depth(&self) -> usize {
+        self.max_prototype_depth
+    }
+
+    /// Set the maximum prototype chain depth.
+    pub fn set_max_prototype_depth(&mut self, depth: usize) {
+        self.max_prototype_depth = depth;
+    }
+
+    /// Reject prototype assignments that would create a cycle or exceed the
+    /// maximum chain depth.
+    pub(crate) async fn check_prototype(
+        &self,
+        id: Option<EntityId>,
+        prototype_id: Option<EntityId>,
+    ) -> Result<(), StorageError> {
+        let Some(prototype_id) = prototype_id else {
+            return Ok(());
+        };
+
+        // Fetch one hop past the limit so both cycles and overly deep chains
+        // are visible without unbounded recursion.
+        let mut rows = self
+            .conn
+            .query(
+                r#"
+            WITH RECURSIVE lineage AS (
+                SELECT id, prototype_id, 0 as depth FROM entities WHERE id = ?1
+                UNION ALL
+                SELECT e.id, e.prototype_id, l.depth + 1
+                FROM entities e
+                JOIN lineage l ON e.id = l.prototype_id
+                WHERE l.depth <= ?2
+            )
+            SELECT id, prototype_id FROM lineage
+            "#,
+                params![prototype_id, self.max_prototype_depth as i64],
+            )
+            .await?;
+
+        let mut parents = HashMap::new();
+        while let Some(row) = rows.next().await? {
+            let ancestor: EntityId = row.get(0)?;
+            let parent: Option<EntityId> = row.get(1)?;
+            parents.entry(ancestor).or_insert(parent);
+        }
+
+        check_chain(id, prototype_id, self.max_prototype_depth, |entity| {
+            parents.get(&entity).copied().flatten()
+        })
+    }
+
+    /// Scan the whole world for prototype cycles and dangling prototypes.
+    ///
+    /// Intended for checking databases written before cycle detection
+    /// existed, or edited outside lotus-core.
+    pub async fn validate_world(&self) -> Result<Vec<WorldIssue>, StorageError> {
+        let mut rows = self
+            .conn
+            .query("SELECT id, prototype_id FROM entities ORDER BY id", ())
+            .await?;
+
+        let mut parents: HashMap<EntityId, Option<EntityId>> = HashMap::new();
+        let mut ids = Vec::new();
+        while let Some(row) = rows.next().await? {
+            let id: EntityId = row.get(0)?;
+            parents.insert(id, row.get(1)?);
+            ids.push(id);
+        }
+
+        let mut issues = Vec::new();
+        for &id in &ids {
+            if let Some(prototype_id) = parents[&id]
+                && !parents.contains_key(&prototype_id)
+            {
+                issues.push(WorldIssue::DanglingPrototype { id, prototype_id });
+            }
+        }
+
+        // Walk each chain once; a walk that runs into its own path has found
+        // a cycle, one that runs into an earlier walk has not.
+        let mut done: HashSet<EntityId> = HashSet::new();
+        for &start in &ids {
+            let mut path = Vec::new();
+            let mut on_path = HashSet::new();
+            let mut current = Some(start);
+            while let Some(id) = current {
+                if done.contains(&id) || !parents.contains_key(&id) {
+                    break;
+                }
+                if !on_path.insert(id) {
+                    let begin = path.iter().position(|&entity| entity == id).unwrap();
+                    let mut cycle = path[begin..].to_vec();
+                    let lowest = cycle
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, entity)| **entity)
+                        .unwrap()
+                        .0;
+                    cycle.rotate_left(lowest);
+                    issues.push(WorldIssue::PrototypeCycle { entities: cycle });
+                    break;
+                }
+                path.push(id);
+                current = parents[&id];
+            }
+            done.extend(path);
+        }
+
+        Ok(issues)
+    }
+}
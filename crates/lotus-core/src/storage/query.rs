@@ -0,0 +1,430 @@
+//! Finding entities by property values.
+//!
+//! [`EntityQuery`] is compiled to a single `SELECT` over `entities` using
+//! SQLite's `json_extract`/`json_type`, so filtering happens in the database
+//! rather than by fetching entities one at a time.
+//!
+//! Property paths are dot-separated keys into props, e.g. `location` or
+//! `stats.hp`. A segment made only of digits indexes into an array
+//! (`inventory.0`). Paths are inlined into the SQL as literals so that
+//! expression indexes on `json_extract(props, ...)` can be used.
+
+use libsql::Value as SqlValue;
+use serde_json::Value;
+
+use super::{StorageError, WorldStorage};
+use crate::entity::{Entity, EntityId};
+
+/// Sort direction for [`EntityQuery::order_by`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// A condition on a property path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Eq(String, Value),
+    /// Not equal. Like SQL `!=`, does not match entities where the path is
+    /// absent; combine with [`Filter::Missing`] if those are wanted.
+    Ne(String, Value),
+    Lt(String, Value),
+    Le(String, Value),
+    Gt(String, Value),
+    Ge(String, Value),
+    /// The path is present (a JSON `null` counts as present).
+    Exists(String),
+    /// The path is absent.
+    Missing(String),
+    /// The value at the path equals one of the given values.
+    In(String, Vec<Value>),
+}
+
+/// A query over entities, built with chained methods.
+///
+/// ```ignore
+/// let golems = storage
+///     .find_entities(
+///         &EntityQuery::new()
+///             .eq("location", 42)
+///             .gt("stats.hp", 0)
+///             .order_by("name", Order::Asc)
+///             .limit(10),
+///     )
+///     .await?;
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EntityQuery {
+    filters: Vec<Filter>,
+    descendant_of: Option<EntityId>,
+    inherited: bool,
+    order_by: Option<(String, Order)>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+}
+
+impl EntityQuery {
+    /// Create a query matching every entity.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an arbitrary filter.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    pub fn eq(self, path: &str, value: impl Into<Value>) -> Self {
+        self.filter(Filter::Eq(path.to_string(), value.into()))
+    }
+
+    pub fn ne(self, path: &str, value: impl Into<Value>) -> Self {
+        self.filter(Filter::Ne(path.to_string(), value.into()))
+    }
+
+    pub fn lt(self, path: &str, value: impl Into<Value>) -> Self {
+        self.filter(Filter::Lt(path.to_string(), value.into()))
+    }
+
+    pub fn le(self, path: &str, value: impl Into<Value>) -> Self {
+        self.filter(Filter::Le(path.to_string(), value.into()))
+    }
+
+    pub fn gt(self, path: &str, value: impl Into<Value>) -> Self {
+        self.filter(Filter::Gt(path.to_string(), value.into()))
+    }
+
+    pub fn ge(self, path: &str, value: impl Into<Value>) -> Self {
+        self.filter(Filter::Ge(path.to_string(), value.into()))
+    }
+
+    pub fn exists(self, path: &str) -> Self {
+        self.filter(Filter::Exists(path.to_string()))
+    }
+
+    pub fn missing(self, path: &str) -> Self {
+        self.filter(Filter::Missing(path.to_string()))
+    }
+
+    pub fn is_in<V: Into<Value>>(self, path: &str, values: impl IntoIterator<Item = V>) -> Self {
+        self.filter(Filter::In(
+            path.to_string(),
+            values.into_iter().map(Into::into).collect(),
+        ))
+    }
+
+    /// Only match entities that have `prototype_id` somewhere in their
+    /// prototype chain (not including the prototype itself).
+    pub fn descendant_of(mut self, prototype_id: EntityId) -> Self {
+        self.descendant_of = Some(prototype_id);
+        self
+    }
+
+    /// Match filters against props resolved through the prototype chain
+    /// instead of only the entity's own props.
+    ///
+    /// Resolution follows `get_entity`: the nearest entity in the chain that
+    /// has the path's top-level key supplies the value.
+    pub fn inherited(mut self, inherited: bool) -> Self {
+        self.inherited = inherited;
+        self
+    }
+
+    /// Sort results by the value at `path`. Ties, and unordered queries, are
+    /// sorted by entity ID.
+    pub fn order_by(mut self, path: &str, order: Order) -> Self {
+        self.order_by = Some((path.to_string(), order));
+        self
+    }
+
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Compile to SQL and positional parameters.
+    pub(crate) fn to_sql(&self, max_depth: usize) -> Result<(String, Vec<SqlValue>), StorageError> {
+        let mut params = Vec::new();
+        let mut conditions = Vec::new();
+
+        for filter in &self.filters {
+            conditions.push(self.compile_filter(filter, &mut params)?);
+        }
+
+        // Candidates are the only rows the lineage is walked from: the
+        // descendants of `descendant_of`, found by walking down from it, or
+        // every entity
+        let mut ctes = Vec::new();
+        if let Some(prototype_id) = self.descendant_of {
+            params.push(SqlValue::Integer(prototype_id));
+            ctes.push(format!(
+                "candidates(id, depth) AS (
+                SELECT id, 1 FROM entities WHERE prototype_id = ?{}
+                UNION
+                SELECT e.id, c.depth + 1
+                FROM entities e
+                JOIN candidates c ON e.prototype_id = c.id
+                WHERE c.depth < {}
+            )",
+                params.len(),
+                max_depth
+            ));
+            conditions.push("e.id IN (SELECT id FROM candidates)".to_string());
+        }
+        if self.inherited {
+            let seed = if self.descendant_of.is_some() {
+                "SELECT DISTINCT c.id, e.id, e.prototype_id, 0
+                FROM candidates c JOIN entities e ON e.id = c.id"
+            } else {
+                "SELECT id, id, prototype_id, 0 FROM entities"
+            };
+            ctes.push(format!(
+                "lineage(root_id, id, prototype_id, depth) AS (
+                {}
+                UNION ALL
+                SELECT l.root_id, e.id, e.prototype_id, l.depth + 1
+                FROM entities e
+                JOIN lineage l ON e.id = l.prototype_id
+                WHERE l.depth < {}
+            )",
+                seed, max_depth
+            ));
+        }
+
+        let mut sql = String::new();
+        if !ctes.is_empty() {
+            sql.push_str("WITH RECURSIVE ");
+            sql.push_str(&ctes.join(", "));
+            sql.push(' ');
+        }
+        sql.push_str("SELECT e.id, e.prototype_id, e.props FROM entities e");
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+
+        sql.push_str(" ORDER BY ");
+        if let Some((path, order)) = &self.order_by {
+            let path = json_path(path)?;
+            sql.push_str(&format!(
+                "json_extract({}, {}) {}, ",
+                self.source(&path),
+                sql_literal(&path.full),
+                match order {
+                    Order::Asc => "ASC",
+                    Order::Desc => "DESC",
+                }
+            ));
+        }
+        sql.push_str("e.id ASC");
+
+        if self.limit.is_some() || self.offset.is_some() {
+            let limit = self.limit.map_or(-1, |limit| limit as i64);
+            sql.push_str(&format!(" LIMIT {}", limit));
+            if let Some(offset) = self.offset {
+                sql.push_str(&format!(" OFFSET {}", offset));
+            }
+        }
+
+        Ok((sql, params))
+    }
+
+    /// Expression for the props a path is read from.
+    fn source(&self, path: &JsonPath) -> String {
+        if self.inherited {
+            format!(
+                "(SELECT p.props FROM lineage l JOIN entities p ON p.id = l.id
+                WHERE l.root_id = e.id AND json_type(p.props, {}) IS NOT NULL
+                ORDER BY l.depth LIMIT 1)",
+                sql_literal(&path.top)
+            )
+        } else {
+            "e.props".to_string()
+        }
+    }
+
+    fn compile_filter(
+        &self,
+        filter: &Filter,
+        params: &mut Vec<SqlValue>,
+    ) -> Result<String, StorageError> {
+        let (path, op, value) = match filter {
+            Filter::Exists(path) | Filter::Missing(path) => {
+                let path = json_path(path)?;
+                let null = if matches!(filter, Filter::Exists(_)) {
+                    "IS NOT NULL"
+                } else {
+                    "IS NULL"
+                };
+                return Ok(format!(
+                    "json_type({}, {}) {}",
+                    self.source(&path),
+                    sql_literal(&path.full),
+                    null
+                ));
+            }
+            Filter::In(path, values) => {
+                let path = json_path(path)?;
+                if values.is_empty() {
+                    return Ok("0".to_string());
+                }
+                let alternatives = values
+                    .iter()
+                    .map(|value| self.compare(&path, "=", value, params))
+                    .collect::<Vec<_>>();
+                return Ok(format!("({})", alternatives.join(" OR ")));
+            }
+            Filter::Eq(path, value) => (path, "=", value),
+            Filter::Ne(path, value) => (path, "!=", value),
+            Filter::Lt(path, value) => (path, "<", value),
+            Filter::Le(path, value) => (path, "<=", value),
+            Filter::Gt(path, value) => (path, ">", value),
+            Filter::Ge(path, value) => (path, ">=", value),
+        };
+        let path = json_path(path)?;
+        Ok(self.compare(&path, op, value, params))
+    }
+
+    fn compare(
+        &self,
+        path: &JsonPath,
+        op: &str,
+        value: &Value,
+        params: &mut Vec<SqlValue>,
+    ) -> String {
+        let source = self.source(path);
+        let full = sql_literal(&path.full);
+        match value {
+            // json_extract turns booleans into 0/1, so compare on the JSON
+            // type to keep `true` distinct from the number 1.
+            Value::Null | Value::Bool(_) if op == "=" || op == "!=" => {
+                let json_type = match value {
+                    Value::Null => "null",
+                    Value::Bool(true) => "true",
+                    _ => "false",
+                };
+                // json_type is NULL for an absent path, which neither
+                // comparison matches
+                format!("json_type({}, {}) {} '{}'", source, full, op, json_type)
+            }
+            Value::Array(_) | Value::Object(_) => {
+                params.push(SqlValue::Text(value.to_string()));
+                format!(
+                    "json_extract({}, {}) {} json(?{})",
+                    source,
+                    full,
+                    op,
+                    params.len()
+                )
+            }
+            _ => {
+                params.push(to_sql_value(value));
+                let comparison = format!(
+                    "json_extract({}, {}) {} ?{}",
+                    source,
+                    full,
+                    op,
+                    params.len()
+                );
+                // Stored booleans come out of json_extract as 0/1 as well,
+                // and are never equal to, or ordered against, a number
+                let boolean = format!("json_type({}, {}) IN ('true', 'false')", source, full);
+                if op == "!=" {
+                    format!("({} OR {})", boolean, comparison)
+                } else {
+                    format!("(NOT {} AND {})", boolean, comparison)
+                }
+            }
+        }
+    }
+}
+
+/// A compiled property path.
+pub(crate) struct JsonPath {
+    /// SQLite JSON path for the full property path.
+    pub(crate) full: String,
+    /// SQLite JSON path for just the top-level key.
+    top: String,
+}
+
+pub(crate) fn json_path(path: &str) -> Result<JsonPath, StorageError> {
+    if path.is_empty() {
+        return Err(StorageError::InvalidQuery(
+            "empty property path".to_string(),
+        ));
+    }
+    let mut full = String::from("$");
+    let mut top = None;
+    for segment in path.split('.') {
+        if segment.is_empty() {
+            return Err(StorageError::InvalidQuery(format!(
+                "empty segment in property path '{}'",
+                path
+            )));
+        }
+        if top.is_some() && segment.bytes().all(|byte| byte.is_ascii_digit()) {
+            full.push_str(&format!("[{}]", segment));
+        } else if segment.contains('"') {
+            return Err(StorageError::InvalidQuery(format!(
+                "property path '{}' contains a double quote",
+                path
+            )));
+        } else {
+            full.push_str(&format!(".\"{}\"", segment));
+        }
+        if top.is_none() {
+            top = Some(full.clone());
+        }
+    }
+    Ok(JsonPath {
+        full,
+        top: top.unwrap(),
+    })
+}
+
+pub(crate) fn sql_literal(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+fn to_sql_value(value: &Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(flag) => SqlValue::Integer(*flag as i64),
+        Value::Number(number) => match number.as_i64() {
+            Some(integer) => SqlValue::Integer(integer),
+            None => SqlValue::Real(number.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(text) => SqlValue::Text(text.clone()),
+        other => SqlValue::Text(other.to_string()),
+    }
+}
+
+impl WorldStorage {
+    /// Find entities matching a query.
+    ///
+    /// Returned entities carry their own props only, as with
+    /// [`get_entity_raw`](WorldStorage::get_entity_raw), even when the query
+    /// matched inherited props.
+    pub async fn find_entities(&self, query: &EntityQuery) -> Result<Vec<Entity>, StorageError> {
+        let (sql, params) = query.to_sql(self.max_prototype_depth)?;
+        let mut rows = self.conn.query(&sql, params).await?;
+
+        let mut entities = Vec::new();
+        while let Some(row) = rows.next().await? {
+            let props_str: String = row.get(2)?;
+            entities.push(Entity {
+                id: row.get(0)?,
+                prototype_id: row.get(1)?,
+                props: serde_json::from_str(&props_str)?,
+            });
+        }
+        Ok(entities)
+    }
+}
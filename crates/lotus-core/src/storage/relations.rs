@@ -0,0 +1,287 @@
+//! Typed edges between entities.
+//!
+//! A relation is a named, directed edge from a source entity to a target
+//! entity, optionally carrying JSON attributes (`exit` with `{"dir":
+//! "north"}`, `requires` between quest steps). At most one edge exists per
+//! (source, relation, target); linking again replaces its attributes.
+//! Relations are removed along with either endpoint.
+
+use std::collections::{HashMap, HashSet};
+
+use libsql::{Value as SqlValue, params};
+use serde::{Deserialize, Serialize};
+
+use super::{ChangeEvent, StorageError, WorldStorage};
+use crate::entity::EntityId;
+
+/// A directed, named edge between two entities.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Relation {
+    pub source_id: EntityId,
+    pub relation: String,
+    pub target_id: EntityId,
+    pub attributes: serde_json::Value,
+}
+
+/// Most sources looked up per query by `edges_from`, well under SQLite's
+/// smallest default limit of 999 bound parameters.
+const EDGE_QUERY_CHUNK: usize = 500;
+
+fn row_to_relation(row: &libsql::Row) -> Result<Relation, StorageError> {
+    let attributes: String = row.get(3)?;
+    Ok(Relation {
+        source_id: row.get(0)?,
+        relation: row.get(1)?,
+        target_id: row.get(2)?,
+        attributes: serde_json::from_str(&attributes)?,
+    })
+}
+
+impl WorldStorage {
+    /// Link `source_id` to `target_id` with the named relation.
+    ///
+    /// Replaces the attributes if the edge already exists. Both entities
+    /// must exist.
+    pub async fn link(
+        &self,
+        source_id: EntityId,
+        relation: &str,
+        target_id: EntityId,
+        attributes: serde_json::Value,
+    ) -> Result<(), StorageError> {
+        if relation.is_empty() {
+            return Err(StorageError::Constraint(
+                "relation name must not be empty".to_string(),
+            ));
+        }
+        for id in [source_id, target_id] {
+            if self.get_entity_raw(id).await?.is_none() {
+                return Err(StorageError::EntityNotFound(id));
+            }
+        }
+
+        let attributes_str = serde_json::to_string(&attributes)?;
+        self.conn
+            .execute(
+                "INSERT INTO relations (source_id, relation, target_id, attributes, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT(source_id, relation, target_id)
+                 DO UPDATE SET attributes = excluded.attributes",
+                params![
+                    source_id,
+                    relation,
+                    target_id,
+                    attributes_str,
+                    self.clock.now_ms()
+                ],
+            )
+            .await?;
+        self.emit(ChangeEvent::RelationLinked {
+            source_id,
+            relation: relation.to_string(),
+            target_id,
+        });
+        Ok(())
+    }
+
+    /// Remove an edge. Removing an edge that does not exist is a no-op.
+    pub async fn unlink(
+        &self,
+        source_id: EntityId,
+        relation: &str,
+        target_id: EntityId,
+    ) -> Result<(), StorageError> {
+        let removed = self
+            .conn
+            .execute(
+                "DELETE FROM relations WHERE source_id = ?1 AND relation = ?2 AND target_id = ?3",
+                params![source_id, relation, target_id],
+            )
+            .await?;
+        if removed > 0 {
+            self.emit(ChangeEvent::RelationUnlinked {
+                source_id,
+                relation: relation.to_string(),
+                target_id,
+            });
+        }
+        Ok(())
+    }
+
+    /// Edges leaving `source_id`, optionally only those of one relation,
+    /// ordered by relation then target.
+    pub async fn outgoing(
+        &self,
+        source_id: EntityId,
+        relation: Option<&str>,
+    ) -> Result<Vec<Relation>, StorageError> {
+        let mut rows = self
+            .conn
+            .query(
+                "SELECT source_id, relation, target_id, attributes FROM relations
+                 WHERE source_id = ?1 AND (?2 IS NULL OR relation = ?2)
+                 ORDER BY relation, target_id",
+                params![source_id, relation],
+            )
+            .await?;
+
+        let mut relations = Vec::new();
+        while let Some(row) = rows.next().await? {
+            relations.push(row_to_relation(&row)?);
+        }
+        Ok(relations)
+    }
+
+    /// Edges arriving at `target_id`, optionally only those of one relation,
+    /// ordered by relation then source.
+    pub async fn incoming(
+        &self,
+        target_id: EntityId,
+        relation: Option<&str>,
+    ) -> Result<Vec<Relation>, StorageError> {
+        let mut rows = self
+            .conn
+            .query(
+                "SELECT source_id, relation, target_id, attributes FROM relations
+                 WHERE target_id = ?1 AND (?2 IS NULL OR relation = ?2)
+                 ORDER BY relation, source_id",
+                params![target_id, relation],
+            )
+            .await?;
+
+        let mut relations = Vec::new();
+        while let Some(row) = rows.next().await? {
+            relations.push(row_to_relation(&row)?);
+        }
+        Ok(relations)
+    }
+
+    /// Entities reachable from `start` by following at most `max_hops`
+    /// outgoing edges (of `relation`, if given).
+    ///
+    /// Returns `(id, hops)` pairs with the fewest hops needed to reach each
+    /// entity, ordered by hops then ID. `start` itself is not included.
+    pub async fn reachable(
+        &self,
+        start: EntityId,
+        relation: Option<&str>,
+        max_hops: usize,
+    ) -> Result<Vec<(EntityId, usize)>, StorageError> {
+        let mut rows = self
+            .conn
+            .query(
+                r#"
+            WITH RECURSIVE walk(id, depth) AS (
+                SELECT ?1, 0
+                UNION
+                SELECT r.target_id, w.depth + 1
+                FROM relations r
+                JOIN walk w ON r.source_id = w.id
+                WHERE w.depth < ?2 AND (?3 IS NULL OR r.relation = ?3)
+            )
+            SELECT id, MIN(depth) AS hops FROM walk
+            WHERE id != ?1
+            GROUP BY id
+            ORDER BY hops, id
+            "#,
+                params![start, max_hops as i64, relation],
+            )
+            .await?;
+
+        let mut reached = Vec::new();
+        while let Some(row) = rows.next().await? {
+            let hops: i64 = row.get(1)?;
+            reached.push((row.get(0)?, hops as usize));
+        }
+        Ok(reached)
+    }
+
+    /// The shortest chain of entities from `from` to `to` following outgoing
+    /// edges (of `relation`, if given), including both ends.
+    ///
+    /// Returns `None` if `to` is not reachable within `max_hops`. Among
+    /// equally short paths, the one through lower IDs is preferred.
+    pub async fn shortest_path(
+        &self,
+        from: EntityId,
+        to: EntityId,
+        relation: Option<&str>,
+        max_hops: usize,
+    ) -> Result<Option<Vec<EntityId>>, StorageError> {
+        if from == to {
+            return Ok(Some(vec![from]));
+        }
+
+        // Breadth-first, one query per hop, remembering how each entity was
+        // first reached.
+        let mut previous: HashMap<EntityId, EntityId> = HashMap::new();
+        let mut visited = HashSet::from([from]);
+        let mut frontier = vec![from];
+        for _ in 0..max_hops {
+            if frontier.is_empty() {
+                break;
+            }
+            let mut next = Vec::new();
+            for (source, target) in self.edges_from(&frontier, relation).await? {
+                if visited.insert(target) {
+                    previous.insert(target, source);
+                    next.push(target);
+                }
+            }
+            if previous.contains_key(&to) {
+                let mut path = vec![to];
+                let mut current = to;
+                while let Some(&source) = previous.get(&current) {
+                    path.push(source);
+                    current = source;
+                }
+                path.reverse();
+                return Ok(Some(path));
+            }
+            frontier = next;
+        }
+        Ok(None)
+    }
+
+    /// `(source, target)` pairs for edges leaving any of `sources`, ordered
+    /// by source then target.
+    ///
+    /// Queries the sources in chunks, since a wide frontier could exceed
+    /// SQLite's limit on bound parameters.
+    async fn edges_from(
+        &self,
+        sources: &[EntityId],
+        relation: Option<&str>,
+    ) -> Result<Vec<(EntityId, EntityId)>, StorageError> {
+        let mut sources = sources.to_vec();
+        sources.sort_unstable();
+        let mut edges = Vec::new();
+        for chunk in sources.chunks(EDGE_QUERY_CHUNK) {
+            let mut params: Vec<SqlValue> = vec![relation.map(str::to_string).into()];
+            let placeholders = chunk
+                .iter()
+                .map(|&id| {
+                    params.push(SqlValue::Integer(id));
+                    format!("?{}", params.len())
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            let mut rows = self
+                .conn
+                .query(
+                    &format!(
+                        "SELECT source_id, target_id FROM relations
+                         WHERE source_id IN ({}) AND (?1 IS NULL OR relation = ?1)
+                         ORDER BY source_id, target_id",
+                        placeholders
+                    ),
+                    params,
+                )
+                .await?;
+            while let Some(row) = rows.next().await? {
+                edges.push((row.get(0)?, row.get(1)?));
+            }
+        }
+        Ok(edges)
+    }
+}
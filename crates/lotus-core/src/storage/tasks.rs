@@ -0,0 +1,686 @@
+//! Scheduled tasks.
+//!
+//! One-shot tasks are deleted by the scheduler when they fire. Recurring
+//! tasks keep their row and have `execute_at` moved to the next run
+//! instead; their [`Recurrence`] is stored as either `interval_ms` or
+//! `cron`.
+//!
+//! The scheduler claims due tasks by leasing them rather than deleting
+//! them, and only removes or re-arms a task once its verb has run. A task
+//! whose verb keeps failing is eventually moved to `dead_letter_tasks`
+//! along with its last error. Leases left behind by a crashed scheduler
+//! expire, after which the task is claimed again.
+//!
+//! A task scheduled with a dedupe key replaces any task on the same entity
+//! with the same key, keeping its id, so a seed that schedules its `tick`
+//! on every load ends up with one tick rather than one per load. Replacing a
+//! task while it runs keeps its lease, so the replacement waits for the run
+//! to finish rather than running alongside it. Rescheduling a running task
+//! works the same way.
+//!
+//! Settling a claimed task is fenced on the lease it was claimed with: if
+//! the lease ran out and someone else claimed the task, or the task was
+//! replaced, the late outcome is dropped instead of clobbering the newer
+//! state.
+//!
+//! Tasks due at the same instant run highest priority first, then in the
+//! order they were scheduled.
+
+use std::cmp::Reverse;
+
+use libsql::{Value as SqlValue, params};
+
+use super::{ChangeEvent, StorageError, WorldStorage};
+use crate::entity::EntityId;
+use crate::scheduler::{CronSchedule, Recurrence, checked_interval};
+
+/// A scheduled task.
+#[derive(Debug, Clone)]
+pub struct ScheduledTask {
+    pub id: i64,
+    pub entity_id: EntityId,
+    pub verb: String,
+    pub args: serde_json::Value,
+    pub execute_at: i64,
+    /// How the task is re-armed after it runs; `None` for one-shot tasks.
+    pub recurrence: Option<Recurrence>,
+    /// Key unique among the entity's tasks, if one was given.
+    pub dedupe_key: Option<String>,
+    /// Deliveries so far without success, counting the current one.
+    pub attempts: u32,
+    /// While claimed by a scheduler, the time its lease runs out.
+    pub leased_until: Option<i64>,
+    /// Error from the last failed attempt.
+    pub last_error: Option<String>,
+    /// Entity that scheduled the task, which the verb runs on behalf of.
+    pub caller_id: Option<EntityId>,
+    /// Capability the task runs under, if it was scheduled with one.
+    pub capability_id: Option<String>,
+    /// Tasks due at the same instant run highest priority first.
+    pub priority: i32,
+    /// Free-form data for whoever runs the task.
+    pub metadata: Option<serde_json::Value>,
+}
+
+impl ScheduledTask {
+    /// Key that sorts tasks into the order they should run.
+    pub(crate) fn run_order(&self) -> (i64, Reverse<i32>, i64) {
+        (self.execute_at, Reverse(self.priority), self.id)
+    }
+}
+
+/// A task that failed too many times to retry.
+#[derive(Debug, Clone)]
+pub struct DeadLetterTask {
+    pub id: i64,
+    /// Id the task had while scheduled.
+    pub task_id: i64,
+    pub entity_id: EntityId,
+    pub verb: String,
+    pub args: serde_json::Value,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub failed_at: i64,
+}
+
+/// Optional settings for a new task.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TaskOptions {
+    pub recurrence: Option<Recurrence>,
+    pub dedupe_key: Option<String>,
+    pub caller_id: Option<EntityId>,
+    pub capability_id: Option<String>,
+    pub priority: i32,
+    pub metadata: Option<serde_json::Value>,
+}
+
+impl TaskOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn recurring(mut self, recurrence: Recurrence) -> Self {
+        self.recurrence = Some(recurrence);
+        self
+    }
+
+    /// Replace any task on the same entity with the same key instead of
+    /// adding another.
+    pub fn dedupe_key(mut self, key: &str) -> Self {
+        self.dedupe_key = Some(key.to_string());
+        self
+    }
+
+    /// Record the entity the task is scheduled on behalf of.
+    pub fn caller(mut self, caller_id: EntityId) -> Self {
+        self.caller_id = Some(caller_id);
+        self
+    }
+
+    /// Run the task under a capability, to be re-checked when it fires.
+    pub fn capability(mut self, capability_id: &str) -> Self {
+        self.capability_id = Some(capability_id.to_string());
+        self
+    }
+
+    /// Order among tasks due at the same instant; higher runs first.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn metadata(mut self, metadata: serde_json::Value) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+}
+
+/// Filter for listing or cancelling tasks. Every field set narrows the
+/// result; an empty filter matches every task.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TaskFilter {
+    pub id: Option<i64>,
+    pub entity_id: Option<EntityId>,
+    pub verb: Option<String>,
+    pub dedupe_key: Option<String>,
+    pub caller_id: Option<EntityId>,
+    /// Only recurring (`true`) or one-shot (`false`) tasks.
+    pub recurring: Option<bool>,
+    /// Only tasks with `execute_at <= due_by`.
+    pub due_by: Option<i64>,
+}
+
+impl TaskFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn id(mut self, id: i64) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn entity(mut self, entity_id: EntityId) -> Self {
+        self.entity_id = Some(entity_id);
+        self
+    }
+
+    pub fn verb(mut self, verb: &str) -> Self {
+        self.verb = Some(verb.to_string());
+        self
+    }
+
+    pub fn dedupe_key(mut self, key: &str) -> Self {
+        self.dedupe_key = Some(key.to_string());
+        self
+    }
+
+    pub fn caller(mut self, caller_id: EntityId) -> Self {
+        self.caller_id = Some(caller_id);
+        self
+    }
+
+    pub fn recurring(mut self, recurring: bool) -> Self {
+        self.recurring = Some(recurring);
+        self
+    }
+
+    pub fn due_by(mut self, time: i64) -> Self {
+        self.due_by = Some(time);
+        self
+    }
+
+    /// Check a task against the filter.
+    pub fn matches(&self, task: &ScheduledTask) -> bool {
+        self.id.is_none_or(|id| task.id == id)
+            && self.entity_id.is_none_or(|id| task.entity_id == id)
+            && self.verb.as_ref().is_none_or(|verb| task.verb == *verb)
+            && self
+                .dedupe_key
+                .as_ref()
+                .is_none_or(|key| task.dedupe_key.as_ref() == Some(key))
+            && self.caller_id.is_none_or(|id| task.caller_id == Some(id))
+            && self
+                .recurring
+                .is_none_or(|recurring| task.recurrence.is_some() == recurring)
+            && self.due_by.is_none_or(|time| task.execute_at <= time)
+    }
+
+    /// Build a SQL `WHERE` clause and its params.
+    fn to_sql(&self) -> (String, Vec<SqlValue>) {
+        let mut conditions = Vec::new();
+        let mut params: Vec<SqlValue> = Vec::new();
+        let mut condition = |sql: &str, value: SqlValue| {
+            params.push(value);
+            conditions.push(format!("{} ?{}", sql, params.len()));
+        };
+        if let Some(id) = self.id {
+            condition("id =", id.into());
+        }
+        if let Some(entity_id) = self.entity_id {
+            condition("entity_id =", entity_id.into());
+        }
+        if let Some(verb) = &self.verb {
+            condition("verb =", verb.clone().into());
+        }
+        if let Some(key) = &self.dedupe_key {
+            condition("dedupe_key =", key.clone().into());
+        }
+        if let Some(caller_id) = self.caller_id {
+            condition("caller_id =", caller_id.into());
+        }
+        if let Some(time) = self.due_by {
+            condition("execute_at <=", time.into());
+        }
+        match self.recurring {
+            Some(true) => conditions.push("(interval_ms IS NOT NULL OR cron IS NOT NULL)".into()),
+            Some(false) => conditions.push("(interval_ms IS NULL AND cron IS NULL)".into()),
+            None => {}
+        }
+        if conditions.is_empty() {
+            (String::new(), params)
+        } else {
+            (format!(" WHERE {}", conditions.join(" AND ")), params)
+        }
+    }
+}
+
+const TASK_COLUMNS: &str = "id, entity_id, verb, args, execute_at, interval_ms, cron, dedupe_key, \
+                            attempts, leased_until, last_error, caller_id, capability_id, \
+                            priority, metadata";
+
+/// `ORDER BY` for [`ScheduledTask::run_order`].
+const TASK_ORDER: &str = "execute_at, priority DESC, id";
+
+impl WorldStorage {
+    /// Schedule a task for future execution.
+    pub async fn schedule_task(
+        &self,
+        entity_id: EntityId,
+        verb: &str,
+        args: serde_json::Value,
+        execute_at: i64,
+    ) -> Result<i64, StorageError> {
+        self.schedule_task_with(entity_id, verb, args, execute_at, TaskOptions::default())
+            .await
+    }
+
+    /// Schedule a task that first runs at `execute_at` and then recurs.
+    pub async fn schedule_recurring_task(
+        &self,
+        entity_id: EntityId,
+        verb: &str,
+        args: serde_json::Value,
+        execute_at: i64,
+        recurrence: &Recurrence,
+    ) -> Result<i64, StorageError> {
+        let options = TaskOptions::new().recurring(recurrence.clone());
+        self.schedule_task_with(entity_id, verb, args, execute_at, options)
+            .await
+    }
+
+    /// Schedule a task with extra options. With a dedupe key, an existing
+    /// task on the entity with the same key is replaced and its id returned.
+    pub async fn schedule_task_with(
+        &self,
+        entity_id: EntityId,
+        verb: &str,
+        args: serde_json::Value,
+        execute_at: i64,
+        options: TaskOptions,
+    ) -> Result<i64, StorageError> {
+        let args_str = serde_json::to_string(&args)?;
+        let metadata_str = options
+            .metadata
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+        let (interval_ms, cron) = match &options.recurrence {
+            None => (None, None),
+            Some(Recurrence::Every { interval_ms }) => (
+                Some(checked_interval(*interval_ms).map_err(StorageError::InvalidSchedule)?),
+                None,
+            ),
+            Some(Recurrence::Cron(schedule)) => (None, Some(schedule.as_str().to_string())),
+        };
+        let mut rows = self
+            .conn
+            .query(
+                "INSERT INTO scheduled_tasks
+                 (entity_id, verb, args, execute_at, interval_ms, cron, dedupe_key,
+                  caller_id, capability_id, priority, metadata)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+                 ON CONFLICT(entity_id, dedupe_key) WHERE dedupe_key IS NOT NULL DO UPDATE SET
+                     verb = excluded.verb,
+                     args = excluded.args,
+                     execute_at = excluded.execute_at,
+                     interval_ms = excluded.interval_ms,
+                     cron = excluded.cron,
+                     caller_id = excluded.caller_id,
+                     capability_id = excluded.capability_id,
+                     priority = excluded.priority,
+                     metadata = excluded.metadata,
+                     attempts = 0,
+                     replaced = scheduled_tasks.leased_until IS NOT NULL,
+                     last_error = NULL
+                 RETURNING id",
+                params![
+                    entity_id,
+                    verb,
+                    args_str,
+                    execute_at,
+                    interval_ms,
+                    cron,
+                    options.dedupe_key,
+                    options.caller_id,
+                    options.capability_id,
+                    options.priority,
+                    metadata_str
+                ],
+            )
+            .await?;
+        let id: i64 = match rows.next().await? {
+            Some(row) => row.get(0)?,
+            None => {
+                return Err(StorageError::Constraint(
+                    "scheduling a task returned no id".to_string(),
+                ));
+            }
+        };
+        self.emit(ChangeEvent::TaskScheduled {
+            id,
+            entity_id,
+            verb: verb.to_string(),
+            execute_at,
+        });
+        Ok(id)
+    }
+
+    /// Get all tasks that are due (execute_at <= now).
+    pub async fn get_due_tasks(&self, now: i64) -> Result<Vec<ScheduledTask>, StorageError> {
+        self.list_tasks(&TaskFilter::new().due_by(now)).await
+    }
+
+    /// Lease up to `limit` unleased tasks due at `now` until `lease_until`,
+    /// counting an attempt for each, and return them oldest first.
+    ///
+    /// Tasks whose lease has run out count as unleased, so work claimed by
+    /// a scheduler that died is picked up again. A claimed task whose row
+    /// can't be read, such as one with a corrupt schedule, is dead-lettered
+    /// instead of returned.
+    pub async fn claim_due_tasks(
+        &self,
+        now: i64,
+        lease_until: i64,
+        limit: usize,
+    ) -> Result<Vec<ScheduledTask>, StorageError> {
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let mut rows = self
+            .conn
+            .query(
+                &format!(
+                    "UPDATE scheduled_tasks
+                     SET leased_until = ?2, attempts = attempts + 1, replaced = 0
+                     WHERE id IN (
+                         SELECT id FROM scheduled_tasks
+                         WHERE execute_at <= ?1 AND (leased_until IS NULL OR leased_until <= ?1)
+                         ORDER BY {}
+                         LIMIT ?3
+                     )
+                     RETURNING {}",
+                    TASK_ORDER, TASK_COLUMNS
+                ),
+                params![now, lease_until, limit],
+            )
+            .await?;
+        let mut tasks = Vec::new();
+        let mut unreadable = Vec::new();
+        while let Some(row) = rows.next().await? {
+            match row_to_task(&row) {
+                Ok(task) => tasks.push(task),
+                Err(error) => unreadable.push((row.get::<i64>(0)?, error)),
+            }
+        }
+        // The batch is already leased, so failing the claim would strand
+        // it; a task that can't be read is dead-lettered instead
+        for (id, error) in unreadable {
+            tracing::warn!(task_id = id, %error, "dead-lettering unreadable task");
+            self.fail_task(id, lease_until, &error.to_string(), None, now)
+                .await?;
+        }
+        tasks.sort_by_key(ScheduledTask::run_order);
+        Ok(tasks)
+    }
+
+    /// Finish a claimed task that ran successfully: re-arm it for
+    /// `next_run` if given, otherwise delete it.
+    ///
+    /// `leased_until` is the lease the task was claimed with. Returns
+    /// `false`, recording nothing, if that lease is no longer held; a task
+    /// replaced while it ran is released to run its replacement instead.
+    pub async fn complete_task(
+        &self,
+        id: i64,
+        leased_until: i64,
+        next_run: Option<i64>,
+    ) -> Result<bool, StorageError> {
+        if self.release_replaced(id, leased_until).await? {
+            return Ok(false);
+        }
+        let settled = match next_run {
+            Some(next_run) => {
+                self.conn
+                    .execute(
+                        "UPDATE scheduled_tasks
+                         SET execute_at = ?3, attempts = 0, leased_until = NULL, last_error = NULL
+                         WHERE id = ?1 AND leased_until = ?2",
+                        params![id, leased_until, next_run],
+                    )
+                    .await?
+            }
+            None => {
+                self.conn
+                    .execute(
+                        "DELETE FROM scheduled_tasks WHERE id = ?1 AND leased_until = ?2",
+                        params![id, leased_until],
+                    )
+                    .await?
+            }
+        };
+        Ok(settled > 0)
+    }
+
+    /// Finish a claimed task whose verb failed with `error`: release it to
+    /// run again at `retry_at` if given, otherwise move it to the
+    /// dead-letter table as of `now`.
+    ///
+    /// Fenced on `leased_until` like [`complete_task`](Self::complete_task).
+    pub async fn fail_task(
+        &self,
+        id: i64,
+        leased_until: i64,
+        error: &str,
+        retry_at: Option<i64>,
+        now: i64,
+    ) -> Result<bool, StorageError> {
+        if self.release_replaced(id, leased_until).await? {
+            return Ok(false);
+        }
+        let settled = match retry_at {
+            Some(retry_at) => {
+                self.conn
+                    .execute(
+                        "UPDATE scheduled_tasks
+                         SET execute_at = ?3, leased_until = NULL, last_error = ?4
+                         WHERE id = ?1 AND leased_until = ?2",
+                        params![id, leased_until, retry_at, error],
+                    )
+                    .await?
+            }
+            None => {
+                // Copy and delete together, so the task is never both queued
+                // and dead-lettered. Args that aren't valid JSON (only
+                // possible in a corrupt row) are kept as a JSON string.
+                self.atomically(async || {
+                    let copied = self
+                        .conn
+                        .execute(
+                            "INSERT INTO dead_letter_tasks
+                             (task_id, entity_id, verb, args, attempts, last_error, failed_at)
+                             SELECT id, entity_id, verb,
+                                    CASE WHEN json_valid(args) THEN args ELSE json_quote(args) END,
+                                    attempts, ?3, ?4
+                             FROM scheduled_tasks WHERE id = ?1 AND leased_until = ?2",
+                            params![id, leased_until, error, now],
+                        )
+                        .await?;
+                    self.conn
+                        .execute(
+                            "DELETE FROM scheduled_tasks WHERE id = ?1 AND leased_until = ?2",
+                            params![id, leased_until],
+                        )
+                        .await?;
+                    Ok(copied)
+                })
+                .await?
+            }
+        };
+        Ok(settled > 0)
+    }
+
+    /// Give up the claim on a task without running it, dropping its lease
+    /// and the attempt the claim counted. Fenced on `leased_until` like
+    /// [`complete_task`](Self::complete_task).
+    pub async fn release_task(&self, id: i64, leased_until: i64) -> Result<bool, StorageError> {
+        let released = self
+            .conn
+            .execute(
+                "UPDATE scheduled_tasks
+                 SET leased_until = NULL, attempts = MAX(attempts - 1, 0), replaced = 0
+                 WHERE id = ?1 AND leased_until = ?2",
+                params![id, leased_until],
+            )
+            .await?;
+        Ok(released > 0)
+    }
+
+    /// If the task was replaced while claimed with `leased_until`, release
+    /// it so the replacement can run, and return `true`.
+    async fn release_replaced(&self, id: i64, leased_until: i64) -> Result<bool, StorageError> {
+        let released = self
+            .conn
+            .execute(
+                "UPDATE scheduled_tasks SET leased_until = NULL, replaced = 0
+                 WHERE id = ?1 AND leased_until = ?2 AND replaced = 1",
+                params![id, leased_until],
+            )
+            .await?;
+        Ok(released > 0)
+    }
+
+    /// Release every lease that ran out by `now`, returning how many there
+    /// were. The scheduler calls this on startup.
+    pub async fn reclaim_expired_leases(&self, now: i64) -> Result<u64, StorageError> {
+        let released = self
+            .conn
+            .execute(
+                "UPDATE scheduled_tasks SET leased_until = NULL, replaced = 0 WHERE leased_until <= ?1",
+                params![now],
+            )
+            .await?;
+        Ok(released)
+    }
+
+    /// Get every dead-lettered task, oldest failure first.
+    pub async fn get_dead_letter_tasks(&self) -> Result<Vec<DeadLetterTask>, StorageError> {
+        let mut rows = self
+            .conn
+            .query(
+                "SELECT id, task_id, entity_id, verb, args, attempts, last_error, failed_at
+                 FROM dead_letter_tasks ORDER BY failed_at, id",
+                (),
+            )
+            .await?;
+        let mut tasks = Vec::new();
+        while let Some(row) = rows.next().await? {
+            let args_str: String = row.get(4)?;
+            tasks.push(DeadLetterTask {
+                id: row.get(0)?,
+                task_id: row.get(1)?,
+                entity_id: row.get(2)?,
+                verb: row.get(3)?,
+                args: serde_json::from_str(&args_str)?,
+                attempts: row.get::<u32>(5)?,
+                last_error: row.get(6)?,
+                failed_at: row.get(7)?,
+            });
+        }
+        Ok(tasks)
+    }
+
+    /// List tasks matching `filter`, soonest first.
+    pub async fn list_tasks(
+        &self,
+        filter: &TaskFilter,
+    ) -> Result<Vec<ScheduledTask>, StorageError> {
+        let (where_clause, params) = filter.to_sql();
+        let mut rows = self
+            .conn
+            .query(
+                &format!(
+                    "SELECT {} FROM scheduled_tasks{} ORDER BY {}",
+                    TASK_COLUMNS, where_clause, TASK_ORDER
+                ),
+                params,
+            )
+            .await?;
+        let mut tasks = Vec::new();
+        while let Some(row) = rows.next().await? {
+            tasks.push(row_to_task(&row)?);
+        }
+        Ok(tasks)
+    }
+
+    /// Move a task to a new execution time. Returns whether the task
+    /// exists.
+    ///
+    /// A task moved while it runs is marked replaced, like a dedupe
+    /// replacement, so the run's outcome doesn't undo the move.
+    pub async fn reschedule_task(&self, id: i64, execute_at: i64) -> Result<bool, StorageError> {
+        let changed = self
+            .conn
+            .execute(
+                "UPDATE scheduled_tasks
+                 SET execute_at = ?1, replaced = leased_until IS NOT NULL
+                 WHERE id = ?2",
+                params![execute_at, id],
+            )
+            .await?;
+        Ok(changed > 0)
+    }
+
+    /// Delete a scheduled task.
+    pub async fn delete_task(&self, id: i64) -> Result<(), StorageError> {
+        self.conn
+            .execute("DELETE FROM scheduled_tasks WHERE id = ?1", params![id])
+            .await?;
+        Ok(())
+    }
+
+    /// Delete every task matching `filter`, returning how many went.
+    pub async fn delete_tasks(&self, filter: &TaskFilter) -> Result<u64, StorageError> {
+        let (where_clause, params) = filter.to_sql();
+        let deleted = self
+            .conn
+            .execute(
+                &format!("DELETE FROM scheduled_tasks{}", where_clause),
+                params,
+            )
+            .await?;
+        Ok(deleted)
+    }
+}
+
+/// Read a task from a row of [`TASK_COLUMNS`].
+fn row_to_task(row: &libsql::Row) -> Result<ScheduledTask, StorageError> {
+    let args_str: String = row.get(3)?;
+    let metadata_str: Option<String> = row.get(14)?;
+    let interval_ms: Option<i64> = row.get(5)?;
+    let cron: Option<String> = row.get(6)?;
+    let recurrence = match (interval_ms, cron) {
+        (Some(stored), _) => {
+            let interval_ms = u64::try_from(stored)
+                .ok()
+                .filter(|&ms| ms > 0)
+                .ok_or_else(|| {
+                    StorageError::InvalidSchedule(format!(
+                        "stored interval of {} ms is not positive",
+                        stored
+                    ))
+                })?;
+            Some(Recurrence::Every { interval_ms })
+        }
+        (None, Some(expr)) => Some(Recurrence::Cron(CronSchedule::parse(&expr).map_err(
+            |error| StorageError::InvalidSchedule(format!("stored cron {:?}: {}", expr, error)),
+        )?)),
+        (None, None) => None,
+    };
+    Ok(ScheduledTask {
+        id: row.get(0)?,
+        entity_id: row.get(1)?,
+        verb: row.get(2)?,
+        args: serde_json::from_str(&args_str)?,
+        execute_at: row.get(4)?,
+        recurrence,
+        dedupe_key: row.get(7)?,
+        attempts: row.get::<u32>(8)?,
+        leased_until: row.get(9)?,
+        last_error: row.get(10)?,
+        caller_id: row.get(11)?,
+        capability_id: row.get(12)?,
+        priority: row.get(13)?,
+        metadata: metadata_str
+            .map(|metadata| serde_json::from_str(&metadata))
+            .transpose()?,
+    })
+}
@@ -0,0 +1,108 @@
+//! Exporting capabilities as signed tokens, and importing them.
+//!
+//! A token is a bearer credential: whoever holds it can use it. Minting one
+//! is therefore treated as a delegation, and only possible for capabilities
+//! of a delegable type. Tokens always expire.
+//!
+//! Any server holding the key checks a token with
+//! [`CapabilityToken::verify`], which needs no database. The issuing server
+//! can additionally reject tokens whose capability has since been revoked
+//! or transferred with [`verify_issued_token`], and a receiving server can
+//! keep what a token grants as a capability of its own with
+//! [`import_token`].
+//!
+//! [`verify_issued_token`]: WorldStorage::verify_issued_token
+//! [`import_token`]: WorldStorage::import_token
+
+use super::delegation::check_delegation;
+use super::{StorageError, WorldStorage};
+use crate::capability::{Capability, CapabilityToken, TokenKey};
+use crate::entity::EntityId;
+
+impl WorldStorage {
+    /// Sign the capability `capability_id` with `key`, valid until
+    /// `expires_at` (ms since epoch) or the capability's own expiry,
+    /// whichever is sooner.
+    ///
+    /// Fails with [`StorageError::InvalidDelegation`] if the capability is
+    /// missing, not currently valid, or of a non-delegable type.
+    pub async fn mint_token(
+        &self,
+        capability_id: &str,
+        key: &TokenKey,
+        expires_at: i64,
+    ) -> Result<CapabilityToken, StorageError> {
+        let capability = self.get_capability(capability_id).await?;
+        let params = capability
+            .as_ref()
+            .map_or(serde_json::Value::Null, |capability| {
+                capability.params.clone()
+            });
+        let mut capability = check_delegation(
+            capability,
+            capability_id,
+            &params,
+            &self.capability_types,
+            self.clock.as_ref(),
+        )?;
+        capability.expires_at = Some(
+            capability
+                .expires_at
+                .map_or(expires_at, |own| own.min(expires_at)),
+        );
+        Ok(CapabilityToken::mint(&capability, key))
+    }
+
+    /// Check a token this server minted against `key` and return the
+    /// capability it grants.
+    ///
+    /// Unlike [`CapabilityToken::verify`], this also looks up the
+    /// capability the token was minted from, so only the issuing server
+    /// can use it. Fails with [`StorageError::InvalidCapability`] if that
+    /// capability no longer exists or has changed owner.
+    pub async fn verify_issued_token(
+        &self,
+        token: &CapabilityToken,
+        key: &TokenKey,
+    ) -> Result<Capability, StorageError> {
+        let capability = token.verify(key)?;
+        match self.get_capability(&capability.id).await? {
+            Some(row) if row.owner_id == capability.owner_id => Ok(capability),
+            _ => Err(StorageError::InvalidCapability(format!(
+                "capability {} has been revoked",
+                capability.id
+            ))),
+        }
+    }
+
+    /// Check a token minted elsewhere against `key` and store what it
+    /// grants as a new capability owned by the local entity `owner_id`,
+    /// with the token's validity window. Returns the new capability's ID.
+    ///
+    /// The imported capability is independent of the one the token was
+    /// minted from: revoking that on the issuing server does not reach it.
+    /// Fails with [`StorageError::InvalidCapability`] if the token has
+    /// expired or its type or params are not valid here.
+    pub async fn import_token(
+        &self,
+        token: &CapabilityToken,
+        key: &TokenKey,
+        owner_id: EntityId,
+    ) -> Result<String, StorageError> {
+        let capability = token.verify(key)?;
+        if capability.is_expired_at(self.clock.now_ms()) {
+            return Err(StorageError::InvalidCapability(format!(
+                "token for capability {} has expired",
+                capability.id
+            )));
+        }
+        self.create_capability_with_validity(
+            owner_id,
+            &capability.cap_type,
+            capability.params,
+            capability.not_before,
+            capability.expires_at,
+        )
+        .await
+    }
+}
@@ -0,0 +1,215 @@
+//! Transactions.
+//!
+//! The outermost transaction is a SQLite `BEGIN IMMEDIATE`; transactions
+//! opened inside it are savepoints, each with its own name, so an inner
+//! one can be rolled back without losing the outer one's work.
+//!
+//! [`WorldStorage::transaction`] returns a [`Transaction`] guard that
+//! rolls back when dropped without being committed, including when a `?`
+//! returns early or the task panics. The guard derefs to the storage, so
+//! every storage operation is available on it.
+
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use super::{StorageError, WorldStorage};
+
+/// An open transaction or savepoint, rolled back on drop unless committed.
+pub struct Transaction<'a> {
+    storage: &'a mut WorldStorage,
+    /// `transaction_depth` while this is the innermost transaction.
+    depth: usize,
+    committed: bool,
+}
+
+impl Transaction<'_> {
+    /// Commit the transaction, or release the savepoint if nested. If
+    /// that fails the transaction is rolled back.
+    pub async fn commit(mut self) -> Result<(), StorageError> {
+        self.storage.commit().await?;
+        self.committed = true;
+        Ok(())
+    }
+
+    /// Roll back now rather than on drop, to see whether it succeeded.
+    pub async fn rollback(mut self) -> Result<(), StorageError> {
+        self.committed = true;
+        self.storage.rollback().await
+    }
+}
+
+impl Deref for Transaction<'_> {
+    type Target = WorldStorage;
+
+    fn deref(&self) -> &WorldStorage {
+        self.storage
+    }
+}
+
+impl DerefMut for Transaction<'_> {
+    fn deref_mut(&mut self) -> &mut WorldStorage {
+        self.storage
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        // Already closed by hand through the storage
+        if self.committed || self.storage.transaction_depth != self.depth {
+            return;
+        }
+        if let Err(error) = block_on(self.storage.rollback()) {
+            tracing::error!(%error, "failed to roll back dropped transaction");
+        }
+    }
+}
+
+/// Drive `future` to completion on the current thread.
+///
+/// `Drop` can't await, and leaving the rollback for later would let other
+/// writes land in the abandoned transaction. Storage only opens local
+/// databases, whose statements libSQL runs synchronously, so this finishes
+/// on the first poll rather than parking the thread.
+fn block_on<F: Future>(future: F) -> F::Output {
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+impl WorldStorage {
+    /// Open a transaction, or a savepoint inside the current one.
+    ///
+    /// The returned guard must be [committed](Transaction::commit); dropping
+    /// it rolls back. Call `transaction` on the guard to nest a savepoint.
+    pub async fn transaction(&mut self) -> Result<Transaction<'_>, StorageError> {
+        self.begin_transaction().await?;
+        Ok(Transaction {
+            depth: self.transaction_depth,
+            storage: self,
+            committed: false,
+        })
+    }
+
+    /// Begin a transaction. Uses SAVEPOINT for nested transactions.
+    ///
+    /// Returns the transaction depth (0 for outer transaction). Prefer
+    /// [`transaction`](Self::transaction), which can't be left open.
+    pub async fn begin_transaction(&mut self) -> Result<usize, StorageError> {
+        let depth = self.transaction_depth;
+        if depth == 0 {
+            self.conn.execute("BEGIN IMMEDIATE", ()).await?;
+            self.transaction_id = Some(uuid::Uuid::new_v4().to_string());
+        } else {
+            self.savepoint_seq += 1;
+            let name = format!("sp_{}", self.savepoint_seq);
+            self.conn
+                .execute(&format!("SAVEPOINT {}", name), ())
+                .await?;
+            self.savepoints.push(name);
+        }
+        self.transaction_depth += 1;
+        self.changes.begin();
+        Ok(depth)
+    }
+
+    /// Commit the current transaction.
+    ///
+    /// For nested transactions, releases the savepoint. On failure the
+    /// transaction stays open, so it can still be rolled back.
+    pub async fn commit(&mut self) -> Result<(), StorageError> {
+        match self.transaction_depth {
+            0 => {
+                return Err(StorageError::Transaction(
+                    "no active transaction".to_string(),
+                ));
+            }
+            1 => {
+                if let Err(error) = self.conn.execute("COMMIT", ()).await {
+                    self.sync_with_connection();
+                    return Err(error.into());
+                }
+                self.transaction_id = None;
+            }
+            _ => {
+                let name = self.savepoints.last().expect("savepoint for nested depth");
+                self.conn
+                    .execute(&format!("RELEASE SAVEPOINT {}", name), ())
+                    .await?;
+                self.savepoints.pop();
+            }
+        }
+        self.transaction_depth -= 1;
+        self.changes.commit();
+        Ok(())
+    }
+
+    /// Rollback the current transaction.
+    ///
+    /// For nested transactions, rolls back to the savepoint and releases it.
+    pub async fn rollback(&mut self) -> Result<(), StorageError> {
+        match self.transaction_depth {
+            0 => {
+                return Err(StorageError::Transaction(
+                    "no active transaction".to_string(),
+                ));
+            }
+            1 => {
+                if let Err(error) = self.conn.execute("ROLLBACK", ()).await {
+                    self.sync_with_connection();
+                    return Err(error.into());
+                }
+                self.transaction_id = None;
+            }
+            _ => {
+                let name = self.savepoints.last().expect("savepoint for nested depth");
+                // Rolling back to a savepoint leaves it open
+                self.conn
+                    .execute(&format!("ROLLBACK TO SAVEPOINT {}", name), ())
+                    .await?;
+                self.conn
+                    .execute(&format!("RELEASE SAVEPOINT {}", name), ())
+                    .await?;
+                self.savepoints.pop();
+            }
+        }
+        self.transaction_depth -= 1;
+        self.changes.rollback();
+        Ok(())
+    }
+
+    /// Check if currently in a transaction.
+    pub fn in_transaction(&self) -> bool {
+        self.transaction_depth > 0
+    }
+
+    /// After a failed `COMMIT` or `ROLLBACK`, forget the transaction if
+    /// SQLite has already ended it.
+    fn sync_with_connection(&mut self) {
+        if self.conn.is_autocommit() {
+            while self.transaction_depth > 0 {
+                self.transaction_depth -= 1;
+                self.changes.rollback();
+            }
+            self.savepoints.clear();
+            self.transaction_id = None;
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Storage backend abstraction.
+//!
+//! [`WorldStore`] covers the entity, verb, capability and scheduled-task
+//! operations that consumers of lotus-core rely on. [`WorldStorage`] is the
+//! libSQL-backed implementation; [`MemoryStore`] keeps everything in
+//! process and is intended for tests and embedders without a database.
+//!
+//! [`WorldStorage`]: crate::WorldStorage
+
+use std::future::Future;
+
+use crate::capability::Capability;
+use crate::entity::{Entity, EntityId, Verb};
+use crate::scheduler::Recurrence;
+use crate::storage::{DeadLetterTask, ScheduledTask, StorageError, TaskFilter, TaskOptions};
+
+mod memory;
+
+pub use memory::MemoryStore;
+
+/// Operations every world storage backend must provide.
+///
+/// Method semantics mirror the inherent methods on [`WorldStorage`], so code
+/// written against one backend behaves the same on another. What the trait
+/// doesn't cover has no counterpart in [`MemoryStore`]: it keeps no entity
+/// revisions, capability audit log, relations or property indexes, publishes
+/// no change events and has no transactions.
+///
+/// [`WorldStorage`]: crate::WorldStorage
+pub trait WorldStore: Send + Sync {
+    // =========================================================================
+    // Entities
+    // =========================================================================
+
+    /// Create a new entity.
+    fn create_entity(
+        &self,
+        props: serde_json::Value,
+        prototype_id: Option<EntityId>,
+    ) -> impl Future<Output = Result<EntityId, StorageError>> + Send;
+
+    /// Get an entity by ID (raw, without prototype resolution).
+    fn get_entity_raw(
+        &self,
+        id: EntityId,
+    ) -> impl Future<Output = Result<Option<Entity>, StorageError>> + Send;
+
+    /// Get an entity with resolved prototype chain properties.
+    fn get_entity(
+        &self,
+        id: EntityId,
+    ) -> impl Future<Output = Result<Option<Entity>, StorageError>> + Send;
+
+    /// Merge properties into an entity's own props.
+    fn update_entity(
+        &self,
+        id: EntityId,
+        props: serde_json::Value,
+    ) -> impl Future<Output = Result<(), StorageError>> + Send;
+
+    /// Set an entity's prototype, rejecting changes that would create a
+    /// prototype cycle.
+    fn set_prototype(
+        &self,
+        id: EntityId,
+        prototype_id: Option<EntityId>,
+    ) -> impl Future<Output = Result<(), StorageError>> + Send;
+
+    /// Delete an entity along with its verbs, capabilities (and everything
+    /// delegated from them), scheduled tasks and dead-lettered tasks.
+    ///
+    /// Fails with [`StorageError::PrototypeInUse`], deleting nothing, if
+    /// other entities still have it as their prototype.
+    fn delete_entity(&self, id: EntityId) -> impl Future<Output = Result<(), StorageError>> + Send;
+
+    // =========================================================================
+    // Verbs
+    // =========================================================================
+
+    /// Add a verb to an entity.
+    fn add_verb(
+        &self,
+        entity_id: EntityId,
+        name: &str,
+        code: &serde_json::Value,
+    ) -> impl Future<Output = Result<i64, StorageError>> + Send {
+        self.add_verb_with_cap(entity_id, name, code, None)
+    }
+
+    /// Add a verb to an entity with optional capability requirement.
+    fn add_verb_with_cap(
+        &self,
+        entity_id: EntityId,
+        name: &str,
+        code: &serde_json::Value,
+        required_capability: Option<&str>,
+    ) -> impl Future<Output = Result<i64, StorageError>> + Send;
+
+    /// Get a verb by entity and name (resolves through prototype chain).
+    fn get_verb(
+        &self,
+        entity_id: EntityId,
+        name: &str,
+    ) -> impl Future<Output = Result<Option<Verb>, StorageError>> + Send;
+
+    /// Get all verbs for an entity (including inherited).
+    fn get_verbs(
+        &self,
+        entity_id: EntityId,
+    ) -> impl Future<Output = Result<Vec<Verb>, StorageError>> + Send;
+
+    /// Update a verb's code.
+    fn update_verb(
+        &self,
+        id: i64,
+        code: &serde_json::Value,
+    ) -> impl Future<Output = Result<(), StorageError>> + Send;
+
+    /// Delete a verb.
+    fn delete_verb(&self, id: i64) -> impl Future<Output = Result<(), StorageError>> + Send;
+
+    // =========================================================================
+    // Capabilities
+    // =========================================================================
+
+    /// Create a new capability, validated against the backend's capability
+    /// type registry.
+    fn create_capability(
+        &self,
+        owner_id: EntityId,
+        cap_type: &str,
+        params: serde_json::Value,
+    ) -> impl Future<Output = Result<String, StorageError>> + Send {
+        self.create_capability_with_validity(owner_id, cap_type, params, None, None)
+    }
+
+    /// Create a new capability that is only valid from `not_before` until
+    /// `expires_at` (ms since epoch, either bound optional).
+    fn create_capability_with_validity(
+        &self,
+        owner_id: EntityId,
+        cap_type: &str,
+        params: serde_json::Value,
+        not_before: Option<i64>,
+        expires_at: Option<i64>,
+    ) -> impl Future<Output = Result<String, StorageError>> + Send;
+
+    /// Derive a capability from `parent_id` for `new_owner_id`, restricted
+    /// to `restricted_params`.
+    fn delegate_capability(
+        &self,
+        parent_id: &str,
+        new_owner_id: EntityId,
+        restricted_params: serde_json::Value,
+    ) -> impl Future<Output = Result<String, StorageError>> + Send;
+
+    /// Get a capability by ID, even if it has expired.
+    fn get_capability(
+        &self,
+        id: &str,
+    ) -> impl Future<Output = Result<Option<Capability>, StorageError>> + Send;
+
+    /// Get all capabilities owned by an entity, excluding expired ones.
+    fn get_capabilities(
+        &self,
+        owner_id: EntityId,
+    ) -> impl Future<Output = Result<Vec<Capability>, StorageError>> + Send;
+
+    /// Update the owner of a capability.
+    ///
+    /// Fails with [`StorageError::CapabilityNotFound`] if there is no such
+    /// capability.
+    fn update_capability_owner(
+        &self,
+        id: &str,
+        new_owner_id: EntityId,
+    ) -> impl Future<Output = Result<(), StorageError>> + Send;
+
+    /// Delete a capability and every capability delegated from it.
+    fn delete_capability(&self, id: &str) -> impl Future<Output = Result<(), StorageError>> + Send;
+
+    // =========================================================================
+    // Scheduled Tasks
+    // =========================================================================
+
+    /// Schedule a task for future execution.
+    fn schedule_task(
+        &self,
+        entity_id: EntityId,
+        verb: &str,
+        args: serde_json::Value,
+        execute_at: i64,
+    ) -> impl Future<Output = Result<i64, StorageError>> + Send {
+        self.schedule_task_with(entity_id, verb, args, execute_at, TaskOptions::default())
+    }
+
+    /// Schedule a task that first runs at `execute_at` and then recurs.
+    fn schedule_recurring_task(
+        &self,
+        entity_id: EntityId,
+        verb: &str,
+        args: serde_json::Value,
+        execute_at: i64,
+        recurrence: &Recurrence,
+    ) -> impl Future<Output = Result<i64, StorageError>> + Send {
+        let options = TaskOptions::new().recurring(recurrence.clone());
+        self.schedule_task_with(entity_id, verb, args, execute_at, options)
+    }
+
+    /// Schedule a task with extra options. With a dedupe key, an existing
+    /// task on the entity with the same key is replaced and its id returned;
+    /// if that task is running, the replacement waits for it to settle.
+    fn schedule_task_with(
+        &self,
+        entity_id: EntityId,
+        verb: &str,
+        args: serde_json::Value,
+        execute_at: i64,
+        options: TaskOptions,
+    ) -> impl Future<Output = Result<i64, StorageError>> + Send;
+
+    /// Get all tasks that are due (execute_at <= now), oldest first.
+    fn get_due_tasks(
+        &self,
+        now: i64,
+    ) -> impl Future<Output = Result<Vec<ScheduledTask>, StorageError>> + Send;
+
+    /// List tasks matching `filter`, soonest first.
+    fn list_tasks(
+        &self,
+        filter: &TaskFilter,
+    ) -> impl Future<Output = Result<Vec<ScheduledTask>, StorageError>> + Send;
+
+    /// Move a task to a new execution time. Returns whether the task
+    /// exists.
+    ///
+    /// A task moved while it runs keeps its lease, and the move wins over
+    /// the run's outcome: the task is released to run again at
+    /// `execute_at` rather than deleted or re-armed.
+    fn reschedule_task(
+        &self,
+        id: i64,
+        execute_at: i64,
+    ) -> impl Future<Output = Result<bool, StorageError>> + Send;
+
+    /// Delete a scheduled task.
+    fn delete_task(&self, id: i64) -> impl Future<Output = Result<(), StorageError>> + Send;
+
+    /// Delete every task matching `filter`, returning how many went.
+    fn delete_tasks(
+        &self,
+        filter: &TaskFilter,
+    ) -> impl Future<Output = Result<u64, StorageError>> + Send;
+
+    /// Lease up to `limit` unleased (or lease-expired) tasks due at `now`
+    /// until `lease_until`, counting an attempt for each, and return them
+    /// oldest first.
+    fn claim_due_tasks(
+        &self,
+        now: i64,
+        lease_until: i64,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<ScheduledTask>, StorageError>> + Send;
+
+    /// Finish a claimed task that ran successfully: re-arm it for
+    /// `next_run` if given, otherwise delete it.
+    ///
+    /// `leased_until` is the lease the task was claimed with. Returns
+    /// `false`, recording nothing, if that lease is no longer held; a task
+    /// replaced while it ran is released to run its replacement instead.
+    fn complete_task(
+        &self,
+        id: i64,
+        leased_until: i64,
+        next_run: Option<i64>,
+    ) -> impl Future<Output = Result<bool, StorageError>> + Send;
+
+    /// Finish a claimed task whose verb failed: release it to run again at
+    /// `retry_at` if given, otherwise move it to the dead-letter queue.
+    /// Fenced on `leased_until` like [`complete_task`](Self::complete_task).
+    fn fail_task(
+        &self,
+        id: i64,
+        leased_until: i64,
+        error: &str,
+        retry_at: Option<i64>,
+        now: i64,
+    ) -> impl Future<Output = Result<bool, StorageError>> + Send;
+
+    /// Give up the claim on a task without running it, dropping its lease
+    /// and the attempt the claim counted. Fenced on `leased_until` like
+    /// [`complete_task`](Self::complete_task).
+    fn release_task(
+        &self,
+        id: i64,
+        leased_until: i64,
+    ) -> impl Future<Output = Result<bool, StorageError>> + Send;
+
+    /// Release every lease that ran out by `now`, returning how many.
+    fn reclaim_expired_leases(
+        &self,
+        now: i64,
+    ) -> impl Future<Output = Result<u64, StorageError>> + Send;
+
+    /// Get every dead-lettered task, oldest failure first.
+    fn get_dead_letter_tasks(
+        &self,
+    ) -> impl Future<Output = Result<Vec<DeadLetterTask>, StorageError>> + Send;
+}
+
+#[cfg(test)]
+mod tests;
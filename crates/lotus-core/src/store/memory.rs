@@ -0,0 +1,669 @@
+//! In-process storage backend.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::WorldStore;
+use crate::capability::{Capability, CapabilityRegistry, CapabilityType};
+use crate::clock::{Clock, SystemClock};
+use crate::entity::{Entity, EntityId, Verb};
+use crate::storage::delegation::check_delegation;
+use crate::storage::prototype::check_chain;
+use crate::storage::{
+    DEFAULT_MAX_PROTOTYPE_DEPTH, DeadLetterTask, ScheduledTask, StorageError, TaskFilter,
+    TaskOptions,
+};
+
+/// World storage kept entirely in memory behind `HashMap`s.
+///
+/// Nothing is persisted; dropping the store discards the world. IDs are
+/// allocated sequentially starting at 1, matching the libSQL backend.
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+    /// Time source for capability validity checks.
+    clock: Arc<dyn Clock>,
+    capability_types: CapabilityRegistry,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self {
+            state: Mutex::default(),
+            clock: Arc::new(SystemClock),
+            capability_types: CapabilityRegistry::default(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct MemoryState {
+    entities: HashMap<EntityId, Entity>,
+    verbs: HashMap<i64, Verb>,
+    capabilities: HashMap<String, Capability>,
+    tasks: HashMap<i64, ScheduledTask>,
+    /// Tasks replaced while leased, released instead of settled when the
+    /// run they were leased for finishes.
+    replaced: HashSet<i64>,
+    dead_letters: Vec<DeadLetterTask>,
+    last_entity_id: EntityId,
+    last_verb_id: i64,
+    last_task_id: i64,
+    last_dead_letter_id: i64,
+}
+
+impl MemoryStore {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the clock used to decide whether capabilities have expired.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// The clock used to decide whether capabilities have expired.
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Register a capability type so capabilities of it can be created.
+    pub fn register_capability_type(
+        &mut self,
+        capability_type: CapabilityType,
+    ) -> Result<(), StorageError> {
+        self.capability_types.register(capability_type)
+    }
+
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        // A panic while holding the lock cannot leave the maps half-updated
+        // in a way later readers care about, so recover from poisoning.
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl MemoryState {
+    /// Whether task `id` is still leased until `leased_until`, so the run
+    /// it was claimed for may settle it. A task replaced during that run is
+    /// released instead.
+    fn holds_lease(&mut self, id: i64, leased_until: i64) -> bool {
+        let Some(task) = self.tasks.get_mut(&id) else {
+            return false;
+        };
+        if task.leased_until != Some(leased_until) {
+            return false;
+        }
+        if self.replaced.remove(&id) {
+            task.leased_until = None;
+            return false;
+        }
+        true
+    }
+
+    /// Remove the capabilities matching `root` and everything delegated
+    /// from them, however deep.
+    fn revoke_capability_trees(&mut self, root: impl Fn(&Capability) -> bool) {
+        let mut revoked: HashSet<String> = self
+            .capabilities
+            .values()
+            .filter(|cap| root(cap))
+            .map(|cap| cap.id.clone())
+            .collect();
+        loop {
+            let children: Vec<String> = self
+                .capabilities
+                .values()
+                .filter(|cap| {
+                    !revoked.contains(&cap.id)
+                        && cap
+                            .parent_id
+                            .as_ref()
+                            .is_some_and(|parent_id| revoked.contains(parent_id))
+                })
+                .map(|cap| cap.id.clone())
+                .collect();
+            if children.is_empty() {
+                break;
+            }
+            revoked.extend(children);
+        }
+        self.capabilities.retain(|id, _| !revoked.contains(id));
+    }
+
+    /// Entity IDs from `id` up through its prototypes, instance first.
+    fn lineage(&self, id: EntityId) -> Vec<EntityId> {
+        let mut chain = Vec::new();
+        let mut seen = HashSet::new();
+        let mut current = Some(id);
+        while let Some(id) = current {
+            let Some(entity) = self.entities.get(&id) else {
+                break;
+            };
+            if !seen.insert(id) || chain.len() > DEFAULT_MAX_PROTOTYPE_DEPTH {
+                break;
+            }
+            chain.push(id);
+            current = entity.prototype_id;
+        }
+        chain
+    }
+
+    fn check_prototype(
+        &self,
+        id: Option<EntityId>,
+        prototype_id: Option<EntityId>,
+    ) -> Result<(), StorageError> {
+        match prototype_id {
+            Some(prototype_id) => {
+                check_chain(id, prototype_id, DEFAULT_MAX_PROTOTYPE_DEPTH, |ancestor| {
+                    self.entities
+                        .get(&ancestor)
+                        .and_then(|entity| entity.prototype_id)
+                })
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl WorldStore for MemoryStore {
+    async fn create_entity(
+        &self,
+        props: serde_json::Value,
+        prototype_id: Option<EntityId>,
+    ) -> Result<EntityId, StorageError> {
+        let mut state = self.state();
+        state.check_prototype(None, prototype_id)?;
+        state.last_entity_id += 1;
+        let id = state.last_entity_id;
+        state.entities.insert(
+            id,
+            Entity {
+                id,
+                prototype_id,
+                props,
+            },
+        );
+        Ok(id)
+    }
+
+    async fn get_entity_raw(&self, id: EntityId) -> Result<Option<Entity>, StorageError> {
+        Ok(self.state().entities.get(&id).cloned())
+    }
+
+    async fn get_entity(&self, id: EntityId) -> Result<Option<Entity>, StorageError> {
+        let state = self.state();
+        let Some(instance) = state.entities.get(&id) else {
+            return Ok(None);
+        };
+
+        // Merge properties from root (oldest prototype) to leaf (instance)
+        let mut merged_props = serde_json::Map::new();
+        for ancestor in state.lineage(id).iter().rev() {
+            if let serde_json::Value::Object(obj) = &state.entities[ancestor].props {
+                for (key, value) in obj {
+                    merged_props.insert(key.clone(), value.clone());
+                }
+            }
+        }
+
+        Ok(Some(Entity {
+            id,
+            prototype_id: instance.prototype_id,
+            props: serde_json::Value::Object(merged_props),
+        }))
+    }
+
+    async fn update_entity(
+        &self,
+        id: EntityId,
+        props: serde_json::Value,
+    ) -> Result<(), StorageError> {
+        let mut state = self.state();
+        let entity = state
+            .entities
+            .get_mut(&id)
+            .ok_or(StorageError::EntityNotFound(id))?;
+
+        if !entity.props.is_object() {
+            entity.props = serde_json::Value::Object(serde_json::Map::new());
+        }
+        if let (serde_json::Value::Object(merged), serde_json::Value::Object(updates)) =
+            (&mut entity.props, props)
+        {
+            for (key, value) in updates {
+                merged.insert(key, value);
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_prototype(
+        &self,
+        id: EntityId,
+        prototype_id: Option<EntityId>,
+    ) -> Result<(), StorageError> {
+        let mut state = self.state();
+        state.check_prototype(Some(id), prototype_id)?;
+        if let Some(entity) = state.entities.get_mut(&id) {
+            entity.prototype_id = prototype_id;
+        }
+        Ok(())
+    }
+
+    async fn delete_entity(&self, id: EntityId) -> Result<(), StorageError> {
+        let mut state = self.state();
+        if state
+            .entities
+            .values()
+            .any(|entity| entity.prototype_id == Some(id))
+        {
+            return Err(StorageError::PrototypeInUse(id));
+        }
+        state.verbs.retain(|_, verb| verb.entity_id != id);
+        state.revoke_capability_trees(|cap| cap.owner_id == id);
+        state.tasks.retain(|_, task| task.entity_id != id);
+        state.dead_letters.retain(|task| task.entity_id != id);
+        state.entities.remove(&id);
+        Ok(())
+    }
+
+    async fn add_verb_with_cap(
+        &self,
+        entity_id: EntityId,
+        name: &str,
+        code: &serde_json::Value,
+        required_capability: Option<&str>,
+    ) -> Result<i64, StorageError> {
+        let mut state = self.state();
+        if state
+            .verbs
+            .values()
+            .any(|verb| verb.entity_id == entity_id && verb.name == name)
+        {
+            return Err(StorageError::Constraint(format!(
+                "verb '{}' already exists on entity {}",
+                name, entity_id
+            )));
+        }
+        state.last_verb_id += 1;
+        let id = state.last_verb_id;
+        state.verbs.insert(
+            id,
+            Verb {
+                id,
+                entity_id,
+                name: name.to_string(),
+                code: code.clone(),
+                required_capability: required_capability.map(str::to_string),
+                required_params: None,
+            },
+        );
+        Ok(id)
+    }
+
+    async fn get_verb(
+        &self,
+        entity_id: EntityId,
+        name: &str,
+    ) -> Result<Option<Verb>, StorageError> {
+        let state = self.state();
+        for ancestor in state.lineage(entity_id) {
+            if let Some(verb) = state
+                .verbs
+                .values()
+                .find(|verb| verb.entity_id == ancestor && verb.name == name)
+            {
+                return Ok(Some(verb.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn get_verbs(&self, entity_id: EntityId) -> Result<Vec<Verb>, StorageError> {
+        let state = self.state();
+
+        // Walk from the root prototype down so child verbs override parent verbs
+        let mut verb_map = HashMap::new();
+        for ancestor in state.lineage(entity_id).iter().rev() {
+            for verb in state
+                .verbs
+                .values()
+                .filter(|verb| verb.entity_id == *ancestor)
+            {
+                verb_map.insert(verb.name.clone(), verb.clone());
+            }
+        }
+
+        Ok(verb_map.into_values().collect())
+    }
+
+    async fn update_verb(&self, id: i64, code: &serde_json::Value) -> Result<(), StorageError> {
+        if let Some(verb) = self.state().verbs.get_mut(&id) {
+            verb.code = code.clone();
+        }
+        Ok(())
+    }
+
+    async fn delete_verb(&self, id: i64) -> Result<(), StorageError> {
+        self.state().verbs.remove(&id);
+        Ok(())
+    }
+
+    async fn create_capability_with_validity(
+        &self,
+        owner_id: EntityId,
+        cap_type: &str,
+        params: serde_json::Value,
+        not_before: Option<i64>,
+        expires_at: Option<i64>,
+    ) -> Result<String, StorageError> {
+        self.capability_types.validate(cap_type, &params)?;
+        let id = uuid::Uuid::new_v4().to_string();
+        self.state().capabilities.insert(
+            id.clone(),
+            Capability {
+                id: id.clone(),
+                owner_id,
+                cap_type: cap_type.to_string(),
+                params,
+                not_before,
+                expires_at,
+                parent_id: None,
+            },
+        );
+        Ok(id)
+    }
+
+    async fn delegate_capability(
+        &self,
+        parent_id: &str,
+        new_owner_id: EntityId,
+        restricted_params: serde_json::Value,
+    ) -> Result<String, StorageError> {
+        let mut state = self.state();
+        let parent = check_delegation(
+            state.capabilities.get(parent_id).cloned(),
+            parent_id,
+            &restricted_params,
+            &self.capability_types,
+            self.clock.as_ref(),
+        )?;
+        let id = uuid::Uuid::new_v4().to_string();
+        state.capabilities.insert(
+            id.clone(),
+            Capability {
+                id: id.clone(),
+                owner_id: new_owner_id,
+                cap_type: parent.cap_type,
+                params: restricted_params,
+                not_before: parent.not_before,
+                expires_at: parent.expires_at,
+                parent_id: Some(parent_id.to_string()),
+            },
+        );
+        Ok(id)
+    }
+
+    async fn get_capability(&self, id: &str) -> Result<Option<Capability>, StorageError> {
+        Ok(self.state().capabilities.get(id).cloned())
+    }
+
+    async fn get_capabilities(&self, owner_id: EntityId) -> Result<Vec<Capability>, StorageError> {
+        let now = self.clock.now_ms();
+        Ok(self
+            .state()
+            .capabilities
+            .values()
+            .filter(|cap| {
+                cap.owner_id == owner_id && cap.expires_at.is_none_or(|expires_at| expires_at > now)
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn update_capability_owner(
+        &self,
+        id: &str,
+        new_owner_id: EntityId,
+    ) -> Result<(), StorageError> {
+        match self.state().capabilities.get_mut(id) {
+            Some(cap) => {
+                cap.owner_id = new_owner_id;
+                Ok(())
+            }
+            None => Err(StorageError::CapabilityNotFound(id.to_string())),
+        }
+    }
+
+    async fn delete_capability(&self, id: &str) -> Result<(), StorageError> {
+        self.state().revoke_capability_trees(|cap| cap.id == id);
+        Ok(())
+    }
+
+    async fn schedule_task_with(
+        &self,
+        entity_id: EntityId,
+        verb: &str,
+        args: serde_json::Value,
+        execute_at: i64,
+        options: TaskOptions,
+    ) -> Result<i64, StorageError> {
+        if let Some(recurrence) = &options.recurrence {
+            recurrence
+                .validate()
+                .map_err(StorageError::InvalidSchedule)?;
+        }
+        let mut state = self.state();
+        let existing = options.dedupe_key.as_ref().and_then(|key| {
+            state
+                .tasks
+                .values()
+                .find(|task| task.entity_id == entity_id && task.dedupe_key.as_ref() == Some(key))
+                .map(|task| task.id)
+        });
+        let id = match existing {
+            Some(id) => id,
+            None => {
+                state.last_task_id += 1;
+                state.last_task_id
+            }
+        };
+        let leased_until = state.tasks.get(&id).and_then(|task| task.leased_until);
+        if leased_until.is_some() {
+            state.replaced.insert(id);
+        }
+        state.tasks.insert(
+            id,
+            ScheduledTask {
+                id,
+                entity_id,
+                verb: verb.to_string(),
+                args,
+                execute_at,
+                recurrence: options.recurrence,
+                dedupe_key: options.dedupe_key,
+                attempts: 0,
+                leased_until,
+                last_error: None,
+                caller_id: options.caller_id,
+                capability_id: options.capability_id,
+                priority: options.priority,
+                metadata: options.metadata,
+            },
+        );
+        Ok(id)
+    }
+
+    async fn get_due_tasks(&self, now: i64) -> Result<Vec<ScheduledTask>, StorageError> {
+        self.list_tasks(&TaskFilter::new().due_by(now)).await
+    }
+
+    async fn list_tasks(&self, filter: &TaskFilter) -> Result<Vec<ScheduledTask>, StorageError> {
+        let mut tasks: Vec<ScheduledTask> = self
+            .state()
+            .tasks
+            .values()
+            .filter(|task| filter.matches(task))
+            .cloned()
+            .collect();
+        tasks.sort_by_key(ScheduledTask::run_order);
+        Ok(tasks)
+    }
+
+    async fn reschedule_task(&self, id: i64, execute_at: i64) -> Result<bool, StorageError> {
+        let mut state = self.state();
+        let Some(task) = state.tasks.get_mut(&id) else {
+            return Ok(false);
+        };
+        task.execute_at = execute_at;
+        if task.leased_until.is_some() {
+            state.replaced.insert(id);
+        }
+        Ok(true)
+    }
+
+    async fn delete_task(&self, id: i64) -> Result<(), StorageError> {
+        self.state().tasks.remove(&id);
+        Ok(())
+    }
+
+    async fn delete_tasks(&self, filter: &TaskFilter) -> Result<u64, StorageError> {
+        let mut state = self.state();
+        let before = state.tasks.len();
+        state.tasks.retain(|_, task| !filter.matches(task));
+        Ok((before - state.tasks.len()) as u64)
+    }
+
+    async fn claim_due_tasks(
+        &self,
+        now: i64,
+        lease_until: i64,
+        limit: usize,
+    ) -> Result<Vec<ScheduledTask>, StorageError> {
+        let mut state = self.state();
+        let mut due: Vec<_> = state
+            .tasks
+            .values()
+            .filter(|task| {
+                task.execute_at <= now && task.leased_until.is_none_or(|until| until <= now)
+            })
+            .map(ScheduledTask::run_order)
+            .collect();
+        due.sort();
+        due.truncate(limit);
+        let mut claimed = Vec::new();
+        for (_, _, id) in due {
+            if let Some(task) = state.tasks.get_mut(&id) {
+                task.leased_until = Some(lease_until);
+                task.attempts += 1;
+                claimed.push(task.clone());
+            }
+            state.replaced.remove(&id);
+        }
+        Ok(claimed)
+    }
+
+    async fn complete_task(
+        &self,
+        id: i64,
+        leased_until: i64,
+        next_run: Option<i64>,
+    ) -> Result<bool, StorageError> {
+        let mut state = self.state();
+        if !state.holds_lease(id, leased_until) {
+            return Ok(false);
+        }
+        match next_run {
+            Some(next_run) => {
+                if let Some(task) = state.tasks.get_mut(&id) {
+                    task.execute_at = next_run;
+                    task.attempts = 0;
+                    task.leased_until = None;
+                    task.last_error = None;
+                }
+            }
+            None => {
+                state.tasks.remove(&id);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn fail_task(
+        &self,
+        id: i64,
+        leased_until: i64,
+        error: &str,
+        retry_at: Option<i64>,
+        now: i64,
+    ) -> Result<bool, StorageError> {
+        let mut state = self.state();
+        if !state.holds_lease(id, leased_until) {
+            return Ok(false);
+        }
+        match retry_at {
+            Some(retry_at) => {
+                if let Some(task) = state.tasks.get_mut(&id) {
+                    task.execute_at = retry_at;
+                    task.leased_until = None;
+                    task.last_error = Some(error.to_string());
+                }
+            }
+            None => {
+                if let Some(task) = state.tasks.remove(&id) {
+                    state.last_dead_letter_id += 1;
+                    let dead = DeadLetterTask {
+                        id: state.last_dead_letter_id,
+                        task_id: task.id,
+                        entity_id: task.entity_id,
+                        verb: task.verb,
+                        args: task.args,
+                        attempts: task.attempts,
+                        last_error: Some(error.to_string()),
+                        failed_at: now,
+                    };
+                    state.dead_letters.push(dead);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    async fn release_task(&self, id: i64, leased_until: i64) -> Result<bool, StorageError> {
+        let mut state = self.state();
+        let Some(task) = state
+            .tasks
+            .get_mut(&id)
+            .filter(|task| task.leased_until == Some(leased_until))
+        else {
+            return Ok(false);
+        };
+        task.leased_until = None;
+        task.attempts = task.attempts.saturating_sub(1);
+        state.replaced.remove(&id);
+        Ok(true)
+    }
+
+    async fn reclaim_expired_leases(&self, now: i64) -> Result<u64, StorageError> {
+        let mut released = 0;
+        let mut state = self.state();
+        let MemoryState {
+            tasks, replaced, ..
+        } = &mut *state;
+        for task in tasks.values_mut() {
+            if task.leased_until.is_some_and(|until| until <= now) {
+                task.leased_until = None;
+                replaced.remove(&task.id);
+                released += 1;
+            }
+        }
+        Ok(released)
+    }
+
+    async fn get_dead_letter_tasks(&self) -> Result<Vec<DeadLetterTask>, StorageError> {
+        Ok(self.state().dead_letters.clone())
+    }
+}
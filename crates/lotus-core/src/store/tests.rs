@@ -0,0 +1,674 @@
+//! Tests run against every WorldStore backend.
+
+use super::*;
+use crate::WorldStorage;
+use crate::scheduler::{CronSchedule, Recurrence, TaskFilter, TaskOptions};
+use serde_json::json;
+
+async fn check_entities<S: WorldStore>(store: &S) {
+    let proto_id = store
+        .create_entity(json!({"name": "Proto", "inherited": true}), None)
+        .await
+        .unwrap();
+    let id = store
+        .create_entity(json!({"name": "Instance"}), Some(proto_id))
+        .await
+        .unwrap();
+    assert_eq!(id, proto_id + 1);
+
+    let entity = store.get_entity(id).await.unwrap().unwrap();
+    assert_eq!(entity.name(), Some("Instance"));
+    assert_eq!(entity.prototype_id, Some(proto_id));
+    assert_eq!(entity.get_prop("inherited"), Some(&json!(true)));
+
+    let raw = store.get_entity_raw(id).await.unwrap().unwrap();
+    assert!(raw.get_prop("inherited").is_none());
+
+    store
+        .update_entity(id, json!({"description": "Updated"}))
+        .await
+        .unwrap();
+    let entity = store.get_entity(id).await.unwrap().unwrap();
+    assert_eq!(entity.name(), Some("Instance"));
+    assert_eq!(entity.description(), Some("Updated"));
+
+    assert!(matches!(
+        store.update_entity(999, json!({})).await,
+        Err(StorageError::EntityNotFound(999))
+    ));
+
+    store.set_prototype(id, None).await.unwrap();
+    let entity = store.get_entity(id).await.unwrap().unwrap();
+    assert!(entity.get_prop("inherited").is_none());
+
+    store.delete_entity(id).await.unwrap();
+    assert!(store.get_entity(id).await.unwrap().is_none());
+
+    // A prototype can't be deleted from under the entities inheriting it
+    let child = store
+        .create_entity(json!({}), Some(proto_id))
+        .await
+        .unwrap();
+    store.add_verb(proto_id, "look", &json!(1)).await.unwrap();
+    assert!(matches!(
+        store.delete_entity(proto_id).await,
+        Err(StorageError::PrototypeInUse(found)) if found == proto_id
+    ));
+    assert!(store.get_entity(proto_id).await.unwrap().is_some());
+    assert!(store.get_verb(proto_id, "look").await.unwrap().is_some());
+    store.delete_entity(child).await.unwrap();
+    store.delete_entity(proto_id).await.unwrap();
+    assert!(store.get_entity(proto_id).await.unwrap().is_none());
+}
+
+async fn check_verbs<S: WorldStore>(store: &S) {
+    let proto_id = store.create_entity(json!({}), None).await.unwrap();
+    let id = store
+        .create_entity(json!({}), Some(proto_id))
+        .await
+        .unwrap();
+
+    store
+        .add_verb(proto_id, "overridden", &json!(1))
+        .await
+        .unwrap();
+    store
+        .add_verb_with_cap(proto_id, "protected", &json!(2), Some("admin.execute"))
+        .await
+        .unwrap();
+    let own_id = store.add_verb(id, "overridden", &json!(3)).await.unwrap();
+    assert!(store.add_verb(id, "overridden", &json!(4)).await.is_err());
+
+    let verb = store.get_verb(id, "overridden").await.unwrap().unwrap();
+    assert_eq!(verb.id, own_id);
+    assert_eq!(verb.code, json!(3));
+
+    let verb = store.get_verb(id, "protected").await.unwrap().unwrap();
+    assert_eq!(verb.entity_id, proto_id);
+    assert_eq!(verb.required_capability.as_deref(), Some("admin.execute"));
+
+    let verbs = store.get_verbs(id).await.unwrap();
+    assert_eq!(verbs.len(), 2);
+    let overridden = verbs.iter().find(|verb| verb.name == "overridden").unwrap();
+    assert_eq!(overridden.entity_id, id);
+
+    store.update_verb(own_id, &json!(5)).await.unwrap();
+    let verb = store.get_verb(id, "overridden").await.unwrap().unwrap();
+    assert_eq!(verb.code, json!(5));
+
+    store.delete_verb(own_id).await.unwrap();
+    let verb = store.get_verb(id, "overridden").await.unwrap().unwrap();
+    assert_eq!(verb.entity_id, proto_id);
+}
+
+async fn check_capabilities<S: WorldStore>(store: &S) {
+    let alice = store.create_entity(json!({}), None).await.unwrap();
+    let bob = store.create_entity(json!({}), None).await.unwrap();
+
+    let cap_id = store
+        .create_capability(alice, "entity.control", json!({"target_id": bob}))
+        .await
+        .unwrap();
+    let cap = store.get_capability(&cap_id).await.unwrap().unwrap();
+    assert_eq!(cap.owner_id, alice);
+    assert!(cap.permits("entity.control", &json!({"target_id": bob})));
+
+    store.update_capability_owner(&cap_id, bob).await.unwrap();
+    assert!(store.get_capabilities(alice).await.unwrap().is_empty());
+    assert_eq!(store.get_capabilities(bob).await.unwrap().len(), 1);
+
+    store.delete_capability(&cap_id).await.unwrap();
+    assert!(store.get_capability(&cap_id).await.unwrap().is_none());
+    assert!(matches!(
+        store.update_capability_owner(&cap_id, alice).await,
+        Err(StorageError::CapabilityNotFound(missing)) if missing == cap_id
+    ));
+
+    store
+        .create_capability(bob, "fs.read", json!({"path": "/"}))
+        .await
+        .unwrap();
+    store.delete_entity(bob).await.unwrap();
+    assert!(store.get_capabilities(bob).await.unwrap().is_empty());
+
+    // Capabilities are validated against the type registry
+    assert!(matches!(
+        store
+            .create_capability(alice, "fs.raed", json!({"path": "/"}))
+            .await,
+        Err(StorageError::InvalidCapability(_))
+    ));
+    assert!(matches!(
+        store
+            .create_capability(alice, "entity.control", json!({}))
+            .await,
+        Err(StorageError::InvalidCapability(_))
+    ));
+}
+
+async fn check_capability_expiry_and_delegation<S: WorldStore>(store: &S) {
+    let alice = store.create_entity(json!({}), None).await.unwrap();
+    let bob = store.create_entity(json!({}), None).await.unwrap();
+    let carol = store.create_entity(json!({}), None).await.unwrap();
+
+    // Expired capabilities are hidden from the owner's list but still
+    // readable by ID
+    let expired = store
+        .create_capability_with_validity(alice, "fs.read", json!({"path": "/"}), None, Some(1))
+        .await
+        .unwrap();
+    assert!(store.get_capabilities(alice).await.unwrap().is_empty());
+    assert!(store.get_capability(&expired).await.unwrap().is_some());
+    assert!(matches!(
+        store
+            .delegate_capability(&expired, bob, json!({"path": "/tmp"}))
+            .await,
+        Err(StorageError::InvalidDelegation(_))
+    ));
+
+    let root = store
+        .create_capability(alice, "fs.read", json!({"path": {"$path": "/"}}))
+        .await
+        .unwrap();
+    let child = store
+        .delegate_capability(&root, bob, json!({"path": {"$path": "/tmp"}}))
+        .await
+        .unwrap();
+    let grandchild = store
+        .delegate_capability(&child, carol, json!({"path": "/tmp/x"}))
+        .await
+        .unwrap();
+    let cap = store.get_capability(&grandchild).await.unwrap().unwrap();
+    assert_eq!(cap.parent_id.as_deref(), Some(child.as_str()));
+    assert!(matches!(
+        store
+            .delegate_capability(&child, carol, json!({"path": "/etc"}))
+            .await,
+        Err(StorageError::InvalidDelegation(_))
+    ));
+    let exec = store
+        .create_capability(alice, "system.exec", json!({}))
+        .await
+        .unwrap();
+    assert!(matches!(
+        store.delegate_capability(&exec, bob, json!({})).await,
+        Err(StorageError::InvalidDelegation(_))
+    ));
+
+    // Deleting a capability revokes everything delegated from it
+    store.delete_capability(&child).await.unwrap();
+    assert!(store.get_capability(&grandchild).await.unwrap().is_none());
+    assert!(store.get_capability(&root).await.unwrap().is_some());
+
+    // So does deleting its owner
+    let child = store
+        .delegate_capability(&root, bob, json!({"path": {"$path": "/tmp"}}))
+        .await
+        .unwrap();
+    store.delete_entity(alice).await.unwrap();
+    assert!(store.get_capability(&child).await.unwrap().is_none());
+    assert!(store.get_capabilities(bob).await.unwrap().is_empty());
+}
+
+async fn check_delete_entity_removes_tasks<S: WorldStore>(store: &S) {
+    let doomed = store.create_entity(json!({}), None).await.unwrap();
+    let other = store.create_entity(json!({}), None).await.unwrap();
+    store
+        .schedule_task(doomed, "later", json!([]), 1_000)
+        .await
+        .unwrap();
+    let failed = store
+        .schedule_task(doomed, "broken", json!([]), 0)
+        .await
+        .unwrap();
+    store.claim_due_tasks(0, 100, 10).await.unwrap();
+    store.fail_task(failed, 100, "boom", None, 0).await.unwrap();
+    let kept = store
+        .schedule_task(other, "later", json!([]), 1_000)
+        .await
+        .unwrap();
+
+    store.delete_entity(doomed).await.unwrap();
+    let ids: Vec<_> = store
+        .list_tasks(&TaskFilter::new())
+        .await
+        .unwrap()
+        .iter()
+        .map(|task| task.id)
+        .collect();
+    assert_eq!(ids, vec![kept]);
+    assert!(store.get_dead_letter_tasks().await.unwrap().is_empty());
+}
+
+async fn check_tasks<S: WorldStore>(store: &S) {
+    let id = store.create_entity(json!({}), None).await.unwrap();
+
+    let late = store
+        .schedule_task(id, "late", json!([]), 200)
+        .await
+        .unwrap();
+    let early = store
+        .schedule_task(id, "early", json!([1]), 100)
+        .await
+        .unwrap();
+    store
+        .schedule_task(id, "future", json!([]), 1_000)
+        .await
+        .unwrap();
+
+    let due = store.get_due_tasks(500).await.unwrap();
+    let ids: Vec<_> = due.iter().map(|task| task.id).collect();
+    assert_eq!(ids, vec![early, late]);
+    assert_eq!(due[0].args, json!([1]));
+
+    store.delete_task(early).await.unwrap();
+    assert_eq!(store.get_due_tasks(500).await.unwrap().len(), 1);
+
+    // Recurring tasks keep their schedule and can be moved
+    let hourly = Recurrence::Cron(CronSchedule::parse("@hourly").unwrap());
+    let tick = store
+        .schedule_recurring_task(id, "tick", json!([]), 300, &hourly)
+        .await
+        .unwrap();
+    let recurring = store
+        .list_tasks(&TaskFilter::new().recurring(true))
+        .await
+        .unwrap();
+    assert_eq!(recurring.len(), 1);
+    assert_eq!(recurring[0].recurrence, Some(hourly));
+    store.reschedule_task(tick, 2_000).await.unwrap();
+    assert_eq!(store.get_due_tasks(500).await.unwrap().len(), 1);
+    assert!(
+        store.get_due_tasks(2_000).await.unwrap()[0]
+            .recurrence
+            .is_none()
+    );
+    assert_eq!(
+        store.get_due_tasks(2_000).await.unwrap().last().unwrap().id,
+        tick
+    );
+
+    // Intervals must fit a stored i64 and be positive
+    for interval_ms in [0, i64::MAX as u64 + 1, u64::MAX] {
+        assert!(matches!(
+            store
+                .schedule_recurring_task(
+                    id,
+                    "tick",
+                    json!([]),
+                    300,
+                    &Recurrence::Every { interval_ms }
+                )
+                .await,
+            Err(StorageError::InvalidSchedule(_))
+        ));
+    }
+
+    // A dedupe key replaces the entity's existing task with that key
+    let first = store
+        .schedule_task_with(
+            id,
+            "tick",
+            json!([1]),
+            400,
+            TaskOptions::new().dedupe_key("tick"),
+        )
+        .await
+        .unwrap();
+    let second = store
+        .schedule_task_with(
+            id,
+            "tick",
+            json!([2]),
+            450,
+            TaskOptions::new().dedupe_key("tick"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(first, second);
+    let keyed = store
+        .list_tasks(&TaskFilter::new().dedupe_key("tick"))
+        .await
+        .unwrap();
+    assert_eq!(keyed.len(), 1);
+    assert_eq!(keyed[0].args, json!([2]));
+    assert_eq!(keyed[0].execute_at, 450);
+    let other = store.create_entity(json!({}), None).await.unwrap();
+    let other_tick = store
+        .schedule_task_with(
+            other,
+            "tick",
+            json!([]),
+            450,
+            TaskOptions::new().dedupe_key("tick"),
+        )
+        .await
+        .unwrap();
+    assert_ne!(other_tick, first);
+
+    assert!(!store.reschedule_task(999, 0).await.unwrap());
+    assert_eq!(
+        store
+            .delete_tasks(&TaskFilter::new().entity(id).verb("tick"))
+            .await
+            .unwrap(),
+        2
+    );
+    let remaining = store.list_tasks(&TaskFilter::new()).await.unwrap();
+    let verbs: Vec<_> = remaining.iter().map(|task| task.verb.as_str()).collect();
+    assert_eq!(verbs, vec!["late", "tick", "future"]);
+    assert_eq!(remaining[1].entity_id, other);
+
+    // Tasks go away with their entity
+    store.delete_entity(other).await.unwrap();
+    store.delete_entity(id).await.unwrap();
+    assert!(store.get_due_tasks(i64::MAX).await.unwrap().is_empty());
+}
+
+async fn check_task_delivery<S: WorldStore>(store: &S) {
+    let id = store.create_entity(json!({}), None).await.unwrap();
+    let once = store
+        .schedule_task(id, "once", json!([]), 100)
+        .await
+        .unwrap();
+    let hourly = Recurrence::Every {
+        interval_ms: 3_600_000,
+    };
+    let tick = store
+        .schedule_recurring_task(id, "tick", json!([]), 100, &hourly)
+        .await
+        .unwrap();
+
+    // Claimed tasks are leased, not removed, and count an attempt
+    let claimed = store.claim_due_tasks(500, 1_000, 1).await.unwrap();
+    assert_eq!(claimed.len(), 1);
+    assert_eq!(claimed[0].id, once);
+    assert_eq!(claimed[0].attempts, 1);
+    assert_eq!(claimed[0].leased_until, Some(1_000));
+    let claimed = store.claim_due_tasks(500, 1_000, usize::MAX).await.unwrap();
+    assert_eq!(claimed.len(), 1);
+    assert_eq!(claimed[0].id, tick);
+    assert!(
+        store
+            .claim_due_tasks(500, 1_000, usize::MAX)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    assert_eq!(store.get_due_tasks(500).await.unwrap().len(), 2);
+
+    // Failures are released for retry with the error recorded
+    assert!(
+        store
+            .fail_task(once, 1_000, "boom", Some(600), 500)
+            .await
+            .unwrap()
+    );
+    assert!(
+        store
+            .complete_task(tick, 1_000, Some(3_600_100))
+            .await
+            .unwrap()
+    );
+    let tasks = store.list_tasks(&TaskFilter::new()).await.unwrap();
+    assert_eq!(tasks[0].id, once);
+    assert_eq!(tasks[0].execute_at, 600);
+    assert_eq!(tasks[0].leased_until, None);
+    assert_eq!(tasks[0].last_error.as_deref(), Some("boom"));
+    assert_eq!(tasks[1].id, tick);
+    assert_eq!(tasks[1].attempts, 0);
+
+    // Leases that run out are picked up again
+    let claimed = store.claim_due_tasks(600, 700, usize::MAX).await.unwrap();
+    assert_eq!(claimed[0].attempts, 2);
+    assert_eq!(store.reclaim_expired_leases(699).await.unwrap(), 0);
+    assert_eq!(store.reclaim_expired_leases(700).await.unwrap(), 1);
+    assert_eq!(
+        store
+            .claim_due_tasks(700, 800, usize::MAX)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+
+    store
+        .fail_task(once, 800, "boom again", None, 750)
+        .await
+        .unwrap();
+    assert_eq!(store.list_tasks(&TaskFilter::new()).await.unwrap().len(), 1);
+    let dead = store.get_dead_letter_tasks().await.unwrap();
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0].task_id, once);
+    assert_eq!(dead[0].verb, "once");
+    assert_eq!(dead[0].attempts, 3);
+    assert_eq!(dead[0].last_error.as_deref(), Some("boom again"));
+    assert_eq!(dead[0].failed_at, 750);
+
+    store.delete_entity(id).await.unwrap();
+    assert!(store.get_dead_letter_tasks().await.unwrap().is_empty());
+}
+
+async fn check_task_lease_fence<S: WorldStore>(store: &S) {
+    let id = store.create_entity(json!({}), None).await.unwrap();
+    let keyed = TaskOptions::new().dedupe_key("tick");
+    let task = store
+        .schedule_task_with(id, "tick", json!([1]), 100, keyed.clone())
+        .await
+        .unwrap();
+    store.claim_due_tasks(100, 1_000, 10).await.unwrap();
+
+    // Replacing a running task keeps its lease, so it isn't run twice
+    store
+        .schedule_task_with(id, "tick", json!([2]), 200, keyed)
+        .await
+        .unwrap();
+    let tasks = store.list_tasks(&TaskFilter::new()).await.unwrap();
+    assert_eq!(tasks[0].leased_until, Some(1_000));
+    assert_eq!(tasks[0].attempts, 0);
+    assert!(
+        store
+            .claim_due_tasks(200, 2_000, 10)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    // The old run finishing releases the replacement rather than removing it
+    assert!(!store.complete_task(task, 1_000, None).await.unwrap());
+    let claimed = store.claim_due_tasks(200, 2_000, 10).await.unwrap();
+    assert_eq!(claimed.len(), 1);
+    assert_eq!(claimed[0].args, json!([2]));
+
+    // Outcomes under a lease that ran out and was claimed again are dropped
+    assert_eq!(store.reclaim_expired_leases(2_000).await.unwrap(), 1);
+    store.claim_due_tasks(2_000, 3_000, 10).await.unwrap();
+    assert!(!store.complete_task(task, 2_000, None).await.unwrap());
+    assert!(
+        !store
+            .fail_task(task, 2_000, "late", None, 2_500)
+            .await
+            .unwrap()
+    );
+    let tasks = store.list_tasks(&TaskFilter::new()).await.unwrap();
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0].leased_until, Some(3_000));
+    assert!(store.get_dead_letter_tasks().await.unwrap().is_empty());
+
+    // Rescheduling a running task outlasts the run, whether it recurs
+    assert!(store.reschedule_task(task, 5_000).await.unwrap());
+    assert!(!store.complete_task(task, 3_000, Some(4_000)).await.unwrap());
+    let tasks = store.list_tasks(&TaskFilter::new()).await.unwrap();
+    assert_eq!(tasks[0].execute_at, 5_000);
+    assert_eq!(tasks[0].leased_until, None);
+    let claimed = store.claim_due_tasks(5_000, 6_000, 10).await.unwrap();
+    assert_eq!(claimed.len(), 1);
+    assert!(store.reschedule_task(task, 7_000).await.unwrap());
+    assert!(!store.complete_task(task, 6_000, None).await.unwrap());
+    let tasks = store.list_tasks(&TaskFilter::new()).await.unwrap();
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0].execute_at, 7_000);
+
+    store.claim_due_tasks(7_000, 8_000, 10).await.unwrap();
+    assert!(store.complete_task(task, 8_000, None).await.unwrap());
+    assert!(
+        store
+            .list_tasks(&TaskFilter::new())
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+async fn check_task_origin_and_priority<S: WorldStore>(store: &S) {
+    let player = store.create_entity(json!({}), None).await.unwrap();
+    let door = store.create_entity(json!({}), None).await.unwrap();
+
+    let low = store
+        .schedule_task_with(door, "creak", json!([]), 100, TaskOptions::new())
+        .await
+        .unwrap();
+    let high = store
+        .schedule_task_with(
+            door,
+            "close",
+            json!([]),
+            100,
+            TaskOptions::new()
+                .caller(player)
+                .capability("cap-1")
+                .priority(10)
+                .metadata(json!({"reason": "draft"})),
+        )
+        .await
+        .unwrap();
+    let earlier = store
+        .schedule_task_with(door, "open", json!([]), 50, TaskOptions::new().priority(-5))
+        .await
+        .unwrap();
+
+    // Priority only breaks ties between tasks due at the same instant
+    let tasks = store.list_tasks(&TaskFilter::new()).await.unwrap();
+    let ids: Vec<_> = tasks.iter().map(|task| task.id).collect();
+    assert_eq!(ids, vec![earlier, high, low]);
+    assert_eq!(tasks[1].caller_id, Some(player));
+    assert_eq!(tasks[1].capability_id.as_deref(), Some("cap-1"));
+    assert_eq!(tasks[1].priority, 10);
+    assert_eq!(tasks[1].metadata, Some(json!({"reason": "draft"})));
+    assert_eq!(tasks[2].caller_id, None);
+    assert_eq!(tasks[2].priority, 0);
+    assert_eq!(tasks[2].metadata, None);
+
+    let claimed = store.claim_due_tasks(100, 200, 2).await.unwrap();
+    let ids: Vec<_> = claimed.iter().map(|task| task.id).collect();
+    assert_eq!(ids, vec![earlier, high]);
+
+    let by_player = store
+        .list_tasks(&TaskFilter::new().caller(player))
+        .await
+        .unwrap();
+    assert_eq!(by_player.len(), 1);
+    assert_eq!(by_player[0].id, high);
+}
+
+#[tokio::test]
+async fn test_memory_store_entities() {
+    check_entities(&MemoryStore::new()).await;
+}
+
+#[tokio::test]
+async fn test_world_storage_entities() {
+    check_entities(&WorldStorage::in_memory().await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_memory_store_verbs() {
+    check_verbs(&MemoryStore::new()).await;
+}
+
+#[tokio::test]
+async fn test_world_storage_verbs() {
+    check_verbs(&WorldStorage::in_memory().await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_memory_store_capabilities() {
+    check_capabilities(&MemoryStore::new()).await;
+}
+
+#[tokio::test]
+async fn test_world_storage_capabilities() {
+    check_capabilities(&WorldStorage::in_memory().await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_memory_store_capability_expiry_and_delegation() {
+    check_capability_expiry_and_delegation(&MemoryStore::new()).await;
+}
+
+#[tokio::test]
+async fn test_world_storage_capability_expiry_and_delegation() {
+    check_capability_expiry_and_delegation(&WorldStorage::in_memory().await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_memory_store_delete_entity_removes_tasks() {
+    check_delete_entity_removes_tasks(&MemoryStore::new()).await;
+}
+
+#[tokio::test]
+async fn test_world_storage_delete_entity_removes_tasks() {
+    check_delete_entity_removes_tasks(&WorldStorage::in_memory().await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_memory_store_tasks() {
+    check_tasks(&MemoryStore::new()).await;
+}
+
+#[tokio::test]
+async fn test_world_storage_tasks() {
+    check_tasks(&WorldStorage::in_memory().await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_memory_store_task_lease_fence() {
+    check_task_lease_fence(&MemoryStore::new()).await;
+}
+
+#[tokio::test]
+async fn test_world_storage_task_lease_fence() {
+    check_task_lease_fence(&WorldStorage::in_memory().await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_memory_store_task_origin_and_priority() {
+    check_task_origin_and_priority(&MemoryStore::new()).await;
+}
+
+#[tokio::test]
+async fn test_world_storage_task_origin_and_priority() {
+    check_task_origin_and_priority(&WorldStorage::in_memory().await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn test_memory_store_rejects_prototype_cycle() {
+    let store = MemoryStore::new();
+    let parent = store.create_entity(json!({}), None).await.unwrap();
+    let child = store.create_entity(json!({}), Some(parent)).await.unwrap();
+
+    assert!(matches!(
+        store.set_prototype(parent, Some(child)).await,
+        Err(StorageError::PrototypeCycle { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_memory_store_task_delivery() {
+    check_task_delivery(&MemoryStore::new()).await;
+}
+
+#[tokio::test]
+async fn test_world_storage_task_delivery() {
+    check_task_delivery(&WorldStorage::in_memory().await.unwrap()).await;
+}